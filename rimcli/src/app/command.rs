@@ -1,19 +1,48 @@
+mod adjust;
+mod compare;
 mod completions;
+mod convert;
+mod crop;
+mod dedupe;
+mod flip;
 mod info;
+mod montage;
+mod overlay;
 mod recolor;
 mod resize;
+mod rotate;
 mod transparent;
 
+use adjust::AdjustArgs;
+use compare::CompareArgs;
 use completions::CompletionArgs;
+use convert::ConvertArgs;
+use crop::CropArgs;
+use dedupe::DedupeArgs;
+use flip::FlipArgs;
 use info::InfoArgs;
+use montage::MontageArgs;
+use overlay::OverlayArgs;
 use recolor::RecolorArgs;
 use resize::ResizeArgs;
+use rotate::RotateArgs;
 use transparent::TransparentArgs;
 
 use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
-use std::path::PathBuf;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
+use image::DynamicImage;
+use rimlib::image::animation::{Animation, open_animation_oriented, open_animation_with_metadata};
+use rimlib::image::formats::{Compression, EncodeOptions, PngFilter, output_path};
+use rimlib::image::input::{Glob, InputOptions, collect_inputs, read_path_list};
+use rimlib::image::manipulator::{open_image_oriented, open_image_with_metadata};
+use rimlib::image::metadata::{Metadata, supports_metadata};
+use rimlib::image::naming::NameTemplate;
+use rimlib::image::operation::{Pipeline, Quantize};
+use rimlib::image::quantize::{Dither, QuantizeOptions, Quantizer};
 
 use crate::backend::error::AppError;
 
@@ -40,10 +69,27 @@ pub struct ImageArgs {
     #[command(subcommand)]
     pub image_command: Option<ImageCommand>,
 
-    /// Images to be converted
-    #[clap(short, long, value_parser, num_args(1..10000), value_delimiter(' '), global(true))]
+    /// Images, or directories of images, to be converted
+    #[clap(short, long, value_parser, num_args(1..), value_delimiter(' '), global(true))]
     pub images: Vec<PathBuf>,
 
+    /// Also read input paths from this file, one per line or NUL separated, - for stdin
+    #[clap(long, global(true), value_name = "PATH")]
+    pub from_file: Option<PathBuf>,
+
+    /// Search input directories recursively
+    #[clap(short = 'R', long, global(true))]
+    pub recursive: bool,
+
+    /// Only take files from input directories matching this glob, may be repeated
+    #[clap(long, global(true), value_name = "GLOB")]
+    pub include: Vec<Glob>,
+
+    /// Skip files, given or found in input directories, and directories matching this glob,
+    /// may be repeated
+    #[clap(long, global(true), value_name = "GLOB")]
+    pub exclude: Vec<Glob>,
+
     /// Output path, use a directory when batch converting, cannot be used with format
     #[clap(short, long, global(true))]
     pub output: Option<PathBuf>,
@@ -52,13 +98,165 @@ pub struct ImageArgs {
     #[clap(short('x'), long, global(true))]
     pub overwrite: bool,
 
-    /// Output file name expression
+    /// Print the inputs, outputs and operations without decoding or writing any image
+    #[clap(long, global(true))]
+    pub dry_run: bool,
+
+    /// Recreate the directories of the inputs under the output directory in batches
+    #[clap(long, global(true))]
+    pub preserve_tree: bool,
+
+    /// Output file name template in batches, such as {stem}_{width}w or {date}_{index:03}
     #[clap(short, long, global(true))]
-    pub name_expr: Option<String>,
+    pub name_expr: Option<NameTemplate>,
 
     /// Output image(s) format
     #[clap(short, long, global(true))]
     pub format: Option<String>,
+
+    /// Keep EXIF, ICC and XMP metadata in output images, failing for formats that cannot hold it
+    #[clap(long, global(true), conflicts_with("strip_metadata"))]
+    pub keep_metadata: bool,
+
+    /// Remove EXIF, ICC and XMP metadata from output images
+    #[clap(long, global(true))]
+    pub strip_metadata: bool,
+
+    /// Encoding quality from 1 to 100, for JPEG and AVIF output
+    #[clap(long, global(true), value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: Option<u8>,
+
+    /// Compression for PNG (default, fast, best) or TIFF (none, lzw, deflate, packbits, fast, best) output
+    #[clap(long, global(true))]
+    pub compression: Option<Compression>,
+
+    /// Row filter for PNG output (none, sub, up, avg, paeth, adaptive)
+    #[clap(long, global(true))]
+    pub png_filter: Option<PngFilter>,
+
+    /// Encoding speed from 1 (slowest) to 10 (fastest), for AVIF output
+    #[clap(long, global(true), value_parser = clap::value_parser!(u8).range(1..=10))]
+    pub speed: Option<u8>,
+
+    /// Turn images upright according to their EXIF orientation before processing
+    #[clap(long, global(true))]
+    pub auto_orient: bool,
+
+    /// Only keep the first frame of animated GIF, PNG and WebP images
+    #[clap(long, global(true))]
+    pub first_frame: bool,
+
+    /// Reduce images to this many colors and save PNG or GIF output with a palette
+    #[clap(long, global(true), value_parser = clap::value_parser!(u16).range(2..=256))]
+    pub colors: Option<u16>,
+
+    /// Palette algorithm used with --colors: median-cut, k-means or neuquant
+    #[clap(long, global(true), default_value = "median-cut", requires("colors"))]
+    pub quantizer: Quantizer,
+
+    /// Dithering used with --colors: none, floyd-steinberg or ordered
+    #[clap(long, global(true), default_value = "floyd-steinberg", requires("colors"))]
+    pub dither: Dither,
+
+    /// Most images held in memory at once in batches, twice the thread count by default
+    #[clap(long, global(true))]
+    pub in_flight: Option<NonZeroUsize>,
+
+    /// Inputs as given, before directories were replaced by the images in them
+    #[clap(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+impl ImageArgs {
+    /// Sets the images to every input: the given paths and those read from the list file,
+    /// with directories replaced by the images in them
+    pub fn expand_inputs(&mut self) -> Result<()> {
+        let mut paths = self.images.clone();
+
+        if let Some(list) = &self.from_file {
+            paths.extend(match list.as_os_str() == "-" {
+                true => read_path_list(std::io::stdin().lock())?,
+                false => read_path_list(File::open(list)?)?,
+            });
+        }
+
+        let options = InputOptions {
+            recursive: self.recursive,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        };
+        self.images = collect_inputs(&paths, &options)?;
+        self.sources = paths;
+        Ok(())
+    }
+
+    /// Metadata to write into an output image.
+    ///
+    /// Formats that cannot hold metadata fail with --keep-metadata,
+    /// otherwise the metadata is left out with a warning.
+    pub fn output_metadata(&self, metadata: &Metadata, out: &Path) -> rimlib::Result<Metadata> {
+        if self.strip_metadata || metadata.is_empty() {
+            return Ok(Metadata::default());
+        }
+
+        let (out_path, format) = output_path(out, self.format.as_deref())?;
+        if supports_metadata(format) {
+            return Ok(metadata.clone());
+        }
+        if self.keep_metadata {
+            return Err(rimlib::Error::unsupported_format(
+                Some(&out_path),
+                &format!("{format:?} cannot hold EXIF, ICC or XMP metadata, use --strip-metadata"),
+            ));
+        }
+
+        eprintln!("Dropping metadata: {format:?} cannot hold it, {out_path:?}");
+        Ok(Metadata::default())
+    }
+
+    /// Decodes every frame of an animation with its metadata, turned upright when
+    /// auto-orient is set. Returns `None` for still images.
+    pub fn open_animation(&self, image_path: &Path) -> rimlib::Result<Option<(Animation, Metadata)>> {
+        if self.auto_orient {
+            open_animation_oriented(image_path)
+        } else {
+            open_animation_with_metadata(image_path)
+        }
+    }
+
+    /// Decodes an image with its metadata, turned upright when auto-orient is set
+    pub fn open_image(&self, image_path: &Path) -> rimlib::Result<(DynamicImage, Metadata)> {
+        if self.auto_orient {
+            open_image_oriented(image_path)
+        } else {
+            open_image_with_metadata(image_path)
+        }
+    }
+
+    /// Encoder settings for output images
+    pub fn encode_options(&self) -> EncodeOptions {
+        EncodeOptions {
+            quality: self.quality,
+            compression: self.compression,
+            png_filter: self.png_filter,
+            speed: self.speed,
+            indexed: self.colors.is_some(),
+        }
+    }
+
+    /// Builds the pipeline of the command, reducing colors last when asked to
+    pub fn pipeline(&self, command: &ImageCommand) -> Result<Pipeline> {
+        let mut pipeline = command.pipeline(self.format.as_deref())?;
+
+        if let Some(colors) = self.colors {
+            pipeline.push(Box::new(Quantize::new(QuantizeOptions {
+                colors: colors as usize,
+                quantizer: self.quantizer,
+                dither: self.dither,
+            })));
+        }
+        Ok(pipeline)
+    }
 }
 
 #[derive(Parser)]
@@ -72,16 +270,33 @@ struct VerbosityArgs {
     verbose: bool,
 }
 
+/// Image commands can be chained, each one running on the output of the last:
+/// `rimi resize -w 800 -H 600 recolor -c luma -b 8 convert -f png`
 #[derive(Subcommand, Debug, Clone)]
 pub enum ImageCommand {
     /// Convert an image
     #[clap(short_flag('c'))]
-    Convert,
+    Convert(ConvertArgs),
 
     /// Resize an image
     #[clap(short_flag('r'))]
     Resize(ResizeArgs),
 
+    /// Crop an image to a region
+    Crop(CropArgs),
+
+    /// Rotate an image clockwise
+    Rotate(RotateArgs),
+
+    /// Mirror an image horizontally or vertically
+    Flip(FlipArgs),
+
+    /// Composite a logo or watermark onto an image
+    Overlay(OverlayArgs),
+
+    /// Blur, sharpen or change the tones of an image
+    Adjust(AdjustArgs),
+
     /// Remove the background from an image
     #[clap(short_flag('t'))]
     Transparentize(TransparentArgs),
@@ -102,10 +317,59 @@ pub enum AppCommand {
     /// Show image information
     Info(InfoArgs),
 
+    /// Lay many images out in a grid on one contact sheet
+    Montage(MontageArgs),
+
+    /// Measure how much two images differ
+    Compare(CompareArgs),
+
+    /// Find images that look the same, whatever their size or format
+    Dedupe(DedupeArgs),
+
     /// Print shell completions
     Completions(CompletionArgs),
 }
 
+impl ImageCommand {
+    /// The command chained after this one, if any
+    pub fn then(&self) -> Option<&ImageCommand> {
+        match self {
+            ImageCommand::Convert(args) => args.then.as_deref(),
+            ImageCommand::Resize(args) => args.then.as_deref(),
+            ImageCommand::Crop(args) => args.then.as_deref(),
+            ImageCommand::Rotate(args) => args.then.as_deref(),
+            ImageCommand::Flip(args) => args.then.as_deref(),
+            ImageCommand::Overlay(args) => args.then.as_deref(),
+            ImageCommand::Adjust(args) => args.then.as_deref(),
+            ImageCommand::Transparentize(args) => args.then.as_deref(),
+            ImageCommand::Recolor(args) => args.then.as_deref(),
+        }
+    }
+
+    /// Builds a pipeline from this command and every command chained after it
+    pub fn pipeline(&self, format: Option<&str>) -> Result<Pipeline> {
+        let mut pipeline = Pipeline::new();
+        let mut command = Some(self);
+
+        while let Some(current) = command {
+            match current {
+                ImageCommand::Convert(args) => pipeline.push(Box::new(args.operation(format)?)),
+                ImageCommand::Resize(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Crop(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Rotate(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Flip(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Overlay(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Adjust(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Transparentize(args) => pipeline.push(Box::new(args.operation()?)),
+                ImageCommand::Recolor(args) => pipeline.push(Box::new(args.operation()?)),
+            }
+            command = current.then();
+        }
+
+        Ok(pipeline)
+    }
+}
+
 impl CommandArgs {
     pub fn run(&self) -> Result<()> {
         let verbosity = match (self.verbosity_args.quiet, self.verbosity_args.verbose) {
@@ -117,12 +381,20 @@ impl CommandArgs {
         match &self.misc_args.command {
             Some(AppCommand::Completions(args)) => args.run(),
             Some(AppCommand::Info(args)) => args.run(),
+            Some(AppCommand::Montage(args)) => args.run(self.image_args.dry_run),
+            Some(AppCommand::Compare(args)) => args.run(self.image_args.dry_run),
+            Some(AppCommand::Dedupe(args)) => args.run(self.image_args.dry_run),
             None => match &self.image_args.image_command {
-                Some(command) => match self.image_args.images.len() {
-                    0 => Err(AppError::NoImages.into()),
-                    1 => Ok(self.image_args.run_single(command, verbosity)?),
-                    _ => Ok(self.image_args.run_batch(command, verbosity)?),
-                },
+                Some(command) => {
+                    let mut image_args = self.image_args.clone();
+                    image_args.expand_inputs()?;
+
+                    match image_args.images.len() {
+                        0 => Err(AppError::NoImages.into()),
+                        1 => Ok(image_args.run_single(command, verbosity)?),
+                        _ => Ok(image_args.run_batch(command, verbosity)?),
+                    }
+                }
                 None => Ok(clap::Command::print_help(&mut super::Args::command())?),
             },
         }
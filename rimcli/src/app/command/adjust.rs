@@ -0,0 +1,85 @@
+use super::ImageCommand;
+
+use anyhow::Result;
+use clap::{ArgGroup, Parser};
+use rimlib::image::adjust::Adjustment;
+use rimlib::image::operation::Adjust;
+
+/// Adjustments are applied in the order of the options below
+#[derive(Parser, Debug, Clone)]
+#[clap(group = ArgGroup::new("adjustment").required(true).multiple(true).args([
+    "blur", "unsharp", "exposure", "brightness", "contrast", "gamma", "grayscale", "invert",
+]))]
+pub struct AdjustArgs {
+    /// Gaussian blur with this standard deviation in pixels
+    #[clap(long, value_name = "SIGMA")]
+    blur: Option<f32>,
+
+    /// Unsharp mask with a blur of this standard deviation in pixels
+    #[clap(long, value_name = "SIGMA")]
+    unsharp: Option<f32>,
+
+    /// Strength of the unsharp mask
+    #[clap(long, default_value_t = 1.0, requires("unsharp"))]
+    unsharp_amount: f32,
+
+    /// Smallest difference the unsharp mask sharpens, in percent
+    #[clap(long, default_value_t = 0.0, requires("unsharp"))]
+    unsharp_threshold: f32,
+
+    /// Exposure change in stops, each one doubling the light
+    #[clap(long, allow_negative_numbers(true), value_name = "STOPS")]
+    exposure: Option<f32>,
+
+    /// Brightness change from -100 to 100 percent
+    #[clap(long, allow_negative_numbers(true), value_name = "PERCENT")]
+    brightness: Option<f32>,
+
+    /// Contrast factor, 1 leaves the image unchanged
+    #[clap(long, value_name = "FACTOR")]
+    contrast: Option<f32>,
+
+    /// Gamma correction, values above 1 brighten midtones
+    #[clap(long)]
+    gamma: Option<f32>,
+
+    /// Replace colors with their luminance, keeping the color type
+    #[clap(long)]
+    grayscale: bool,
+
+    /// Invert colors
+    #[clap(long)]
+    invert: bool,
+
+    /// Command to run after adjusting
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
+
+impl AdjustArgs {
+    pub fn operation(&self) -> Result<Adjust> {
+        let adjustments = [
+            self.blur.map(|sigma| Adjustment::Blur { sigma }),
+            self.unsharp.map(|sigma| Adjustment::Unsharp {
+                sigma,
+                amount: self.unsharp_amount,
+                threshold: self.unsharp_threshold / 100.0,
+            }),
+            self.exposure.map(Adjustment::Exposure),
+            self.brightness
+                .map(|percent| Adjustment::Brightness(percent / 100.0)),
+            self.contrast.map(Adjustment::Contrast),
+            self.gamma.map(Adjustment::Gamma),
+            self.grayscale.then_some(Adjustment::Grayscale),
+            self.invert.then_some(Adjustment::Invert),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<Adjustment>>();
+
+        for adjustment in &adjustments {
+            adjustment.validate()?;
+        }
+        Ok(Adjust::new(adjustments))
+    }
+}
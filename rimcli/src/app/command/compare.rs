@@ -0,0 +1,86 @@
+use rimlib::image::compare::{Comparison, Metric, diff_image};
+use rimlib::image::formats::save_image_format;
+use rimlib::image::manipulator::open_image;
+
+use anyhow::{Result, bail};
+use clap::Parser;
+use image::DynamicImage;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+pub struct CompareArgs {
+    /// Reference image
+    first: PathBuf,
+
+    /// Image compared against the reference
+    second: PathBuf,
+
+    /// Also compute the mean and maximum CIEDE2000 color difference
+    #[clap(short = 'e', long)]
+    delta_e: bool,
+
+    /// Save an image showing the differing pixels in red
+    #[clap(short, long, value_name = "PATH")]
+    diff: Option<PathBuf>,
+
+    /// Channel differences up to this percentage are not shown in the diff image
+    #[clap(long, default_value_t = 0.0, requires("diff"))]
+    tolerance: f32,
+
+    /// Metric checked against the threshold: mse, psnr, ssim, max-delta or delta-e
+    #[clap(short, long, default_value = "ssim")]
+    metric: Metric,
+
+    /// Fail when the metric is worse than this value, for example 0.98 for SSIM or 40 for PSNR
+    #[clap(short, long)]
+    threshold: Option<f64>,
+
+    /// Print the results as JSON
+    #[clap(long, conflicts_with("toml"))]
+    json: bool,
+
+    /// Print the results as TOML
+    #[clap(long)]
+    toml: bool,
+}
+
+impl CompareArgs {
+    /// Compares the images, leaving the diff image unwritten on a dry run
+    pub fn run(&self, dry_run: bool) -> Result<()> {
+        let first = open_image(&self.first)?;
+        let second = open_image(&self.second)?;
+
+        let delta_e = self.delta_e || self.metric == Metric::DeltaE;
+        let comparison = Comparison::compute(&first, &second, delta_e)?;
+
+        match (&self.diff, dry_run) {
+            (Some(path), true) => eprintln!("Dry run, the diff image would be saved to {path:?}"),
+            (Some(path), false) => {
+                let diff = diff_image(&first, &second, self.tolerance / 100.0)?;
+                save_image_format(&DynamicImage::ImageRgb8(diff), path, None)?;
+            }
+            (None, _) => (),
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&comparison)?);
+        } else if self.toml {
+            print!("{}", toml::to_string(&comparison)?);
+        } else {
+            println!("{}", comparison.to_text());
+        }
+
+        if let Some(threshold) = self.threshold {
+            if comparison.exceeds(self.metric, threshold)? {
+                bail!(
+                    "{} and {} differ: {} {:.6} is worse than the threshold {threshold}",
+                    self.first.display(),
+                    self.second.display(),
+                    self.metric,
+                    comparison.value(self.metric).unwrap_or_default()
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use super::ImageCommand;
+
+use anyhow::Result;
+use clap::Parser;
+use rimlib::image::operation::Convert;
+
+#[derive(Parser, Debug, Clone)]
+pub struct ConvertArgs {
+    /// Command to run after converting
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
+
+impl ConvertArgs {
+    pub fn operation(&self, format: Option<&str>) -> Result<Convert> {
+        Ok(Convert::new(format))
+    }
+}
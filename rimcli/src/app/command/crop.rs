@@ -0,0 +1,27 @@
+use super::ImageCommand;
+
+use anyhow::Result;
+use clap::Parser;
+use rimlib::image::geometry::{Geometry, Gravity};
+use rimlib::image::operation::Crop;
+
+#[derive(Parser, Debug, Clone)]
+pub struct CropArgs {
+    /// Region to keep: WxH+X+Y, percentages such as 50%x50% or an aspect ratio such as 16:9
+    #[clap(short, long)]
+    geometry: Geometry,
+
+    /// Anchor the region is placed relative to, such as center or north-east
+    #[clap(short = 'G', long, default_value = "north-west")]
+    gravity: Gravity,
+
+    /// Command to run after cropping
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
+
+impl CropArgs {
+    pub fn operation(&self) -> Result<Crop> {
+        Ok(Crop::new(self.geometry, self.gravity))
+    }
+}
@@ -0,0 +1,203 @@
+use rimlib::image::hash::{HashAlgorithm, ImageHash, group_duplicates};
+use rimlib::image::input::{InputOptions, collect_inputs};
+use rimlib::image::manipulator::open_image;
+
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+pub struct DedupeArgs {
+    /// Directories or images to search for duplicates
+    #[clap(required(true), num_args(1..))]
+    paths: Vec<PathBuf>,
+
+    /// Also search the subdirectories of every directory
+    #[clap(short, long)]
+    recursive: bool,
+
+    /// Hash algorithm: ahash, dhash or phash
+    #[clap(short, long, default_value = "phash")]
+    algorithm: HashAlgorithm,
+
+    /// Largest number of differing bits, out of 64, between hashes of duplicates
+    #[clap(short, long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(0..=64))]
+    distance: u32,
+
+    /// Move the duplicates into this directory, keeping the largest image of each group
+    #[clap(short, long, value_name = "DIR", conflicts_with("link_to"))]
+    move_to: Option<PathBuf>,
+
+    /// Create symbolic links to the duplicates in this directory, leaving them in place
+    #[clap(short, long, value_name = "DIR")]
+    link_to: Option<PathBuf>,
+
+    /// Print the groups as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// A hashed image
+#[derive(Serialize, Debug)]
+struct Hashed {
+    path: PathBuf,
+    #[serde(serialize_with = "serialize_hash")]
+    hash: ImageHash,
+    width: u32,
+    height: u32,
+    file_size: u64,
+}
+
+/// Images whose hashes are close, the first one being kept
+#[derive(Serialize)]
+struct Group<'a> {
+    keep: &'a Hashed,
+    duplicates: Vec<&'a Hashed>,
+}
+
+impl DedupeArgs {
+    /// Finds the duplicates, only listing where they would go on a dry run
+    pub fn run(&self, dry_run: bool) -> Result<()> {
+        let options = InputOptions {
+            recursive: self.recursive,
+            ..Default::default()
+        };
+        let paths = collect_inputs(&self.paths, &options)?;
+
+        // Images are hashed right after decoding so only the hashes are kept in memory
+        let hashed = paths
+            .par_iter()
+            .filter_map(|path| match open_image(path) {
+                Ok(image) => Some(Hashed {
+                    path: path.to_path_buf(),
+                    hash: ImageHash::compute(&image, self.algorithm),
+                    width: image.width(),
+                    height: image.height(),
+                    file_size: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+                }),
+                Err(error) => {
+                    eprintln!("Skipping: {path:?}\nErr:{error}");
+                    None
+                }
+            })
+            .collect::<Vec<Hashed>>();
+
+        let hashes = hashed.iter().map(|image| image.hash).collect::<Vec<ImageHash>>();
+        let groups = group_duplicates(&hashes, self.distance)
+            .into_iter()
+            .map(|indices| {
+                let mut images = indices
+                    .into_iter()
+                    .map(|index| &hashed[index])
+                    .collect::<Vec<&Hashed>>();
+                // The image with the most pixels is kept, then the largest file
+                images.sort_by_key(|image| {
+                    std::cmp::Reverse((image.width as u64 * image.height as u64, image.file_size))
+                });
+                Group {
+                    keep: images[0],
+                    duplicates: images[1..].to_vec(),
+                }
+            })
+            .collect::<Vec<Group>>();
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&groups)?);
+        } else {
+            for (number, group) in groups.iter().enumerate() {
+                println!(
+                    "Group {}: keeping {} ({}x{})",
+                    number + 1,
+                    group.keep.path.display(),
+                    group.keep.width,
+                    group.keep.height
+                );
+                for duplicate in &group.duplicates {
+                    println!(
+                        "  {} ({}x{}, distance {})",
+                        duplicate.path.display(),
+                        duplicate.width,
+                        duplicate.height,
+                        group.keep.hash.distance(&duplicate.hash)
+                    );
+                }
+            }
+            println!(
+                "Found {} duplicates in {} groups among {} images",
+                groups.iter().map(|group| group.duplicates.len()).sum::<usize>(),
+                groups.len(),
+                hashed.len()
+            );
+        }
+
+        if let Some(directory) = self.move_to.as_ref().or(self.link_to.as_ref()) {
+            if !dry_run {
+                fs::create_dir_all(directory)?;
+            }
+
+            for (number, group) in groups.iter().enumerate() {
+                for duplicate in &group.duplicates {
+                    // Prefixed with the group so duplicates with the same name do not collide
+                    let name = duplicate
+                        .path
+                        .file_name()
+                        .ok_or_else(|| anyhow!("{:?} has no file name", duplicate.path))?;
+                    let target = directory.join(format!(
+                        "{:03}_{}",
+                        number + 1,
+                        name.to_string_lossy()
+                    ));
+                    if target.try_exists()? {
+                        return Err(anyhow!("{target:?} already exists"));
+                    }
+
+                    if dry_run {
+                        // Kept off stdout so the JSON output stays valid
+                        let line = format!(
+                            "Would {} {:?} to {target:?}",
+                            if self.move_to.is_some() { "move" } else { "link" },
+                            duplicate.path
+                        );
+                        match self.json {
+                            true => eprintln!("{line}"),
+                            false => println!("{line}"),
+                        }
+                        continue;
+                    }
+
+                    match self.move_to.is_some() {
+                        true => move_file(&duplicate.path, &target)?,
+                        false => link_file(&fs::canonicalize(&duplicate.path)?, &target)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renames the file, copying it when the target is on another file system
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn link_file(original: &Path, link: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(original, link)?)
+}
+
+#[cfg(windows)]
+fn link_file(original: &Path, link: &Path) -> Result<()> {
+    Ok(std::os::windows::fs::symlink_file(original, link)?)
+}
+
+fn serialize_hash<S: serde::Serializer>(hash: &ImageHash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(hash)
+}
@@ -0,0 +1,23 @@
+use super::ImageCommand;
+
+use anyhow::Result;
+use clap::Parser;
+use rimlib::image::operation::Flip;
+use rimlib::image::transform::FlipDirection;
+
+#[derive(Parser, Debug, Clone)]
+pub struct FlipArgs {
+    /// Mirror direction: horizontal or vertical
+    #[clap(short, long, default_value = "horizontal")]
+    direction: FlipDirection,
+
+    /// Command to run after flipping
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
+
+impl FlipArgs {
+    pub fn operation(&self) -> Result<Flip> {
+        Ok(Flip(self.direction))
+    }
+}
@@ -1,27 +1,137 @@
-use crate::backend::error::TaskError;
-use crate::image::{info::print_info, manipulator::open_image};
+use rimlib::image::formats::save_image_format;
+use rimlib::image::info::ImageInfo;
+use rimlib::image::manipulator::open_image;
+use rimlib::image::stats::{ImageStats, sample_rect};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use clap::Parser;
+use image::DynamicImage;
+use serde::Serialize;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 pub struct InfoArgs {
     /// Shorted information
-    #[clap(short, long)]
+    #[clap(short, long, conflicts_with_all(["json", "toml"]))]
     short: bool,
 
+    /// Print the information as JSON
+    #[clap(long, conflicts_with("toml"))]
+    json: bool,
+
+    /// Print the information as TOML
+    #[clap(long)]
+    toml: bool,
+
+    /// Decode the image and print pixel statistics
+    #[clap(long)]
+    stats: bool,
+
+    /// Save the histogram of every channel as a PNG image
+    #[clap(long, value_name = "PATH")]
+    histogram: Option<PathBuf>,
+
+    /// Print the values of the pixel at X,Y
+    #[clap(long, value_name = "X,Y", value_parser = numbers::<2>)]
+    pixel: Option<[u32; 2]>,
+
+    /// Print the average values over the rectangle at X,Y with size W,H
+    #[clap(long, value_name = "X,Y,W,H", value_parser = numbers::<4>)]
+    rect: Option<[u32; 4]>,
+
     ///Path to the image file
     image_file: PathBuf,
 }
 
+/// Everything printed by `info`, serialized for `--json` and `--toml`
+#[derive(Serialize)]
+struct InfoReport {
+    #[serde(flatten)]
+    info: ImageInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<ImageStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pixel: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rect: Option<Vec<f32>>,
+}
+
 impl InfoArgs {
     pub fn run(&self) -> Result<()> {
-        let image = match open_image(&self.image_file) {
-            Ok(image) => image,
-            Err(decode_error) => return Err(TaskError::SingleError(decode_error).into()),
+        let info = ImageInfo::read(&self.image_file)?;
+
+        let needs_pixels =
+            self.stats || self.histogram.is_some() || self.pixel.is_some() || self.rect.is_some();
+
+        let image = match needs_pixels {
+            true => Some(open_image(&self.image_file)?),
+            false => None,
+        };
+
+        let stats = match &image {
+            Some(image) if self.stats || self.histogram.is_some() => {
+                Some(ImageStats::compute(image))
+            }
+            _ => None,
         };
-        print_info(&image, self.image_file.to_path_buf(), self.short);
+
+        if let (Some(stats), Some(path)) = (&stats, &self.histogram) {
+            let histogram = DynamicImage::ImageRgb8(stats.histogram_image(128));
+            save_image_format(&histogram, path, Some("png"))?;
+        }
+
+        let sample = |area: Option<[u32; 4]>| -> Result<Option<Vec<f32>>> {
+            match (&image, area) {
+                (Some(image), Some([x, y, width, height])) => {
+                    Ok(Some(sample_rect(image, x, y, width, height)?))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        let report = InfoReport {
+            info,
+            stats: stats.filter(|_| self.stats),
+            pixel: sample(self.pixel.map(|[x, y]| [x, y, 1, 1]))?,
+            rect: sample(self.rect)?,
+        };
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else if self.toml {
+            print!("{}", toml::to_string(&report)?);
+        } else {
+            println!("{}", report.info.to_text(self.short));
+
+            if let Some(stats) = &report.stats {
+                println!("{}", stats.to_text());
+            }
+            if let Some(pixel) = &report.pixel {
+                println!("Pixel: {}", format_values(pixel));
+            }
+            if let Some(rect) = &report.rect {
+                println!("Rectangle average: {}", format_values(rect));
+            }
+        }
         Ok(())
     }
 }
+
+fn format_values(values: &[f32]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{value:.4}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn numbers<const N: usize>(value: &str) -> Result<[u32; N]> {
+    let numbers = value
+        .split(',')
+        .map(|number| number.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()?;
+
+    numbers
+        .try_into()
+        .map_err(|_| anyhow!("expected {N} comma separated numbers"))
+}
@@ -0,0 +1,126 @@
+use rimlib::image::formats::{output_path, save_image_format};
+use rimlib::image::manipulator::{filter_type, open_image};
+use rimlib::image::montage::{MontageOptions, Tile, montage};
+use rimlib::image::transform::Background;
+
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+pub struct MontageArgs {
+    /// Images to lay out, in order
+    #[clap(short, long, required(true), num_args(1..10000), value_delimiter(' '))]
+    images: Vec<PathBuf>,
+
+    /// Path of the contact sheet
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// Contact sheet format, guessed from the output path when not given
+    #[clap(short, long)]
+    format: Option<String>,
+
+    /// Images per row, close to a square grid when not given
+    #[clap(short, long)]
+    columns: Option<u32>,
+
+    /// Size of the box every image is fitted in, as WxH
+    #[clap(long, default_value = "256x256", value_parser = cell_size)]
+    cell: (u32, u32),
+
+    /// Gap in pixels between cells and around the sheet
+    #[clap(short, long, default_value_t = 8)]
+    spacing: u32,
+
+    /// Sheet background: transparent, a name, #rrggbbaa or r,g,b,a
+    #[clap(short, long, default_value = "white")]
+    background: Background,
+
+    /// Write the file name of every image under it
+    #[clap(long)]
+    captions: bool,
+
+    /// Image sampling filter used to shrink images
+    #[clap(short = 'F', long, default_value = "triangle")]
+    filter: String,
+
+    /// Overwrite the output if it exists
+    #[clap(short('x'), long)]
+    overwrite: bool,
+}
+
+impl MontageArgs {
+    /// Lays out the contact sheet, only printing its inputs and size on a dry run
+    pub fn run(&self, dry_run: bool) -> Result<()> {
+        if self.output.try_exists()? && !self.overwrite {
+            return Err(anyhow!(
+                "{:?} already exists, pass --overwrite to replace it",
+                self.output
+            ));
+        }
+
+        let options = MontageOptions {
+            columns: self.columns,
+            cell_width: self.cell.0,
+            cell_height: self.cell.1,
+            spacing: self.spacing,
+            background: self.background,
+            captions: self.captions,
+            filter: filter_type(&self.filter)?,
+        };
+        let (width, height) = options.sheet_size(self.images.len() as u32)?;
+
+        if dry_run {
+            let (output, _) = output_path(&self.output, self.format.as_deref())?;
+            for path in &self.images {
+                println!("{:?}", path.as_os_str());
+            }
+            println!("  -> {:?} {width}x{height}", output.as_os_str());
+            println!(
+                "Dry run, a sheet of {} images would be saved and nothing was written",
+                self.images.len()
+            );
+            return Ok(());
+        }
+
+        // Images are shrunk right after decoding so only the tiles are kept in memory
+        let tiles = self
+            .images
+            .par_iter()
+            .filter_map(|path| match open_image(path) {
+                Ok(image) => {
+                    let caption = path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string());
+                    Some(Tile::new(&image, caption, &options))
+                }
+                Err(error) => {
+                    eprintln!("Skipping: {path:?}\nErr:{error}");
+                    None
+                }
+            })
+            .collect::<Vec<Tile>>();
+
+        let sheet = montage(&tiles, &options)?;
+        save_image_format(&sheet, &self.output, self.format.as_deref())?;
+
+        println!(
+            "Saved a {}x{} sheet of {} images to {:?}",
+            sheet.width(),
+            sheet.height(),
+            tiles.len(),
+            self.output
+        );
+        Ok(())
+    }
+}
+
+fn cell_size(value: &str) -> Result<(u32, u32)> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| anyhow!("expected a cell size such as 256x256"))?;
+
+    Ok((width.trim().parse()?, height.trim().parse()?))
+}
@@ -0,0 +1,60 @@
+use super::ImageCommand;
+
+use anyhow::Result;
+use clap::Parser;
+use rimlib::image::geometry::{Gravity, Length};
+use rimlib::image::manipulator::{filter_type, open_image};
+use rimlib::image::operation::Overlay;
+use rimlib::image::overlay::OverlayOptions;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+pub struct OverlayArgs {
+    /// Image to composite onto every input, such as a logo or watermark
+    #[clap(short, long)]
+    watermark: PathBuf,
+
+    /// Anchor the overlay is placed against, such as center or south-east
+    #[clap(short = 'G', long, default_value = "south-east")]
+    gravity: Gravity,
+
+    /// Distance from the edges, or between tiles, in pixels or as a percentage such as 2%
+    #[clap(short, long, default_value = "0")]
+    margin: Length,
+
+    /// Opacity of the overlay in percent
+    #[clap(short = 'O', long, default_value_t = 100.0)]
+    opacity: f32,
+
+    /// Width of the overlay as a percentage of the image width
+    #[clap(short, long)]
+    scale: Option<f32>,
+
+    /// Repeat the overlay over the whole image
+    #[clap(long)]
+    tile: bool,
+
+    /// Image sampling filter used to scale the overlay
+    #[clap(short = 'F', long, default_value = "triangle")]
+    filter: String,
+
+    /// Command to run after compositing
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
+
+impl OverlayArgs {
+    /// Decodes the overlay once, every image in a batch shares it
+    pub fn operation(&self) -> Result<Overlay> {
+        let options = OverlayOptions {
+            gravity: self.gravity,
+            margin: self.margin,
+            opacity: self.opacity / 100.0,
+            scale: self.scale.map(|scale| scale / 100.0),
+            tile: self.tile,
+            filter: filter_type(&self.filter)?,
+        };
+
+        Ok(Overlay::new(open_image(&self.watermark)?, options))
+    }
+}
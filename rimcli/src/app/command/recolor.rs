@@ -1,25 +1,30 @@
-use crate::image::color::{BitDepth, ColorInfo, ColorSpace};
+use super::ImageCommand;
 
 use anyhow::Result;
-
 use clap::Parser;
-use image::DynamicImage;
+use rimlib::image::color::{BitDepth, ColorInfo, ColorSpace};
+use rimlib::image::operation::Recolor;
 
 #[derive(Parser, Debug, Clone)]
 pub struct RecolorArgs {
     /// Color space of the image
-    #[clap(short, long, value_enum)]
+    #[clap(short, long)]
     color_space: ColorSpace,
 
     /// Bit depth of the image
-    #[clap(short, long, value_enum)]
+    #[clap(short, long)]
     bit_depth: BitDepth,
+
+    /// Command to run after recoloring
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
 }
 
 impl RecolorArgs {
-    pub fn run(&self, image: DynamicImage) -> Result<DynamicImage> {
+    pub fn operation(&self) -> Result<Recolor> {
         let color_info = ColorInfo::new(&self.color_space, &self.bit_depth);
-
-        Ok(color_info.convert_image(image))
+        // Fail while parsing arguments instead of on every image
+        color_info.to_color_type()?;
+        Ok(Recolor(color_info))
     }
 }
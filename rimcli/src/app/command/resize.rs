@@ -1,19 +1,33 @@
-use crate::backend::error::TaskError;
-use crate::image::manipulator::{resize_image, Dimensions};
+use super::ImageCommand;
 
 use anyhow::Result;
-use clap::Parser;
-use image::DynamicImage;
+use clap::{ArgGroup, Parser};
+use rimlib::image::geometry::{Length, ResizeMode, ResizeSpec};
+use rimlib::image::manipulator::filter_type;
+use rimlib::image::operation::Resize;
 
 #[derive(Parser, Debug, Clone)]
+#[clap(group = ArgGroup::new("size")
+    .required(true)
+    .multiple(true)
+    .args(["width", "height", "geometry", "max_edge"]))]
 pub struct ResizeArgs {
-    /// New width
+    /// New width, the height follows the aspect ratio when not given
     #[clap(short, long)]
-    width: u32,
+    width: Option<u32>,
 
-    /// New height
+    /// New height, the width follows the aspect ratio when not given
     #[clap(short = 'H', long)]
-    height: u32,
+    height: Option<u32>,
+
+    /// Resize geometry: WxH, Wx, xH or 50%, followed by ! to stretch,
+    /// ^ to cover and center-crop or > to only ever shrink
+    #[clap(short, long, conflicts_with_all(["width", "height", "max_edge", "preserve_aspect"]))]
+    geometry: Option<ResizeSpec>,
+
+    /// Shrink images so their longest side is at most this many pixels
+    #[clap(short, long, conflicts_with_all(["width", "height", "preserve_aspect"]))]
+    max_edge: Option<u32>,
 
     /// Image Sampling filter
     #[clap(short = 'F', long, default_value = "Nearest")]
@@ -22,21 +36,28 @@ pub struct ResizeArgs {
     /// Preserve aspect ratio
     #[clap(short = 'P', long)]
     preserve_aspect: bool,
+
+    /// Command to run after resizing
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
 }
 
 impl ResizeArgs {
-    pub fn run(&self, image: DynamicImage) -> Result<DynamicImage> {
-        match resize_image(
-            image,
-            Dimensions {
-                x: self.width,
-                y: self.height,
+    pub fn operation(&self) -> Result<Resize> {
+        let spec = match (self.geometry, self.max_edge) {
+            (Some(spec), _) => spec,
+            (None, Some(edge)) => ResizeSpec::max_edge(edge),
+            (None, None) => ResizeSpec {
+                width: self.width.map(Length::Pixels),
+                height: self.height.map(Length::Pixels),
+                mode: match self.preserve_aspect {
+                    true => ResizeMode::Fit,
+                    false => ResizeMode::Exact,
+                },
+                shrink_only: false,
             },
-            self.filter.to_string(),
-            self.preserve_aspect,
-        ) {
-            Ok(image) => Ok(image),
-            Err(resize_error) => Err(TaskError::SingleError(resize_error).into()),
-        }
+        };
+
+        Ok(Resize::with_spec(spec).filter(filter_type(&self.filter)?))
     }
 }
@@ -0,0 +1,30 @@
+use super::ImageCommand;
+
+use anyhow::Result;
+use clap::Parser;
+use rimlib::image::operation::Rotate;
+use rimlib::image::transform::{Background, normalize_angle};
+
+#[derive(Parser, Debug, Clone)]
+pub struct RotateArgs {
+    /// Clockwise angle in degrees, negative values rotate counter-clockwise
+    #[clap(short, long, allow_negative_numbers(true))]
+    degrees: f32,
+
+    /// Fill for the corners uncovered by angles that are not a multiple of 90:
+    /// transparent, a name, #rrggbbaa or r,g,b,a
+    #[clap(short, long, default_value = "transparent")]
+    background: Background,
+
+    /// Command to run after rotating
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
+
+impl RotateArgs {
+    pub fn operation(&self) -> Result<Rotate> {
+        // Clap parses "nan" and "inf" as angles
+        normalize_angle(self.degrees)?;
+        Ok(Rotate::new(self.degrees).background(self.background))
+    }
+}
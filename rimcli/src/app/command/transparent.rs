@@ -1,14 +1,51 @@
-use crate::image::manipulator::remove_background;
+use super::ImageCommand;
 
 use anyhow::Result;
 use clap::Parser;
-use image::DynamicImage;
+use rimlib::image::operation::Transparentize;
+use rimlib::image::transparency::{BackgroundOptions, KeyColor, RemovalMode};
 
 #[derive(Parser, Debug, Clone)]
-pub struct TransparentArgs {}
+pub struct TransparentArgs {
+    /// Background color to remove: a name, #rrggbb or r,g,b
+    #[clap(short, long, default_value = "white")]
+    key: KeyColor,
+
+    /// Distance from the key color still treated as background, in percent
+    #[clap(short = 'T', long, default_value_t = 0.0, value_parser = percent)]
+    tolerance: f32,
+
+    /// Width of the soft edge past the tolerance, in percent
+    #[clap(short = 'F', long, default_value_t = 0.0, value_parser = percent)]
+    feather: f32,
+
+    /// Only remove background connected to the image border
+    #[clap(short, long)]
+    border: bool,
+
+    /// Command to run after removing the background
+    #[command(subcommand)]
+    pub then: Option<Box<ImageCommand>>,
+}
 
 impl TransparentArgs {
-    pub fn run(&self, image: DynamicImage) -> Result<DynamicImage> {
-        Ok(remove_background(image))
+    pub fn operation(&self) -> Result<Transparentize> {
+        Ok(Transparentize::new(BackgroundOptions {
+            key: self.key,
+            tolerance: self.tolerance / 100.0,
+            feather: self.feather / 100.0,
+            mode: if self.border {
+                RemovalMode::Border
+            } else {
+                RemovalMode::Global
+            },
+        }))
+    }
+}
+
+fn percent(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("{value:?} is not a percentage from 0 to 100")),
     }
 }
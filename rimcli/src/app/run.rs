@@ -1,12 +1,13 @@
-use std::error::Error;
-use std::fmt::Display;
-
-use anyhow::{Error, Result};
+use anyhow::Result;
 use image::DynamicImage;
+use rimlib::image::animation::{Animation, save_animation};
+use rimlib::image::color::ColorInfo;
+use rimlib::image::formats::save_image;
+use rimlib::image::metadata::Metadata;
+use rimlib::image::operation::Pipeline;
+use std::path::Path;
 
-use crate::image::manipulator::convert_image;
-
-use super::command::ImageCommand;
+use super::command::{ImageArgs, ImageCommand};
 
 mod batch;
 mod single;
@@ -19,37 +20,66 @@ pub trait RunBatch {
     fn run_batch(&self, command: &ImageCommand, verbosity: u32) -> anyhow::Result<()>;
 }
 
-fn run_command(
-    command: &ImageCommand,
-    image: DynamicImage,
-    format: Option<&str>,
-) -> Result<DynamicImage> {
-    match command {
-        ImageCommand::Convert => match convert_image(image, format) {
-            Ok(image) => Ok(image),
-            Err(convert_error) => Err(Error::msg(convert_error)),
-        },
-        ImageCommand::Resize(args) => match args.run(image) {
-            Ok(image) => Ok(image),
-            Err(resize_error) => Err(resize_error),
-        },
-        ImageCommand::Recolor(args) => match args.run(image) {
-            Ok(image) => Ok(image),
-            Err(recolor_error) => Err(recolor_error),
-        },
-        ImageCommand::Transparentize(args) => match args.run(image) {
-            Ok(image) => Ok(image),
-            Err(removal_error) => Err(removal_error),
-        },
+/// A decoded input image with its metadata, animations keeping every frame
+#[derive(Debug, Clone)]
+enum Decoded {
+    Still(DynamicImage, Metadata),
+    Animated(Animation, Metadata),
+}
+
+impl Decoded {
+    /// Decodes every frame of animated images unless only the first frame is wanted
+    fn open(args: &ImageArgs, image_path: &Path) -> rimlib::Result<Self> {
+        if !args.first_frame {
+            if let Some((animation, metadata)) = args.open_animation(image_path)? {
+                return Ok(Decoded::Animated(animation, metadata));
+            }
+        }
+        let (image, metadata) = args.open_image(image_path)?;
+        Ok(Decoded::Still(image, metadata))
+    }
+
+    fn save(&self, output_path: &Path, args: &ImageArgs) -> rimlib::Result<()> {
+        match self {
+            Decoded::Still(image, metadata) => save_image(
+                image,
+                output_path,
+                args.format.as_deref(),
+                &args.encode_options(),
+                &args.output_metadata(metadata, output_path)?,
+            ),
+            Decoded::Animated(animation, metadata) => save_animation(
+                animation,
+                output_path,
+                args.format.as_deref(),
+                &args.encode_options(),
+                &args.output_metadata(metadata, output_path)?,
+            ),
+        }
     }
 }
 
-fn command_msg(command: &ImageCommand, image_name: &str) -> Result<String> {
-    let message = match command {
-        ImageCommand::Convert => "Converting",
-        ImageCommand::Resize(_) => "Resizing",
-        ImageCommand::Recolor(_) => "Recoloring",
-        ImageCommand::Transparentize(_) => "Removing background",
+fn run_command(pipeline: &Pipeline, decoded: Decoded) -> Result<Decoded> {
+    let result = match decoded {
+        Decoded::Still(image, metadata) => pipeline
+            .run(image)
+            .map(|image| Decoded::Still(image, metadata)),
+        Decoded::Animated(animation, metadata) => pipeline
+            .run_animation(animation)
+            .map(|animation| Decoded::Animated(animation, metadata)),
     };
-    Ok(format!("{message}: {image_name}"))
+    match result {
+        Ok(decoded) => Ok(decoded),
+        Err(operation_error) => Err(operation_error.into()),
+    }
+}
+
+/// An input or output image as listed by --dry-run
+fn describe_image(path: &Path, (width, height): (u32, u32), color: ColorInfo) -> String {
+    format!(
+        "{:?} {width}x{height} {} {}-bit",
+        path.as_os_str(),
+        color.color_space,
+        color.bit_depth
+    )
 }
@@ -1,291 +1,550 @@
-use super::{command_msg, run_command, RunBatch};
+use super::{describe_image, run_command, Decoded, RunBatch};
 use crate::app::command::{ImageArgs, ImageCommand};
-use crate::backend::paths::create_paths;
-use crate::image::manipulator::{open_image, save_image_format};
-use anyhow::{Error, Result};
+use image::ImageFormat;
+use rimlib::image::color::ColorInfo;
+use rimlib::image::input::relative_dirs;
+use rimlib::image::manipulator::{probe_image, read_exif};
+use rimlib::image::metadata::Metadata;
+use rimlib::image::naming::{natural_indices, NameFields};
+use rimlib::image::operation::{Operation, Pipeline};
+use anyhow::{bail, Result};
 use crossbeam_channel::{Receiver, Sender};
-use image::DynamicImage;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use rayon::iter::{
-    IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator,
-    ParallelDrainRange, ParallelIterator,
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
 };
-use std::ops::Deref;
+use std::collections::BTreeMap;
+use std::fs;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::time::Duration;
 
-#[derive(Debug, Default, Clone)]
+/// An image that can be processed, with what its output is named after
+struct Planned {
+    path: PathBuf,
+    input_size: (u32, u32),
+    input_color: ColorInfo,
+    /// Dimensions, color layout and format of the output
+    width: u32,
+    height: u32,
+    color: ColorInfo,
+    format: ImageFormat,
+}
+
+/// An image passed between the decoding, processing and saving workers
+#[derive(Debug, Clone)]
 struct ImageTask {
-    image: Option<DynamicImage>,
+    image: Decoded,
     image_path: PathBuf,
+    output_path: PathBuf,
 }
 
-impl ImageTask {
-    fn new(path: &Path, image: Option<DynamicImage>) -> Self {
-        ImageTask {
-            image,
-            image_path: path.to_path_buf(),
-        }
-    }
+/// Step of the batch an image failed at
+#[derive(Clone, Copy)]
+enum Stage {
+    Plan,
+    Decode,
+    Process,
+    Save,
 }
 
 #[derive(Clone)]
 enum TaskState {
+    Planned(u64),
     Decode(String),
-    InitProcessing(u64),
     Process(String),
-    InitSaving(u64),
     Save(String),
     Complete(u64),
-    Failure(String),
+    Failure(Stage, String),
+}
+
+/// Limits how many images are held in memory at once.
+///
+/// A slot is taken before an image is decoded and given back once it is saved
+/// or dropped, so decoding waits for saving when the limit is reached.
+#[derive(Clone)]
+struct InFlight {
+    release_tx: Sender<()>,
+    acquire_rx: Receiver<()>,
+}
+
+impl InFlight {
+    fn new(limit: usize) -> Self {
+        let (release_tx, acquire_rx) = crossbeam_channel::bounded(limit);
+        for _ in 0..limit {
+            release_tx.send(()).unwrap_or(());
+        }
+        Self {
+            release_tx,
+            acquire_rx,
+        }
+    }
+
+    fn acquire(&self) {
+        self.acquire_rx.recv().unwrap_or(());
+    }
+
+    fn release(&self) {
+        self.release_tx.send(()).unwrap_or(());
+    }
 }
 
-fn run(command: ImageCommand, args: ImageArgs, verbosity: u32) -> Result<()> {
-    let (task_tx, task_rx) = crossbeam_channel::unbounded();
+fn run(command: &ImageCommand, args: &ImageArgs, verbosity: u32) -> Result<()> {
     let (state_tx, state_rx) = crossbeam_channel::unbounded();
 
     let len = args.images.len() as u64;
 
-    let images = args.images.clone();
+    let pipeline = args.pipeline(command)?;
 
-    rayon::scope(|s| {
-        let decode_sender = state_tx.clone();
-        let command = Arc::new(command);
-        let args = Arc::new(args);
-        let proc_tx = state_tx.clone();
-        s.spawn(move |_| {
-            decode(images, task_tx, decode_sender);
-            let mut tasks = process(command, args.clone(), task_rx, proc_tx);
-            save_images(&mut tasks, &state_tx, &args);
-        });
+    let workers = rayon::current_num_threads();
+    let in_flight = args
+        .in_flight
+        .map(NonZeroUsize::get)
+        .unwrap_or(workers * 2);
+
+    if args.dry_run {
+        return dry_run(args, &pipeline, state_tx, state_rx);
+    }
 
+    thread::scope(|s| {
         if verbosity != 0 {
-            s.spawn(move |_| message(state_rx, len));
+            s.spawn(move || message(state_rx, len));
         }
+        stream(
+            args,
+            &pipeline,
+            workers.min(in_flight),
+            in_flight,
+            state_tx,
+        )
+    })
+}
+
+/// Streams every image through decoding, processing and saving,
+/// each step running on its own workers connected by bounded channels.
+/// Nothing is decoded when several images would be saved to the same path.
+fn stream(
+    args: &ImageArgs,
+    pipeline: &Pipeline,
+    workers: usize,
+    in_flight: usize,
+    message_tx: Sender<TaskState>,
+) -> Result<()> {
+    let images = plan(args, pipeline, &message_tx);
+    let jobs = name(images, args, &message_tx)?;
+    message_tx
+        .send(TaskState::Planned(jobs.len() as u64))
+        .unwrap_or(());
+
+    let (job_tx, job_rx) = crossbeam_channel::unbounded();
+    for (image, output_path) in jobs {
+        job_tx.send((image.path, output_path)).unwrap_or(());
+    }
+    drop(job_tx);
+
+    let slots = InFlight::new(in_flight);
+    let (decoded_tx, decoded_rx) = crossbeam_channel::bounded(in_flight);
+    let (processed_tx, processed_rx) = crossbeam_channel::bounded(in_flight);
+    let saved = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            {
+                let (job_rx, decoded_tx) = (job_rx.clone(), decoded_tx.clone());
+                let (slots, message_tx) = (slots.clone(), message_tx.clone());
+                s.spawn(move || decode(args, job_rx, decoded_tx, slots, message_tx));
+            }
+            {
+                let (decoded_rx, processed_tx) = (decoded_rx.clone(), processed_tx.clone());
+                let (slots, message_tx) = (slots.clone(), message_tx.clone());
+                s.spawn(move || process(pipeline, decoded_rx, processed_tx, slots, message_tx));
+            }
+            {
+                let processed_rx = processed_rx.clone();
+                let (slots, message_tx, saved) = (slots.clone(), message_tx.clone(), &saved);
+                s.spawn(move || save(args, processed_rx, slots, saved, message_tx));
+            }
+        }
+        // Every step stops once the channel feeding it is closed,
+        // so only the workers may keep senders alive
+        drop((job_rx, decoded_tx, decoded_rx, processed_tx, processed_rx));
     });
+
+    message_tx
+        .send(TaskState::Complete(saved.into_inner() as u64))
+        .unwrap_or(());
+    Ok(())
+}
+
+/// Prints what the batch would do, without decoding or writing any image
+fn dry_run(
+    args: &ImageArgs,
+    pipeline: &Pipeline,
+    message_tx: Sender<TaskState>,
+    message_rx: Receiver<TaskState>,
+) -> Result<()> {
+    println!("{}", pipeline.describe());
+
+    let images = plan(args, pipeline, &message_tx);
+    let jobs = name(images, args, &message_tx);
+    drop(message_tx);
+
+    for state in message_rx {
+        if let TaskState::Failure(_, message) = state {
+            println!("{message}");
+        }
+    }
+
+    let jobs = jobs?;
+    for (image, output_path) in &jobs {
+        println!(
+            "{}\n  -> {}",
+            describe_image(&image.path, image.input_size, image.input_color),
+            describe_image(output_path, (image.width, image.height), image.color)
+        );
+    }
+    println!("Dry run, {} images would be saved and nothing was written", jobs.len());
     Ok(())
 }
 
 fn message(message_reciever: Receiver<TaskState>, length: u64) {
     const PROGRESS_CHARS: &str = "##-";
     let bar = MultiProgress::new();
-    let decode_bar = bar.add(
-        ProgressBar::new(length).with_style(
-            ProgressStyle::with_template(
-                "[{pos}/{len}] {msg}\n{bar:40.cyan/blue} [{elapsed_precise}]",
-            )
-            .unwrap()
-            .progress_chars(PROGRESS_CHARS),
-        ),
-    );
-
-    decode_bar.enable_steady_tick(Duration::from_millis(500));
-
-    let mut process_bar = bar.add(ProgressBar::hidden());
-    let mut save_bar = bar.add(ProgressBar::hidden());
-    let start_progress = |bar: &mut ProgressBar, length: u64| {
-        *bar = ProgressBar::new(length).with_style(
-            ProgressStyle::with_template(
-                "[{pos}/{len}] {msg}\n{bar:40.cyan/blue} [{elapsed_precise}]",
-            )
-            .unwrap()
-            .progress_chars(PROGRESS_CHARS),
+    let add_bar = || {
+        let progress = bar.add(
+            ProgressBar::new(length).with_style(
+                ProgressStyle::with_template(
+                    "[{pos}/{len}] {msg}\n{bar:40.cyan/blue} [{elapsed_precise}]",
+                )
+                .unwrap()
+                .progress_chars(PROGRESS_CHARS),
+            ),
         );
+        progress.enable_steady_tick(Duration::from_millis(500));
+        progress
     };
 
-    let start_saving = |bar: &mut ProgressBar, length: u64| {
-        *bar = ProgressBar::new(length).with_style(
-            ProgressStyle::with_template(
-                "[{pos}/{len}] {msg}\n{bar:40.cyan/blue} [{elapsed_precise}]",
-            )
-            .unwrap()
-            .progress_chars(PROGRESS_CHARS),
-        );
-    };
-    save_bar.enable_steady_tick(Duration::from_millis(500));
+    let decode_bar = add_bar();
+    let process_bar = add_bar();
+    let save_bar = add_bar();
+    let mut errors = [0u64; 3];
+
     while let Ok(state) = message_reciever.recv() {
         match state {
+            TaskState::Planned(num) => {
+                for progress in [&decode_bar, &process_bar, &save_bar] {
+                    progress.set_length(num);
+                }
+            }
             TaskState::Decode(message) => {
                 decode_bar.set_message(message);
                 decode_bar.inc(1);
             }
-            TaskState::InitProcessing(num) => {
-                decode_bar.finish_with_message(format!(
-                    "Decoded {num} images with {} errors.",
-                    length - num
-                ));
-                start_progress(&mut process_bar, num);
-            }
             TaskState::Process(message) => {
                 process_bar.set_message(message);
                 process_bar.inc(1);
             }
-            TaskState::InitSaving(num) => {
-                let length = process_bar.length().unwrap_or(length);
-                process_bar.finish_with_message(format!(
-                    "Processed {num} images with {} errors",
-                    length - num
-                ));
-                start_saving(&mut save_bar, num);
-            }
             TaskState::Save(message) => {
                 save_bar.set_message(message);
                 save_bar.inc(1);
             }
             TaskState::Complete(num) => {
-                let length = save_bar.length().unwrap_or(length);
+                decode_bar.finish_with_message(format!(
+                    "Decoded {} images with {} errors",
+                    decode_bar.position(),
+                    errors[0]
+                ));
+                process_bar.finish_with_message(format!(
+                    "Processed {} images with {} errors",
+                    process_bar.position(),
+                    errors[1]
+                ));
                 save_bar.finish_with_message(format!(
                     "Saved {num} images with {} errors",
-                    length - num
+                    errors[2]
                 ));
             }
-            TaskState::Failure(message) => {
+            TaskState::Failure(stage, message) => {
+                // Later steps will not see the image
+                match stage {
+                    Stage::Plan => (),
+                    Stage::Decode => {
+                        errors[0] += 1;
+                        process_bar.dec_length(1);
+                        save_bar.dec_length(1);
+                    }
+                    Stage::Process => {
+                        errors[1] += 1;
+                        save_bar.dec_length(1);
+                    }
+                    Stage::Save => errors[2] += 1,
+                }
                 bar.println(message).unwrap();
             }
         }
     }
 }
 
-fn decode(image_paths: Vec<PathBuf>, task_tx: Sender<ImageTask>, message_tx: Sender<TaskState>) {
-    let acc = AtomicUsize::new(0);
-    image_paths.par_iter().for_each(|image_path| {
-        let result = open_image(image_path);
+/// Reads the header of every image so files that cannot be decoded,
+/// or that the pipeline cannot be applied to, are reported before any
+/// pixel data is loaded
+fn plan(args: &ImageArgs, pipeline: &Pipeline, message_tx: &Sender<TaskState>) -> Vec<Planned> {
+    let format = args.format.as_deref().and_then(ImageFormat::from_extension);
 
-        match result {
+    args.images
+        .par_iter()
+        .filter_map(|image_path| match probe_image(image_path).and_then(|probe| {
+            let (width, height) = match args.auto_orient {
+                true => probe.upright_size(),
+                false => (probe.width, probe.height),
+            };
+            pipeline
+                .output_size(width, height)
+                .map_err(|e| e.with_path(image_path))
+                .map(|(output_width, output_height)| Planned {
+                    input_size: (width, height),
+                    input_color: probe.color_type.into(),
+                    width: output_width,
+                    height: output_height,
+                    color: pipeline.output_color(probe.color_type.into()),
+                    format: format.unwrap_or(probe.format),
+                    path: probe.path,
+                })
+        }) {
+            Ok(planned) => Some(planned),
+            Err(probe_error) => {
+                message_tx
+                    .send(TaskState::Failure(
+                        Stage::Plan,
+                        format!("Skipping: {:?}\nErr:{}", image_path, probe_error),
+                    ))
+                    .unwrap_or(());
+                None
+            }
+        })
+        .collect()
+}
+
+/// Names the output of every image from the name template,
+/// numbering the images in natural order, and checks no two images
+/// would be saved to the same path
+fn name(
+    images: Vec<Planned>,
+    args: &ImageArgs,
+    message_tx: &Sender<TaskState>,
+) -> Result<Vec<(Planned, PathBuf)>> {
+    let destination = args.output.clone().unwrap_or(PathBuf::from("."));
+    let template = args.name_expr.clone().unwrap_or_default();
+
+    let paths = images
+        .iter()
+        .map(|image| image.path.clone())
+        .collect::<Vec<PathBuf>>();
+    let indices = natural_indices(&paths);
+    let directories = match args.preserve_tree {
+        true => relative_dirs(&paths, &args.sources),
+        false => vec![PathBuf::new(); paths.len()],
+    };
+
+    let jobs = images
+        .into_par_iter()
+        .zip(indices)
+        .zip(directories)
+        .filter_map(|((image, index), directory)| {
+            // Only EXIF fields are used in names
+            let metadata = match template.uses_metadata() {
+                true => Some(Metadata {
+                    exif: read_exif(&image.path).ok().flatten(),
+                    ..Default::default()
+                }),
+                false => None,
+            };
+            let fields = NameFields {
+                path: &image.path,
+                index,
+                width: image.width,
+                height: image.height,
+                format: image.format,
+                metadata: metadata.as_ref(),
+            };
+
+            let output_path = template.render(&fields).and_then(|name| {
+                let output_path = destination
+                    .join(directory)
+                    .join(format!("{name}.{}", image.format.extensions_str()[0]));
+                match output_path.exists() && !args.overwrite {
+                    true => Err(rimlib::Error::operation(
+                        Some(&output_path),
+                        "the output already exists, pass --overwrite to replace it",
+                    )),
+                    false => Ok(output_path),
+                }
+            });
+
+            match output_path {
+                Ok(output_path) => Some((image, output_path)),
+                Err(name_error) => {
+                    message_tx
+                        .send(TaskState::Failure(
+                            Stage::Plan,
+                            format!("Skipping: {:?}\nErr:{}", image.path, name_error),
+                        ))
+                        .unwrap_or(());
+                    None
+                }
+            }
+        })
+        .collect::<Vec<(Planned, PathBuf)>>();
+
+    let mut inputs_of = BTreeMap::<&PathBuf, Vec<&PathBuf>>::new();
+    for (image, output_path) in &jobs {
+        inputs_of.entry(output_path).or_default().push(&image.path);
+    }
+    let collisions = inputs_of
+        .iter()
+        .filter(|(_, image_paths)| image_paths.len() > 1)
+        .map(|(output_path, image_paths)| format!("{output_path:?} from {image_paths:?}"))
+        .collect::<Vec<String>>();
+
+    if !collisions.is_empty() {
+        bail!(
+            "several images would be saved to the same path, nothing was saved:\n{}\n\
+             Pass --preserve-tree or name outputs with a template such as {{parent}}_{{stem}}",
+            collisions.join("\n")
+        );
+    }
+    Ok(jobs)
+}
+
+fn decode(
+    args: &ImageArgs,
+    job_rx: Receiver<(PathBuf, PathBuf)>,
+    task_tx: Sender<ImageTask>,
+    slots: InFlight,
+    message_tx: Sender<TaskState>,
+) {
+    for (image_path, output_path) in job_rx {
+        slots.acquire();
+
+        match Decoded::open(args, &image_path) {
             Ok(good_image) => {
-                let task = ImageTask::new(image_path, Some(good_image));
                 message_tx
                     .send(TaskState::Decode(format!(
                         "{:?}",
-                        task.image_path.file_name().as_slice()
+                        image_path.file_name().as_slice()
                     )))
                     .unwrap_or(());
-                acc.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-                task_tx.send(task).unwrap_or_else(|_| {
-                    println!("Task failed to send!");
-                });
+                let task = ImageTask {
+                    image: good_image,
+                    image_path,
+                    output_path,
+                };
+                if task_tx.send(task).is_err() {
+                    slots.release();
+                }
             }
             Err(decode_error) => {
                 message_tx
-                    .send(TaskState::Failure(format!(
-                        "Failed to decode: {:?}\nErr:{:?}",
-                        image_path, decode_error
-                    )))
+                    .send(TaskState::Failure(
+                        Stage::Decode,
+                        format!("Failed to decode: {:?}\nErr:{}", image_path, decode_error),
+                    ))
                     .unwrap_or(());
+                slots.release();
             }
         }
-    });
-    message_tx
-        .send(TaskState::InitProcessing(acc.into_inner() as u64))
-        .unwrap_or(());
+    }
 }
 
 fn process(
-    command: Arc<ImageCommand>,
-    args: Arc<ImageArgs>,
+    pipeline: &Pipeline,
     task_rx: Receiver<ImageTask>,
+    task_tx: Sender<ImageTask>,
+    slots: InFlight,
     message_tx: Sender<TaskState>,
-) -> Vec<ImageTask> {
-    let mut tasks_vec: Vec<ImageTask> = Vec::new();
-    while let Ok(task) = task_rx.recv() {
-        tasks_vec.push(task);
-    }
+) {
+    for task in task_rx {
+        let ImageTask {
+            image,
+            image_path,
+            output_path,
+        } = task;
 
-    let tasks_vec = tasks_vec.par_iter_mut().filter_map(|task| {
-        let result = if let Some(image) = task.image.take() {
-            run_command(command.deref(), image, args.format.as_deref())
-        } else {
-            Err(Error::msg("Something happened"))
-        };
-        match result {
+        match run_command(pipeline, image) {
             Ok(image) => {
-                let message = command_msg(
-                    &command,
-                    &format!("{:?}", task.image_path.file_name().as_slice()),
-                )
-                .unwrap_or(String::from("Error creating message"));
+                let message = format!(
+                    "{}: {:?}",
+                    pipeline.describe(),
+                    image_path.file_name().as_slice()
+                );
 
                 message_tx.send(TaskState::Process(message)).unwrap_or(());
-                task.image = Some(image);
-                Some(task.to_owned())
+                let task = ImageTask {
+                    image,
+                    image_path,
+                    output_path,
+                };
+                if task_tx.send(task).is_err() {
+                    slots.release();
+                }
             }
             Err(error) => {
                 message_tx
-                    .send(TaskState::Failure(format!("Failed operation: {:?}", error)))
+                    .send(TaskState::Failure(
+                        Stage::Process,
+                        format!("Failed operation: {:?}\nErr:{}", image_path, error),
+                    ))
                     .unwrap_or(());
-                None
+                slots.release();
             }
         }
-    });
-    message_tx
-        .send(TaskState::InitSaving(
-            tasks_vec.opt_len().unwrap_or(0) as u64
-        ))
-        .unwrap_or(());
-    tasks_vec.collect()
+    }
 }
 
-fn save_images(tasks: &mut Vec<ImageTask>, message_tx: &Sender<TaskState>, args: &ImageArgs) {
-    let destination = args.output.clone().unwrap_or(PathBuf::from("."));
-    let acc = AtomicUsize::new(0);
-
-    let create_paths = || {
-        create_paths(
-            tasks
-                .par_iter()
-                .map(|task| task.image_path.to_path_buf())
-                .collect(),
-            destination,
-            args.name_expr.as_deref(),
-            args.format.as_deref(),
-        )
-    };
-
-    let paths = match create_paths() {
-        Ok(paths) => paths,
-        Err(e) => {
-            message_tx.send(TaskState::Failure(e)).unwrap_or(());
-            message_tx.send(TaskState::Complete(1)).unwrap_or(());
-            return;
-        }
-    };
-
-    let tasks = tasks.par_drain(..);
-    let tasks = tasks.zip(paths);
-    tasks.for_each_with(message_tx, |message_tx, (task, path)| {
-        let result = if let Some(image) = task.image {
-            save_image_format(&image, &path, args.format.as_deref())
-        } else {
-            message_tx
-                .send(TaskState::Failure("".to_string()))
-                .unwrap_or(());
-            return;
-        };
+fn save(
+    args: &ImageArgs,
+    task_rx: Receiver<ImageTask>,
+    slots: InFlight,
+    saved: &AtomicUsize,
+    message_tx: Sender<TaskState>,
+) {
+    for task in task_rx {
+        let result =
+            create_parent(&task.output_path).and_then(|()| task.image.save(&task.output_path, args));
+        let path = task.output_path;
+        // The image is freed before its slot is given back
+        drop(task.image);
+        slots.release();
 
         match result {
             Ok(()) => {
                 message_tx
                     .send(TaskState::Save(format!("Image saved:{:?}", path)))
                     .unwrap_or(());
-                let num = acc.load(std::sync::atomic::Ordering::Relaxed);
-                acc.fetch_add(num + 1, std::sync::atomic::Ordering::Relaxed);
+                saved.fetch_add(1, Ordering::Relaxed);
             }
 
             Err(e) => message_tx
-                .send(TaskState::Failure(format!("Error: {:?}", e)))
+                .send(TaskState::Failure(Stage::Save, format!("Error: {}", e)))
                 .unwrap_or(()),
         };
-    });
-    message_tx
-        .send(TaskState::Complete(acc.into_inner() as u64))
-        .unwrap_or(());
+    }
+}
+
+/// Creates the directory an output is saved in, and those above it
+fn create_parent(output_path: &Path) -> rimlib::Result<()> {
+    match output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent)
+            .map_err(|e| rimlib::Error::io(Some(parent), e)),
+        _ => Ok(()),
+    }
 }
 
 impl RunBatch for ImageArgs {
     fn run_batch(&self, command: &ImageCommand, verbosity: u32) -> Result<()> {
-        run(command.clone(), self.clone(), verbosity)
+        run(command, self, verbosity)
     }
 }
@@ -1,11 +1,14 @@
 use super::RunSingle;
 use crate::app::command::{ImageArgs, ImageCommand};
-use crate::app::run::{command_msg, run_command};
+use crate::app::run::{Decoded, describe_image, run_command};
 use crate::backend::error::TaskError;
 use crate::backend::paths::prompt_overwrite_single;
 use crate::backend::progress::AppProgressBar;
 use crate::backend::progress::SingleProgressBar;
-use crate::image::manipulator::{open_image, save_image_format};
+use rimlib::image::formats::output_path;
+use rimlib::image::manipulator::probe_image;
+use rimlib::image::operation::Operation;
+use std::path::{Path, PathBuf};
 
 const TASK_COUNT: usize = 4;
 
@@ -13,6 +16,10 @@ impl RunSingle for ImageArgs {
     fn run_single(&self, command: &ImageCommand, verbosity: u32) -> anyhow::Result<()> {
         let image_path = &self.images[0];
 
+        if self.dry_run {
+            return dry_run(self, command);
+        }
+
         let progress_bar = SingleProgressBar::init(verbosity, TASK_COUNT);
 
         progress_bar.start_task(&format!(
@@ -20,21 +27,18 @@ impl RunSingle for ImageArgs {
             image_path.to_path_buf().to_string_lossy()
         ));
 
-        let image = match open_image(image_path) {
+        let image = match Decoded::open(self, image_path) {
             Ok(image) => {
                 progress_bar.message("Image decoded successfully");
                 image
             }
             Err(decode_error) => {
                 progress_bar.abort("Image decode failed");
-                return Err(TaskError::SingleError(decode_error).into());
+                return Err(decode_error.into());
             }
         };
 
-        let output_path = match &self.output {
-            Some(path) => path,
-            None => image_path,
-        };
+        let output_path = &target_path(self, image_path)?;
 
         match output_path.try_exists() {
             Ok(path_exists) => {
@@ -55,13 +59,15 @@ impl RunSingle for ImageArgs {
             output_path.to_path_buf().to_string_lossy()
         ));
 
+        let pipeline = self.pipeline(command)?;
+
         if let Some(filename) = image_path.file_name() {
             if let Some(filename) = filename.to_str() {
-                progress_bar.start_task(&command_msg(command, filename).unwrap());
+                progress_bar.start_task(&format!("{}: {filename}", pipeline.describe()));
             }
         }
 
-        let image = match run_command(command, image, self.format.as_deref()) {
+        let image = match run_command(&pipeline, image) {
             Ok(good_image) => good_image,
             Err(error) => return Err(error),
         };
@@ -71,14 +77,50 @@ impl RunSingle for ImageArgs {
             output_path.to_path_buf().to_string_lossy()
         ));
 
-        match save_image_format(&image, output_path, self.format.as_deref()) {
+        match image.save(output_path, self) {
             Ok(()) => progress_bar.message("Image saved successfully"),
             Err(save_error) => {
                 progress_bar.abort("Image failed to save");
-                return Err(TaskError::SingleError(save_error).into());
+                return Err(save_error.into());
             }
         }
         progress_bar.exit();
         Ok(())
     }
 }
+
+/// Path the image is saved to: the output or the input path, its extension
+/// replaced to match the output format as saving does
+fn target_path(args: &ImageArgs, image_path: &Path) -> rimlib::Result<PathBuf> {
+    let output = args.output.as_deref().unwrap_or(image_path);
+    output_path(output, args.format.as_deref()).map(|(path, _)| path)
+}
+
+/// Prints what running the command would do, without decoding or writing the image
+fn dry_run(args: &ImageArgs, command: &ImageCommand) -> anyhow::Result<()> {
+    let image_path = &args.images[0];
+    let pipeline = args.pipeline(command)?;
+
+    let probe = probe_image(image_path)?;
+    let (width, height) = match args.auto_orient {
+        true => probe.upright_size(),
+        false => (probe.width, probe.height),
+    };
+    let output_size = pipeline
+        .output_size(width, height)
+        .map_err(|e| e.with_path(image_path))?;
+
+    let output = target_path(args, image_path)?;
+
+    println!("{}", pipeline.describe());
+    println!(
+        "{}\n  -> {}",
+        describe_image(image_path, (width, height), probe.color_type.into()),
+        describe_image(&output, output_size, pipeline.output_color(probe.color_type.into()))
+    );
+    if output.exists() && !args.overwrite {
+        println!("{:?} already exists, you would be asked before it is replaced", output);
+    }
+    println!("Dry run, nothing was written");
+    Ok(())
+}
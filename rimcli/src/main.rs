@@ -15,7 +15,7 @@ fn main() {
         }
         Err(e) => {
             eprintln!("{}", e);
-            exit(0);
+            exit(1);
         }
     }
 }
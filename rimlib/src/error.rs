@@ -0,0 +1,224 @@
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use image::ImageError;
+use image::error::UnsupportedErrorKind;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned by rimlib
+///
+/// Each variant keeps the path of the image that caused it, when there is one,
+/// along with the underlying error so callers can decide how to handle it.
+#[derive(Debug)]
+pub enum Error {
+    /// The file could not be opened, read or written
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+
+    /// The image format is unknown or not supported
+    UnsupportedFormat {
+        path: Option<PathBuf>,
+        format: String,
+        source: Option<ImageError>,
+    },
+
+    /// The image data is corrupt or could not be decoded
+    Decode {
+        path: Option<PathBuf>,
+        source: ImageError,
+    },
+
+    /// The image could not be encoded into the chosen format
+    Encode {
+        path: Option<PathBuf>,
+        source: ImageError,
+    },
+
+    /// The color type is invalid or cannot be converted to
+    ColorConversion {
+        path: Option<PathBuf>,
+        reason: String,
+        source: Option<ImageError>,
+    },
+
+    /// A decoder or encoder limit was exceeded
+    Limits {
+        path: Option<PathBuf>,
+        source: ImageError,
+    },
+
+    /// An operation was given parameters it cannot be applied with
+    InvalidOperation {
+        path: Option<PathBuf>,
+        reason: String,
+    },
+}
+
+impl Error {
+    /// Creates an error from one returned while reading or decoding an image
+    pub fn decode(path: Option<&Path>, error: ImageError) -> Self {
+        Self::classify(path, error, false)
+    }
+
+    /// Creates an error from one returned while encoding or writing an image
+    pub fn encode(path: Option<&Path>, error: ImageError) -> Self {
+        Self::classify(path, error, true)
+    }
+
+    /// Creates an io error for the given path
+    pub fn io(path: Option<&Path>, error: io::Error) -> Self {
+        Self::Io {
+            path: path.map(Path::to_path_buf),
+            source: error,
+        }
+    }
+
+    /// Creates an error for a format that could not be obtained or is not supported
+    pub fn unsupported_format(path: Option<&Path>, format: &str) -> Self {
+        Self::UnsupportedFormat {
+            path: path.map(Path::to_path_buf),
+            format: format.to_string(),
+            source: None,
+        }
+    }
+
+    /// Creates an error for a color type that is invalid or unsupported
+    pub fn color(path: Option<&Path>, reason: &str) -> Self {
+        Self::ColorConversion {
+            path: path.map(Path::to_path_buf),
+            reason: reason.to_string(),
+            source: None,
+        }
+    }
+
+    /// Creates an error for an operation that cannot be applied
+    pub fn operation(path: Option<&Path>, reason: &str) -> Self {
+        Self::InvalidOperation {
+            path: path.map(Path::to_path_buf),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Sets the path of the image that caused the error if it has none
+    pub fn with_path(mut self, image_path: &Path) -> Self {
+        match &mut self {
+            Error::Io { path, .. }
+            | Error::UnsupportedFormat { path, .. }
+            | Error::Decode { path, .. }
+            | Error::Encode { path, .. }
+            | Error::ColorConversion { path, .. }
+            | Error::Limits { path, .. }
+            | Error::InvalidOperation { path, .. } => {
+                path.get_or_insert_with(|| image_path.to_path_buf());
+            }
+        }
+        self
+    }
+
+    /// Path of the image that caused the error, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. }
+            | Error::UnsupportedFormat { path, .. }
+            | Error::Decode { path, .. }
+            | Error::Encode { path, .. }
+            | Error::ColorConversion { path, .. }
+            | Error::Limits { path, .. }
+            | Error::InvalidOperation { path, .. } => path.as_deref(),
+        }
+    }
+
+    fn classify(path: Option<&Path>, error: ImageError, encoding: bool) -> Self {
+        let path = path.map(Path::to_path_buf);
+
+        match error {
+            ImageError::IoError(source) => Error::Io { path, source },
+            ImageError::Limits(_) => Error::Limits {
+                path,
+                source: error,
+            },
+            ImageError::Unsupported(ref unsupported) => match unsupported.kind() {
+                UnsupportedErrorKind::Color(color) => Error::ColorConversion {
+                    path,
+                    reason: format!("{color:?} is not supported"),
+                    source: Some(error),
+                },
+                UnsupportedErrorKind::Format(hint) => Error::UnsupportedFormat {
+                    path,
+                    format: hint.to_string(),
+                    source: Some(error),
+                },
+                _ if encoding => Error::Encode {
+                    path,
+                    source: error,
+                },
+                _ => Error::Decode {
+                    path,
+                    source: error,
+                },
+            },
+            ImageError::Encoding(_) => Error::Encode {
+                path,
+                source: error,
+            },
+            ImageError::Decoding(_) => Error::Decode {
+                path,
+                source: error,
+            },
+            ImageError::Parameter(_) if encoding => Error::Encode {
+                path,
+                source: error,
+            },
+            ImageError::Parameter(_) => Error::Decode {
+                path,
+                source: error,
+            },
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = match self.path() {
+            Some(path) => format!(" {path:?}"),
+            None => String::new(),
+        };
+
+        match self {
+            Error::Io { source, .. } => write!(f, "Error accessing file{path}: {source}"),
+            Error::UnsupportedFormat { format, .. } => {
+                write!(f, "Unsupported image format{path}: {format}")
+            }
+            Error::Decode { source, .. } => write!(f, "Error decoding image{path}: {source}"),
+            Error::Encode { source, .. } => write!(f, "Error encoding image{path}: {source}"),
+            Error::ColorConversion { reason, .. } => {
+                write!(f, "Invalid color conversion{path}: {reason}")
+            }
+            Error::Limits { source, .. } => write!(f, "Image limits exceeded{path}: {source}"),
+            Error::InvalidOperation { reason, .. } => {
+                write!(f, "Invalid operation{path}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Decode { source, .. }
+            | Error::Encode { source, .. }
+            | Error::Limits { source, .. } => Some(source),
+            Error::UnsupportedFormat { source, .. } | Error::ColorConversion { source, .. } => {
+                source
+                    .as_ref()
+                    .map(|e| e as &(dyn std::error::Error + 'static))
+            }
+            Error::InvalidOperation { .. } => None,
+        }
+    }
+}
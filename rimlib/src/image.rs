@@ -1,7 +1,24 @@
+pub mod adjust;
+pub mod animation;
 pub mod color;
+pub mod compare;
+mod font;
 pub mod formats;
+pub mod geometry;
+pub mod hash;
+mod heif;
+mod ifd;
 pub mod info;
+pub mod input;
 pub mod manipulator;
+pub mod metadata;
+pub mod montage;
+pub mod naming;
+pub mod operation;
+pub mod overlay;
 pub mod pixels;
+pub mod quantize;
 pub mod randomize;
+pub mod stats;
+pub mod transform;
 pub mod transparency;
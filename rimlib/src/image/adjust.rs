@@ -0,0 +1,207 @@
+use super::color::ColorInfo;
+use super::pixels::Channel;
+use crate::error::{Error, Result};
+use image::DynamicImage;
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSliceMut;
+use std::fmt::Display;
+
+/// Rec. 709 weights of the red, green and blue channels in the luminance
+const LUMA_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// A tonal or spatial filter applied to every color channel.
+///
+/// Values are computed on channels scaled to 0.0 to 1.0 at the bit depth of the image,
+/// so 16-bit and float images are never quantised to 8 bits.
+/// Float images are not clamped, keeping values above 1.0 for HDR content.
+/// Alpha channels are left untouched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjustment {
+    /// Gaussian blur with the given standard deviation in pixels
+    Blur {
+        sigma: f32,
+    },
+    /// Adds `amount` times the difference from a Gaussian blur,
+    /// skipping differences below `threshold` to leave noise alone
+    Unsharp {
+        sigma: f32,
+        amount: f32,
+        threshold: f32,
+    },
+    /// Adds to every channel, from -1.0 to 1.0
+    Brightness(f32),
+    /// Scales the distance from middle gray, 1.0 leaves the image unchanged
+    Contrast(f32),
+    /// Gamma correction, values above 1.0 brighten midtones
+    Gamma(f32),
+    /// Exposure change in stops, each one doubling the light
+    Exposure(f32),
+    Invert,
+    /// Replaces the color channels with their luminance, keeping the color type
+    Grayscale,
+}
+
+impl Adjustment {
+    /// Checks the parameters are in range
+    pub fn validate(&self) -> Result<()> {
+        let valid = match *self {
+            Adjustment::Blur { sigma } => sigma.is_finite() && sigma > 0.0,
+            Adjustment::Unsharp {
+                sigma,
+                amount,
+                threshold,
+            } => {
+                sigma.is_finite()
+                    && sigma > 0.0
+                    && amount.is_finite()
+                    && (0.0..=1.0).contains(&threshold)
+            }
+            Adjustment::Brightness(value) => (-1.0..=1.0).contains(&value),
+            Adjustment::Contrast(factor) => factor.is_finite() && factor >= 0.0,
+            Adjustment::Gamma(gamma) => gamma.is_finite() && gamma > 0.0,
+            Adjustment::Exposure(stops) => stops.is_finite(),
+            Adjustment::Invert | Adjustment::Grayscale => true,
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(Error::operation(
+                None,
+                &format!("invalid adjustment: {self}"),
+            )),
+        }
+    }
+
+    /// Applies the adjustment, keeping the color type of the image
+    pub fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        self.validate()?;
+
+        match *self {
+            Adjustment::Blur { sigma } => Ok(image.blur(sigma)),
+            Adjustment::Unsharp {
+                sigma,
+                amount,
+                threshold,
+            } => unsharp(image, sigma, amount, threshold),
+            Adjustment::Brightness(value) => Ok(map_channels(image, |v| v + value)),
+            Adjustment::Contrast(factor) => Ok(map_channels(image, |v| (v - 0.5) * factor + 0.5)),
+            Adjustment::Gamma(gamma) => Ok(map_channels(image, |v| v.max(0.0).powf(1.0 / gamma))),
+            Adjustment::Exposure(stops) => {
+                let factor = 2f32.powf(stops);
+                Ok(map_channels(image, |v| v * factor))
+            }
+            Adjustment::Invert => Ok(map_channels(image, |v| 1.0 - v)),
+            Adjustment::Grayscale => Ok(map_pixels(image, |color| {
+                if let [r, g, b] = color {
+                    let luma = LUMA_WEIGHTS[0] * *r + LUMA_WEIGHTS[1] * *g + LUMA_WEIGHTS[2] * *b;
+                    (*r, *g, *b) = (luma, luma, luma);
+                }
+            })),
+        }
+    }
+}
+
+impl Display for Adjustment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Adjustment::Blur { sigma } => write!(f, "blur {sigma}"),
+            Adjustment::Unsharp {
+                sigma,
+                amount,
+                threshold,
+            } => write!(f, "unsharp {sigma}, amount {amount}, threshold {threshold}"),
+            Adjustment::Brightness(value) => write!(f, "brightness {value}"),
+            Adjustment::Contrast(factor) => write!(f, "contrast {factor}"),
+            Adjustment::Gamma(gamma) => write!(f, "gamma {gamma}"),
+            Adjustment::Exposure(stops) => write!(f, "exposure {stops}"),
+            Adjustment::Invert => write!(f, "invert"),
+            Adjustment::Grayscale => write!(f, "grayscale"),
+        }
+    }
+}
+
+fn unsharp(image: DynamicImage, sigma: f32, amount: f32, threshold: f32) -> Result<DynamicImage> {
+    let color = ColorInfo::from_image(&image);
+    let blurred = DynamicImage::ImageRgba32F(image.to_rgba32f()).blur(sigma);
+    let blurred = blurred.as_rgba32f().map(|buffer| buffer.as_raw());
+
+    let mut sharpened = image.into_rgba32f();
+    if let Some(blurred) = blurred {
+        let data: &mut [f32] = &mut sharpened;
+        for (pixel, blurred) in data.chunks_exact_mut(4).zip(blurred.chunks_exact(4)) {
+            for (value, blurred) in pixel[..3].iter_mut().zip(blurred) {
+                let difference = *value - blurred;
+                if difference.abs() >= threshold {
+                    *value += amount * difference;
+                }
+            }
+        }
+    }
+
+    color.convert_image(DynamicImage::ImageRgba32F(sharpened))
+}
+
+/// Applies a function to every color channel of every pixel
+fn map_channels<F>(image: DynamicImage, function: F) -> DynamicImage
+where
+    F: Fn(f32) -> f32 + Sync,
+{
+    map_pixels(image, |color| {
+        color.iter_mut().for_each(|value| *value = function(*value));
+    })
+}
+
+/// Applies a function to the color channels of every pixel, scaled to 0.0 to 1.0
+fn map_pixels<F>(mut image: DynamicImage, function: F) -> DynamicImage
+where
+    F: Fn(&mut [f32]) + Sync,
+{
+    let channels = image.color().channel_count() as usize;
+    let color_channels = channels - image.color().has_alpha() as usize;
+
+    match &mut image {
+        DynamicImage::ImageLuma8(buffer) => map_buffer(buffer, channels, color_channels, &function),
+        DynamicImage::ImageLumaA8(buffer) => {
+            map_buffer(buffer, channels, color_channels, &function)
+        }
+        DynamicImage::ImageRgb8(buffer) => map_buffer(buffer, channels, color_channels, &function),
+        DynamicImage::ImageRgba8(buffer) => map_buffer(buffer, channels, color_channels, &function),
+        DynamicImage::ImageLuma16(buffer) => {
+            map_buffer(buffer, channels, color_channels, &function)
+        }
+        DynamicImage::ImageLumaA16(buffer) => {
+            map_buffer(buffer, channels, color_channels, &function)
+        }
+        DynamicImage::ImageRgb16(buffer) => map_buffer(buffer, channels, color_channels, &function),
+        DynamicImage::ImageRgba16(buffer) => {
+            map_buffer(buffer, channels, color_channels, &function)
+        }
+        DynamicImage::ImageRgb32F(buffer) => {
+            map_buffer(buffer, channels, color_channels, &function)
+        }
+        DynamicImage::ImageRgba32F(buffer) => {
+            map_buffer(buffer, channels, color_channels, &function)
+        }
+        _ => (),
+    }
+    image
+}
+
+fn map_buffer<T, F>(data: &mut [T], channels: usize, color_channels: usize, function: &F)
+where
+    T: Channel,
+    F: Fn(&mut [f32]) + Sync,
+{
+    data.par_chunks_mut(channels).for_each(|pixel| {
+        let mut color = [0.0; 4];
+        for (unit, value) in color.iter_mut().zip(&pixel[..color_channels]) {
+            *unit = value.to_unit();
+        }
+
+        function(&mut color[..color_channels]);
+
+        for (value, unit) in pixel[..color_channels].iter_mut().zip(color) {
+            *value = T::from_unit(unit);
+        }
+    });
+}
@@ -0,0 +1,438 @@
+use super::formats::{
+    EncodeOptions, gif_error, gif_size, indexed_gif_frame, output_path, png_error, set_png_options,
+};
+use super::info::count_frames;
+use super::manipulator::{probe_image, read_metadata};
+use super::metadata::{Metadata, supports_metadata};
+use super::quantize::IndexedImage;
+use crate::error::{Error, Result};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::error::DecodingError;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageError, ImageFormat};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Sampling factor used to build the palette of GIF frames with more than 256 colors,
+/// from 1 (slowest, best) to 30
+const GIF_SPEED: i32 = 10;
+
+/// How many times an animation plays
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoopCount {
+    #[default]
+    Infinite,
+    /// Plays this many times, at least once
+    Times(u32),
+}
+
+/// A single frame of an animation, covering the whole canvas
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationFrame {
+    pub image: DynamicImage,
+    /// How long the frame is shown
+    pub delay: Duration,
+}
+
+/// WebP frame flag drawing the frame over the canvas instead of blending it
+const WEBP_NO_BLEND: u8 = 0x02;
+const WEBP_FLAG_ALPHA: u8 = 0x10;
+const WEBP_FLAG_ANIMATION: u8 = 0x02;
+/// WebP frame durations and canvas sizes are 24-bit numbers
+const WEBP_MAX_U24: u32 = 0xFF_FFFF;
+
+/// The frames of an animated GIF, APNG or WebP image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    pub loop_count: LoopCount,
+}
+
+impl Animation {
+    /// Size of the canvas, the size of every frame
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.frames
+            .first()
+            .map(|frame| (frame.image.width(), frame.image.height()))
+            .unwrap_or_default()
+    }
+
+    /// Applies a function to every frame in parallel, keeping the delays.
+    ///
+    /// Fails if the frames no longer share the same size afterwards.
+    pub fn map_frames<F>(self, function: F) -> Result<Self>
+    where
+        F: Fn(DynamicImage) -> Result<DynamicImage> + Send + Sync,
+    {
+        let frames = self
+            .frames
+            .into_par_iter()
+            .map(|frame| {
+                Ok(AnimationFrame {
+                    image: function(frame.image)?,
+                    delay: frame.delay,
+                })
+            })
+            .collect::<Result<Vec<AnimationFrame>>>()?;
+
+        let animation = Self {
+            frames,
+            loop_count: self.loop_count,
+        };
+
+        let size = animation.dimensions();
+        if animation
+            .frames
+            .iter()
+            .any(|frame| (frame.image.width(), frame.image.height()) != size)
+        {
+            return Err(Error::operation(
+                None,
+                "the frames of the animation ended up with different sizes",
+            ));
+        }
+
+        Ok(animation)
+    }
+}
+
+/// Whether the format can store animations when saving
+pub fn supports_animation(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP
+    )
+}
+
+/// Decodes every frame of an animated GIF, APNG or WebP image,
+/// along with their delays and loop count.
+///
+/// Returns `None` for still images and formats that cannot be animated,
+/// which should be opened with [`open_image`](super::manipulator::open_image).
+/// Frames are counted from the file structure first, so still images are not decoded.
+pub fn open_animation(path: &Path) -> Result<Option<Animation>> {
+    let format = probe_image(path)?.format;
+    if count_frames(path, format).map_err(|e| e.with_path(path))? < 2 {
+        return Ok(None);
+    }
+
+    let open = || {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| Error::io(Some(path), e))
+    };
+    let decode_error = |e| Error::decode(Some(path), e);
+
+    let (frames, loop_count) = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(open()?).map_err(decode_error)?;
+            (
+                collect_frames(decoder.into_frames()).map_err(decode_error)?,
+                gif_loop_count(open()?).map_err(decode_error)?,
+            )
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(open()?).map_err(decode_error)?;
+            let frames = decoder.apng().map_err(decode_error)?.into_frames();
+            (
+                collect_frames(frames).map_err(decode_error)?,
+                apng_loop_count(open()?).map_err(decode_error)?,
+            )
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(open()?).map_err(decode_error)?;
+            (
+                collect_frames(decoder.into_frames()).map_err(decode_error)?,
+                webp_loop_count(open()?).map_err(decode_error)?,
+            )
+        }
+        _ => return Ok(None),
+    };
+
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    Ok(Some(Animation { frames, loop_count }))
+}
+
+/// Decodes an animation like [`open_animation`], along with its EXIF, ICC and XMP metadata
+pub fn open_animation_with_metadata(path: &Path) -> Result<Option<(Animation, Metadata)>> {
+    match open_animation(path)? {
+        Some(animation) => Ok(Some((animation, read_metadata(path)?))),
+        None => Ok(None),
+    }
+}
+
+/// Decodes an animation with its metadata and turns every frame upright
+/// according to its EXIF orientation.
+///
+/// The orientation in the returned metadata is reset so viewers
+/// do not rotate the frames a second time.
+pub fn open_animation_oriented(path: &Path) -> Result<Option<(Animation, Metadata)>> {
+    let Some((mut animation, mut metadata)) = open_animation_with_metadata(path)? else {
+        return Ok(None);
+    };
+
+    if let Some(orientation) = metadata.orientation() {
+        for frame in &mut animation.frames {
+            frame.image.apply_orientation(orientation);
+        }
+        metadata.reset_orientation();
+    }
+
+    Ok(Some((animation, metadata)))
+}
+
+fn collect_frames(frames: Frames) -> image::ImageResult<Vec<AnimationFrame>> {
+    frames
+        .map(|frame| {
+            let frame = frame?;
+            Ok(AnimationFrame {
+                delay: frame.delay().into(),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            })
+        })
+        .collect()
+}
+
+/// Reads the loop count of a GIF, which the image crate decoder does not expose.
+/// GIF stores how many times the animation repeats after the first play.
+fn gif_loop_count(reader: BufReader<File>) -> image::ImageResult<LoopCount> {
+    let decoding_error = |e| ImageError::Decoding(DecodingError::new(ImageFormat::Gif.into(), e));
+
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(reader)
+        .map_err(decoding_error)?;
+    // The looping extension comes before the first frame
+    decoder.next_frame_info().map_err(decoding_error)?;
+
+    Ok(match decoder.repeat() {
+        gif::Repeat::Infinite => LoopCount::Infinite,
+        gif::Repeat::Finite(repeats) => LoopCount::Times(u32::from(repeats) + 1),
+    })
+}
+
+fn apng_loop_count(reader: BufReader<File>) -> image::ImageResult<LoopCount> {
+    let decoding_error = |e| ImageError::Decoding(DecodingError::new(ImageFormat::Png.into(), e));
+
+    let reader = ::png::Decoder::new(reader)
+        .read_info()
+        .map_err(decoding_error)?;
+
+    Ok(match reader.info().animation_control() {
+        Some(control) if control.num_plays > 0 => LoopCount::Times(control.num_plays),
+        _ => LoopCount::Infinite,
+    })
+}
+
+fn webp_loop_count(reader: BufReader<File>) -> image::ImageResult<LoopCount> {
+    let decoder = image_webp::WebPDecoder::new(reader)
+        .map_err(|e| ImageError::Decoding(DecodingError::new(ImageFormat::WebP.into(), e)))?;
+
+    Ok(match decoder.loop_count() {
+        image_webp::LoopCount::Forever => LoopCount::Infinite,
+        image_webp::LoopCount::Times(times) => LoopCount::Times(u32::from(times.get())),
+    })
+}
+
+/// Saves every frame of the animation as an animated GIF, APNG or WebP,
+/// writing the metadata into the file.
+///
+/// Other formats cannot store animations and return an error, as does
+/// metadata saved to GIF which cannot hold it.
+/// The extension of the output path is replaced to match the format.
+pub fn save_animation(
+    animation: &Animation,
+    out: &Path,
+    format: Option<&str>,
+    options: &EncodeOptions,
+    metadata: &Metadata,
+) -> Result<()> {
+    let (out_path, image_format) = output_path(out, format)?;
+
+    if !supports_animation(image_format) {
+        return Err(Error::operation(
+            Some(&out_path),
+            &format!(
+                "{image_format:?} cannot store animations, save to GIF, PNG or WebP or keep only the first frame"
+            ),
+        ));
+    }
+    if !metadata.is_empty() && !supports_metadata(image_format) {
+        return Err(Error::unsupported_format(
+            Some(&out_path),
+            &format!("{image_format:?} cannot hold EXIF, ICC or XMP metadata"),
+        ));
+    }
+    if options.indexed && image_format == ImageFormat::Png {
+        return Err(Error::operation(
+            Some(&out_path),
+            "indexed color is not supported for animated PNG",
+        ));
+    }
+    options
+        .validate(image_format)
+        .map_err(|e| e.with_path(&out_path))?;
+
+    let mut encoded = Vec::new();
+    match image_format {
+        ImageFormat::Gif => encode_gif(animation, &mut encoded),
+        ImageFormat::WebP => encode_webp(animation, &mut encoded),
+        _ => encode_apng(animation, &mut encoded, options),
+    }
+    .map_err(|e| Error::encode(Some(&out_path), e))?;
+
+    let encoded = metadata
+        .embed(encoded, image_format)
+        .map_err(|e| e.with_path(&out_path))?;
+
+    std::fs::write(&out_path, encoded).map_err(|e| Error::io(Some(&out_path), e))
+}
+
+/// Writes every frame with its own palette.
+/// Frames with at most 256 colors keep their exact colors,
+/// others are quantized with NeuQuant.
+fn encode_gif<W: Write>(animation: &Animation, writer: &mut W) -> image::ImageResult<()> {
+    let (width, height) = animation.dimensions();
+    let (width, height) = gif_size(width, height)?;
+
+    let mut encoder = gif::Encoder::new(writer, width, height, &[]).map_err(gif_error)?;
+    encoder
+        .set_repeat(match animation.loop_count {
+            LoopCount::Infinite => gif::Repeat::Infinite,
+            LoopCount::Times(times) => {
+                gif::Repeat::Finite(times.saturating_sub(1).min(u16::MAX as u32) as u16)
+            }
+        })
+        .map_err(gif_error)?;
+
+    for frame in &animation.frames {
+        let mut gif_frame = match IndexedImage::from_image(&frame.image) {
+            Ok(indexed) => {
+                let (palette, mut gif_frame) = indexed_gif_frame(&indexed, width, height);
+                gif_frame.palette = Some(palette);
+                gif_frame
+            }
+            Err(_) => {
+                let mut rgba = frame.image.to_rgba8().into_raw();
+                gif::Frame::from_rgba_speed(width, height, &mut rgba, GIF_SPEED)
+            }
+        };
+        // GIF delays are in hundredths of a second
+        gif_frame.delay = (frame.delay.as_millis() / 10).min(u16::MAX as u128) as u16;
+        // Frames cover the whole canvas, so the previous one is cleared
+        // for transparent pixels not to show it
+        gif_frame.dispose = gif::DisposalMethod::Background;
+
+        encoder.write_frame(&gif_frame).map_err(gif_error)?;
+    }
+    Ok(())
+}
+
+/// Writes every frame as 8-bit RGBA
+fn encode_apng<W: Write>(
+    animation: &Animation,
+    writer: &mut W,
+    options: &EncodeOptions,
+) -> image::ImageResult<()> {
+    let (width, height) = animation.dimensions();
+
+    let mut encoder = ::png::Encoder::new(writer, width, height);
+    encoder.set_color(::png::ColorType::Rgba);
+    encoder.set_depth(::png::BitDepth::Eight);
+    set_png_options(&mut encoder, options);
+    encoder
+        .set_animated(
+            animation.frames.len() as u32,
+            match animation.loop_count {
+                LoopCount::Infinite => 0,
+                LoopCount::Times(times) => times.max(1),
+            },
+        )
+        .map_err(png_error)?;
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for frame in &animation.frames {
+        let millis = frame.delay.as_millis().min(u16::MAX as u128) as u16;
+        writer.set_frame_delay(millis, 1000).map_err(png_error)?;
+        writer
+            .write_image_data(frame.image.to_rgba8().as_raw())
+            .map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)
+}
+
+/// Writes every frame as a lossless WebP frame covering the whole canvas
+fn encode_webp(animation: &Animation, writer: &mut Vec<u8>) -> image::ImageResult<()> {
+    let (width, height) = animation.dimensions();
+    if width > WEBP_MAX_U24 + 1 || height > WEBP_MAX_U24 + 1 {
+        return Err(ImageError::Encoding(image::error::EncodingError::new(
+            ImageFormat::WebP.into(),
+            format!("{width}x{height} is too large for an animated WebP"),
+        )));
+    }
+    let canvas = [(width - 1).to_le_bytes(), (height - 1).to_le_bytes()];
+
+    let mut chunks = Vec::new();
+
+    let mut vp8x = vec![WEBP_FLAG_ANIMATION | WEBP_FLAG_ALPHA, 0, 0, 0];
+    vp8x.extend(canvas.iter().flat_map(|size| &size[..3]));
+    write_webp_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    // Transparent background, the loop count is stored as is, 0 meaning forever
+    let loops = match animation.loop_count {
+        LoopCount::Infinite => 0,
+        LoopCount::Times(times) => times.clamp(1, u16::MAX as u32) as u16,
+    };
+    let mut anim = vec![0; 4];
+    anim.extend(loops.to_le_bytes());
+    write_webp_chunk(&mut chunks, b"ANIM", &anim);
+
+    for frame in &animation.frames {
+        let mut still = Vec::new();
+        image_webp::WebPEncoder::new(&mut still)
+            .encode(
+                frame.image.to_rgba8().as_raw(),
+                width,
+                height,
+                image_webp::ColorType::Rgba8,
+            )
+            .map_err(|e| {
+                ImageError::Encoding(image::error::EncodingError::new(
+                    ImageFormat::WebP.into(),
+                    e,
+                ))
+            })?;
+
+        // Frame at the origin, then its size, duration and flags, then the
+        // VP8L chunk of the still image following the RIFF header
+        let millis = frame.delay.as_millis().min(WEBP_MAX_U24 as u128) as u32;
+        let mut anmf = vec![0; 6];
+        anmf.extend(canvas.iter().flat_map(|size| &size[..3]));
+        anmf.extend(&millis.to_le_bytes()[..3]);
+        anmf.push(WEBP_NO_BLEND);
+        anmf.extend(&still[12..]);
+        write_webp_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    writer.extend(b"RIFF");
+    writer.extend((chunks.len() as u32 + 4).to_le_bytes());
+    writer.extend(b"WEBP");
+    writer.extend(chunks);
+    Ok(())
+}
+
+fn write_webp_chunk(writer: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    writer.extend(id);
+    writer.extend((data.len() as u32).to_le_bytes());
+    writer.extend(data);
+    // Chunks are padded to an even size
+    if data.len() % 2 == 1 {
+        writer.push(0);
+    }
+}
@@ -3,31 +3,37 @@ use std::str::FromStr;
 
 use image::{ColorType, DynamicImage, ExtendedColorType};
 
+use crate::error::{Error, Result};
+
 /// Unified trait representing color data
 pub trait ColorData {
-    fn color_info(&self) -> ColorInfo;
-    fn color_type(&self) -> ColorType;
+    /// Layout of this color data.
+    /// Fails for layouts with no known bit depth.
+    fn color_info(&self) -> Result<ColorInfo>;
+    /// Color type of a `DynamicImage` holding this color data.
+    /// Fails when no `DynamicImage` variant can hold it.
+    fn color_type(&self) -> Result<ColorType>;
     fn color_type_extended(&self) -> ExtendedColorType;
 }
 
 impl ColorData for ColorInfo {
-    fn color_info(&self) -> ColorInfo {
-        *self
+    fn color_info(&self) -> Result<ColorInfo> {
+        Ok(*self)
     }
-    fn color_type(&self) -> ColorType {
+    fn color_type(&self) -> Result<ColorType> {
         self.to_color_type()
     }
     fn color_type_extended(&self) -> ExtendedColorType {
-        self.to_color_type().into()
+        self.to_color_type_extended()
     }
 }
 
 impl ColorData for ColorType {
-    fn color_info(&self) -> ColorInfo {
-        ColorInfo::from(*self)
+    fn color_info(&self) -> Result<ColorInfo> {
+        Ok(ColorInfo::from(*self))
     }
-    fn color_type(&self) -> ColorType {
-        *self
+    fn color_type(&self) -> Result<ColorType> {
+        Ok(*self)
     }
     fn color_type_extended(&self) -> ExtendedColorType {
         (*self).into()
@@ -35,25 +41,45 @@ impl ColorData for ColorType {
 }
 
 impl ColorData for ExtendedColorType {
-    fn color_info(&self) -> ColorInfo {
-        ColorInfo::default()
+    fn color_info(&self) -> Result<ColorInfo> {
+        ColorInfo::try_from(*self)
     }
-    fn color_type(&self) -> ColorType {
-        ColorInfo::default().color_type()
+    /// Color type the image crate decodes this source color type into
+    fn color_type(&self) -> Result<ColorType> {
+        match self {
+            ExtendedColorType::Bgr8 => Ok(ColorType::Rgb8),
+            ExtendedColorType::Bgra8 => Ok(ColorType::Rgba8),
+            _ => {
+                let info = self.color_info()?;
+                match info.bit_depth {
+                    // Packed pixels are expanded to 8 bits per channel
+                    BitDepth::B1 | BitDepth::B2 | BitDepth::B4 => {
+                        ColorInfo::new(&info.color_space, &BitDepth::B8).to_color_type()
+                    }
+                    _ => info.to_color_type(),
+                }
+            }
+        }
     }
     fn color_type_extended(&self) -> ExtendedColorType {
         *self
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Pixel layout of an image, as a color space and a bit depth per channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ColorInfo {
     pub bit_depth: BitDepth,
     pub color_space: ColorSpace,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Bits per channel, 32-bit channels are floating point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BitDepth {
+    B1 = 1,
+    B2 = 2,
+    B4 = 4,
     B8 = 8,
     B16 = 16,
     B32 = 32,
@@ -61,35 +87,82 @@ pub enum BitDepth {
 
 impl Display for BitDepth {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            BitDepth::B8 => write!(f,"8"),
-            BitDepth::B16 => write!(f,"16"),
-            BitDepth::B32 => write!(f,"32")
-        }
+        write!(f, "{}", *self as u8)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for BitDepth {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
     }
 }
 
 impl FromStr for BitDepth {
-    type Err = String;
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.trim()
+            .parse::<u8>()
+            .ok()
+            .and_then(|bits| BitDepth::try_from(bits).ok())
+            .ok_or_else(|| {
+                Error::color(
+                    None,
+                    &format!("invalid bit depth {s:?}, must be 1, 2, 4, 8, 16 or 32"),
+                )
+            })
+    }
+}
+
+impl BitDepth {
+    /// Smallest depth of the indices into a palette of this many colors
+    pub fn for_palette(colors: usize) -> Self {
+        match colors {
+            0..=2 => BitDepth::B1,
+            3..=4 => BitDepth::B2,
+            5..=16 => BitDepth::B4,
+            _ => BitDepth::B8,
+        }
+    }
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let num: u32 = s.parse::<u32>().unwrap();
+impl TryFrom<u8> for BitDepth {
+    type Error = Error;
 
-        match num {
+    fn try_from(bits: u8) -> Result<Self> {
+        match bits {
+            1 => Ok(BitDepth::B1),
+            2 => Ok(BitDepth::B2),
+            4 => Ok(BitDepth::B4),
             8 => Ok(BitDepth::B8),
             16 => Ok(BitDepth::B16),
             32 => Ok(BitDepth::B32),
-            _ => Err("Bit depth must be 8, 16 or 32.".into()),
+            _ => Err(Error::color(
+                None,
+                &format!("{bits}-bit channels are not supported"),
+            )),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ColorSpace {
     Rgb,
     RgbA,
+    Bgr,
+    BgrA,
     Luma,
     LumaA,
+    /// Alpha channel only
+    Alpha,
+    Cmyk,
+    /// Palette indices into a color table
+    Indexed,
     Unknown,
 }
 
@@ -99,6 +172,30 @@ impl Display for ColorSpace {
     }
 }
 
+impl FromStr for ColorSpace {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "rgb" => Ok(ColorSpace::Rgb),
+            "rgba" => Ok(ColorSpace::RgbA),
+            "bgr" => Ok(ColorSpace::Bgr),
+            "bgra" => Ok(ColorSpace::BgrA),
+            "luma" | "gray" | "grey" => Ok(ColorSpace::Luma),
+            "lumaa" | "luma-alpha" => Ok(ColorSpace::LumaA),
+            "alpha" => Ok(ColorSpace::Alpha),
+            "cmyk" => Ok(ColorSpace::Cmyk),
+            "indexed" | "palette" => Ok(ColorSpace::Indexed),
+            _ => Err(Error::color(
+                None,
+                &format!(
+                    "invalid color space {s:?}, must be rgb, rgba, bgr, bgra, luma, lumaa, alpha, cmyk or indexed"
+                ),
+            )),
+        }
+    }
+}
+
 impl Default for ColorInfo {
     fn default() -> Self {
         Self {
@@ -115,54 +212,14 @@ impl ColorInfo {
             bit_depth: *bit_depth,
         }
     }
+
     pub fn from_image(image: &DynamicImage) -> Self {
-        match image.color() {
-            ColorType::L8 => ColorInfo {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::Luma,
-            },
-            ColorType::La8 => ColorInfo {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::LumaA,
-            },
-            ColorType::L16 => ColorInfo {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::Luma,
-            },
-            ColorType::La16 => ColorInfo {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::LumaA,
-            },
-            ColorType::Rgb8 => ColorInfo {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgba8 => ColorInfo {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::RgbA,
-            },
-            ColorType::Rgb16 => ColorInfo {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgba16 => ColorInfo {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgb32F => ColorInfo {
-                bit_depth: BitDepth::B32,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgba32F => ColorInfo {
-                bit_depth: BitDepth::B32,
-                color_space: ColorSpace::RgbA,
-            },
-            _ => ColorInfo::default(),
-        }
+        ColorInfo::from(image.color())
     }
-    pub fn convert_image(&self, image: DynamicImage) -> DynamicImage {
-        let color_type = self.to_color_type();
-        match color_type {
+
+    /// Converts the image to this color type
+    pub fn convert_image(&self, image: DynamicImage) -> Result<DynamicImage> {
+        Ok(match self.to_color_type()? {
             ColorType::L8 => DynamicImage::ImageLuma8(image.to_luma8()),
             ColorType::La8 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
             ColorType::L16 => DynamicImage::ImageLuma16(image.to_luma16()),
@@ -173,79 +230,177 @@ impl ColorInfo {
             ColorType::Rgba16 => DynamicImage::ImageRgba16(image.to_rgba16()),
             ColorType::Rgb32F => DynamicImage::ImageRgb32F(image.to_rgb32f()),
             ColorType::Rgba32F => DynamicImage::ImageRgba32F(image.to_rgba32f()),
-            _ => image,
+            _ => return Err(self.unsupported()),
+        })
+    }
+
+    /// Color type of a `DynamicImage` with this layout.
+    ///
+    /// Fails for layouts only found in encoded files, such as packed,
+    /// indexed, BGR or CMYK pixels, and for 32-bit luma which `DynamicImage`
+    /// has no variant for.
+    pub fn to_color_type(self) -> Result<ColorType> {
+        match (self.color_space, self.bit_depth) {
+            (ColorSpace::Rgb, BitDepth::B8) => Ok(ColorType::Rgb8),
+            (ColorSpace::Rgb, BitDepth::B16) => Ok(ColorType::Rgb16),
+            (ColorSpace::Rgb, BitDepth::B32) => Ok(ColorType::Rgb32F),
+            (ColorSpace::RgbA, BitDepth::B8) => Ok(ColorType::Rgba8),
+            (ColorSpace::RgbA, BitDepth::B16) => Ok(ColorType::Rgba16),
+            (ColorSpace::RgbA, BitDepth::B32) => Ok(ColorType::Rgba32F),
+            (ColorSpace::Luma, BitDepth::B8) => Ok(ColorType::L8),
+            (ColorSpace::Luma, BitDepth::B16) => Ok(ColorType::L16),
+            (ColorSpace::LumaA, BitDepth::B8) => Ok(ColorType::La8),
+            (ColorSpace::LumaA, BitDepth::B16) => Ok(ColorType::La16),
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    /// Color type as stored in an encoded file.
+    /// Layouts with no equivalent are reported as `Unknown` with their bit depth.
+    pub fn to_color_type_extended(self) -> ExtendedColorType {
+        use BitDepth::*;
+        use ColorSpace::*;
+
+        match (self.color_space, self.bit_depth) {
+            (Alpha, B8) => ExtendedColorType::A8,
+            (Luma, B1) => ExtendedColorType::L1,
+            (LumaA, B1) => ExtendedColorType::La1,
+            (Rgb, B1) => ExtendedColorType::Rgb1,
+            (RgbA, B1) => ExtendedColorType::Rgba1,
+            (Luma, B2) => ExtendedColorType::L2,
+            (LumaA, B2) => ExtendedColorType::La2,
+            (Rgb, B2) => ExtendedColorType::Rgb2,
+            (RgbA, B2) => ExtendedColorType::Rgba2,
+            (Luma, B4) => ExtendedColorType::L4,
+            (LumaA, B4) => ExtendedColorType::La4,
+            (Rgb, B4) => ExtendedColorType::Rgb4,
+            (RgbA, B4) => ExtendedColorType::Rgba4,
+            (Luma, B8) => ExtendedColorType::L8,
+            (LumaA, B8) => ExtendedColorType::La8,
+            (Rgb, B8) => ExtendedColorType::Rgb8,
+            (RgbA, B8) => ExtendedColorType::Rgba8,
+            (Luma, B16) => ExtendedColorType::L16,
+            (LumaA, B16) => ExtendedColorType::La16,
+            (Rgb, B16) => ExtendedColorType::Rgb16,
+            (RgbA, B16) => ExtendedColorType::Rgba16,
+            (Bgr, B8) => ExtendedColorType::Bgr8,
+            (BgrA, B8) => ExtendedColorType::Bgra8,
+            (Rgb, B32) => ExtendedColorType::Rgb32F,
+            (RgbA, B32) => ExtendedColorType::Rgba32F,
+            (Cmyk, B8) => ExtendedColorType::Cmyk8,
+            (_, bit_depth) => ExtendedColorType::Unknown(bit_depth as u8),
         }
     }
-    fn to_color_type(&self) -> ColorType {
-        match self.color_space {
-            ColorSpace::Rgb => match self.bit_depth {
-                BitDepth::B8 => ColorType::Rgb8,
-                BitDepth::B16 => ColorType::Rgb16,
-                BitDepth::B32 => ColorType::Rgb32F,
-            },
-            ColorSpace::RgbA => match self.bit_depth {
-                BitDepth::B8 => ColorType::Rgba8,
-                BitDepth::B16 => ColorType::Rgba16,
-                BitDepth::B32 => ColorType::Rgba32F,
-            },
-            ColorSpace::Luma => match self.bit_depth {
-                BitDepth::B8 => ColorType::L8,
-                BitDepth::B16 => ColorType::L16,
-                BitDepth::B32 => ColorType::L16,
-            },
-            ColorSpace::LumaA => match self.bit_depth {
-                BitDepth::B8 => ColorType::La8,
-                BitDepth::B16 => ColorType::La16,
-                BitDepth::B32 => ColorType::La16,
-            },
-            ColorSpace::Unknown => ColorType::Rgb8,
+
+    pub fn has_alpha(&self) -> bool {
+        matches!(
+            self.color_space,
+            ColorSpace::RgbA | ColorSpace::BgrA | ColorSpace::LumaA | ColorSpace::Alpha
+        )
+    }
+
+    /// The same layout with or without an alpha channel
+    pub fn with_alpha(self, alpha: bool) -> Self {
+        let color_space = match (self.color_space, alpha) {
+            (ColorSpace::Rgb | ColorSpace::RgbA, true) => ColorSpace::RgbA,
+            (ColorSpace::Rgb | ColorSpace::RgbA, false) => ColorSpace::Rgb,
+            (ColorSpace::Bgr | ColorSpace::BgrA, true) => ColorSpace::BgrA,
+            (ColorSpace::Bgr | ColorSpace::BgrA, false) => ColorSpace::Bgr,
+            (ColorSpace::Luma | ColorSpace::LumaA, true) => ColorSpace::LumaA,
+            (ColorSpace::Luma | ColorSpace::LumaA, false) => ColorSpace::Luma,
+            (color_space, _) => color_space,
+        };
+        Self {
+            color_space,
+            ..self
         }
     }
+
+    fn unsupported(&self) -> Error {
+        Error::color(
+            None,
+            &format!(
+                "{} {}-bit images are not supported, use rgb, rgba, luma or lumaa at 8 or 16 bits, or rgb or rgba at 32 bits",
+                self.color_space, self.bit_depth
+            ),
+        )
+    }
 }
+
 impl From<ColorType> for ColorInfo {
     fn from(value: ColorType) -> Self {
-        match value {
-            ColorType::L8 => Self {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::Luma,
-            },
-            ColorType::La8 => Self {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::LumaA,
-            },
-            ColorType::Rgb8 => Self {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgba8 => Self {
-                bit_depth: BitDepth::B8,
-                color_space: ColorSpace::RgbA,
-            },
-            ColorType::L16 => Self {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::Luma,
-            },
-            ColorType::La16 => Self {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::LumaA,
-            },
-            ColorType::Rgb16 => Self {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgba16 => Self {
-                bit_depth: BitDepth::B16,
-                color_space: ColorSpace::RgbA,
-            },
-            ColorType::Rgb32F => Self {
-                bit_depth: BitDepth::B32,
-                color_space: ColorSpace::Rgb,
-            },
-            ColorType::Rgba32F => Self {
-                bit_depth: BitDepth::B32,
-                color_space: ColorSpace::RgbA,
-            },
-            _ => Self::default(),
+        use BitDepth::*;
+        use ColorSpace::*;
+
+        let (color_space, bit_depth) = match value {
+            ColorType::L8 => (Luma, B8),
+            ColorType::La8 => (LumaA, B8),
+            ColorType::Rgb8 => (Rgb, B8),
+            ColorType::Rgba8 => (RgbA, B8),
+            ColorType::L16 => (Luma, B16),
+            ColorType::La16 => (LumaA, B16),
+            ColorType::Rgb16 => (Rgb, B16),
+            ColorType::Rgba16 => (RgbA, B16),
+            ColorType::Rgb32F => (Rgb, B32),
+            ColorType::Rgba32F => (RgbA, B32),
+            // The enum is non exhaustive, no other decoded layout exists yet
+            _ => (Unknown, B8),
+        };
+
+        ColorInfo {
+            color_space,
+            bit_depth,
         }
     }
 }
+
+impl TryFrom<ExtendedColorType> for ColorInfo {
+    type Error = Error;
+
+    /// Fails for unknown layouts whose bit count is not a channel bit depth
+    fn try_from(value: ExtendedColorType) -> Result<Self> {
+        use BitDepth::*;
+        use ColorSpace::*;
+
+        let (color_space, bit_depth) = match value {
+            ExtendedColorType::A8 => (Alpha, B8),
+            ExtendedColorType::L1 => (Luma, B1),
+            ExtendedColorType::La1 => (LumaA, B1),
+            ExtendedColorType::Rgb1 => (Rgb, B1),
+            ExtendedColorType::Rgba1 => (RgbA, B1),
+            ExtendedColorType::L2 => (Luma, B2),
+            ExtendedColorType::La2 => (LumaA, B2),
+            ExtendedColorType::Rgb2 => (Rgb, B2),
+            ExtendedColorType::Rgba2 => (RgbA, B2),
+            ExtendedColorType::L4 => (Luma, B4),
+            ExtendedColorType::La4 => (LumaA, B4),
+            ExtendedColorType::Rgb4 => (Rgb, B4),
+            ExtendedColorType::Rgba4 => (RgbA, B4),
+            ExtendedColorType::L8 => (Luma, B8),
+            ExtendedColorType::La8 => (LumaA, B8),
+            ExtendedColorType::Rgb8 => (Rgb, B8),
+            ExtendedColorType::Rgba8 => (RgbA, B8),
+            ExtendedColorType::L16 => (Luma, B16),
+            ExtendedColorType::La16 => (LumaA, B16),
+            ExtendedColorType::Rgb16 => (Rgb, B16),
+            ExtendedColorType::Rgba16 => (RgbA, B16),
+            ExtendedColorType::Bgr8 => (Bgr, B8),
+            ExtendedColorType::Bgra8 => (BgrA, B8),
+            ExtendedColorType::Rgb32F => (Rgb, B32),
+            ExtendedColorType::Rgba32F => (RgbA, B32),
+            ExtendedColorType::Cmyk8 => (Cmyk, B8),
+            ExtendedColorType::Unknown(bits) => (Unknown, BitDepth::try_from(bits)?),
+            _ => {
+                return Err(Error::color(
+                    None,
+                    &format!("{value:?} images are not supported"),
+                ));
+            }
+        };
+
+        Ok(ColorInfo {
+            color_space,
+            bit_depth,
+        })
+    }
+}
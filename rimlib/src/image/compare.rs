@@ -0,0 +1,424 @@
+use crate::error::{Error, Result};
+use image::{DynamicImage, GenericImageView, Rgb32FImage, RgbImage, Rgba32FImage};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Standard deviation of the Gaussian window SSIM is computed over
+const SSIM_SIGMA: f32 = 1.5;
+
+/// Stabilizing constants of SSIM for values from 0 to 1
+const SSIM_C1: f32 = 0.01 * 0.01;
+const SSIM_C2: f32 = 0.03 * 0.03;
+
+/// Measure used to decide whether two images differ too much
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Metric {
+    /// Mean squared error, lower is closer
+    Mse,
+    /// Peak signal to noise ratio in decibels, higher is closer
+    Psnr,
+    /// Structural similarity from 0 to 1, higher is closer
+    #[default]
+    Ssim,
+    /// Largest difference of a single channel, lower is closer
+    MaxDelta,
+    /// Mean CIEDE2000 color difference, lower is closer
+    DeltaE,
+}
+
+impl Metric {
+    /// Whether higher values mean the images are closer
+    pub fn higher_is_closer(&self) -> bool {
+        matches!(self, Metric::Psnr | Metric::Ssim)
+    }
+}
+
+impl FromStr for Metric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "mse" => Ok(Metric::Mse),
+            "psnr" => Ok(Metric::Psnr),
+            "ssim" => Ok(Metric::Ssim),
+            "maxdelta" | "delta" => Ok(Metric::MaxDelta),
+            "deltae" | "de" | "ciede2000" => Ok(Metric::DeltaE),
+            _ => Err(Error::operation(
+                None,
+                &format!("unknown metric {s:?}, must be mse, psnr, ssim, max-delta or delta-e"),
+            )),
+        }
+    }
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Metric::Mse => write!(f, "MSE"),
+            Metric::Psnr => write!(f, "PSNR"),
+            Metric::Ssim => write!(f, "SSIM"),
+            Metric::MaxDelta => write!(f, "max delta"),
+            Metric::DeltaE => write!(f, "delta E"),
+        }
+    }
+}
+
+/// CIEDE2000 color differences over every pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeltaE {
+    pub mean: f64,
+    pub max: f64,
+}
+
+/// Differences between two images of the same size.
+/// Channel values are scaled to 0.0 to 1.0 whatever the bit depth,
+/// alpha is compared when either image has it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Comparison {
+    pub width: u32,
+    pub height: u32,
+    /// Mean squared error over every channel
+    pub mse: f64,
+    /// Peak signal to noise ratio in decibels, infinite for identical images
+    pub psnr: f64,
+    /// Mean structural similarity of the luma, 1.0 for identical images
+    pub ssim: f64,
+    /// Largest difference of a single channel of any pixel
+    pub max_delta: f64,
+    /// Percentage of pixels with any difference
+    pub differing_pixels: f64,
+    /// Perceptual color difference, only computed when asked for
+    pub delta_e: Option<DeltaE>,
+}
+
+impl Comparison {
+    /// Compares two images, computing the CIEDE2000 difference when `delta_e` is set
+    pub fn compute(first: &DynamicImage, second: &DynamicImage, delta_e: bool) -> Result<Self> {
+        check_size(first, second)?;
+
+        let channels = match first.color().has_alpha() || second.color().has_alpha() {
+            true => 4,
+            false => 3,
+        };
+        let (first_values, second_values) = (first.to_rgba32f(), second.to_rgba32f());
+        let (width, height) = first.dimensions();
+
+        // Squared error, largest delta and differing pixels of each row
+        let rows = first_values
+            .as_raw()
+            .par_chunks(width as usize * 4)
+            .zip(second_values.as_raw().par_chunks(width as usize * 4))
+            .map(|(first_row, second_row)| {
+                let mut squared = 0.0f64;
+                let mut max_delta = 0.0f32;
+                let mut differing = 0usize;
+
+                for (a, b) in first_row.chunks_exact(4).zip(second_row.chunks_exact(4)) {
+                    let mut pixel_delta = 0.0f32;
+                    for channel in 0..channels {
+                        let delta = (a[channel] - b[channel]).abs();
+                        squared += (delta * delta) as f64;
+                        pixel_delta = pixel_delta.max(delta);
+                    }
+                    max_delta = max_delta.max(pixel_delta);
+                    differing += (pixel_delta > 0.0) as usize;
+                }
+                (squared, max_delta, differing)
+            })
+            .collect::<Vec<(f64, f32, usize)>>();
+
+        let pixel_count = width as f64 * height as f64;
+        let squared = rows.iter().map(|row| row.0).sum::<f64>();
+        let mse = squared / (pixel_count * channels as f64);
+
+        Ok(Self {
+            width,
+            height,
+            mse,
+            psnr: match mse {
+                0.0 => f64::INFINITY,
+                mse => -10.0 * mse.log10(),
+            },
+            ssim: ssim(&luma(&first_values), &luma(&second_values), width as usize),
+            max_delta: rows.iter().map(|row| row.1).fold(0.0, f32::max) as f64,
+            differing_pixels: rows.iter().map(|row| row.2).sum::<usize>() as f64 / pixel_count
+                * 100.0,
+            delta_e: delta_e.then(|| delta_e_2000(&first.to_rgb32f(), &second.to_rgb32f())),
+        })
+    }
+
+    /// Value of the metric, `None` for delta E when it was not computed
+    pub fn value(&self, metric: Metric) -> Option<f64> {
+        match metric {
+            Metric::Mse => Some(self.mse),
+            Metric::Psnr => Some(self.psnr),
+            Metric::Ssim => Some(self.ssim),
+            Metric::MaxDelta => Some(self.max_delta),
+            Metric::DeltaE => self.delta_e.map(|delta_e| delta_e.mean),
+        }
+    }
+
+    /// Whether the images differ more than the threshold allows for the metric
+    pub fn exceeds(&self, metric: Metric, threshold: f64) -> Result<bool> {
+        let value = self.value(metric).ok_or_else(|| {
+            Error::operation(None, "delta E was not computed for this comparison")
+        })?;
+
+        Ok(match metric.higher_is_closer() {
+            true => value < threshold,
+            false => value > threshold,
+        })
+    }
+
+    /// Human readable summary
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "MSE: {:.6}\nPSNR: {:.2} dB\nSSIM: {:.6}\nMax delta: {:.4}\nDiffering pixels: {:.2}%",
+            self.mse, self.psnr, self.ssim, self.max_delta, self.differing_pixels
+        );
+        if let Some(delta_e) = self.delta_e {
+            text.push_str(&format!(
+                "\nDelta E: mean {:.4} max {:.4}",
+                delta_e.mean, delta_e.max
+            ));
+        }
+        text
+    }
+}
+
+/// Draws the differences between two images: a faded grayscale copy of the first image
+/// with every pixel differing by more than `tolerance` in red,
+/// brighter the larger the difference
+pub fn diff_image(first: &DynamicImage, second: &DynamicImage, tolerance: f32) -> Result<RgbImage> {
+    check_size(first, second)?;
+
+    let (first_values, second_values) = (first.to_rgba32f(), second.to_rgba32f());
+    let mut diff = RgbImage::new(first.width(), first.height());
+
+    diff.par_chunks_mut(3)
+        .zip(first_values.as_raw().par_chunks(4))
+        .zip(second_values.as_raw().par_chunks(4))
+        .for_each(|((out, a), b)| {
+            let delta = a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0f32, f32::max);
+
+            let gray = (0.2126 * a[0] + 0.7152 * a[1] + 0.0722 * a[2]) * a[3];
+            let faded = ((0.75 + 0.25 * gray.clamp(0.0, 1.0)) * 255.0) as u8;
+
+            out.copy_from_slice(&match delta > tolerance {
+                true => {
+                    let strength = 0.5 + 0.5 * delta.min(1.0);
+                    [(255.0 * strength) as u8, 0, 0]
+                }
+                false => [faded; 3],
+            });
+        });
+
+    Ok(diff)
+}
+
+fn check_size(first: &DynamicImage, second: &DynamicImage) -> Result<()> {
+    if first.width() == 0 || first.height() == 0 {
+        return Err(Error::operation(None, "cannot compare empty images"));
+    }
+    if first.dimensions() != second.dimensions() {
+        return Err(Error::operation(
+            None,
+            &format!(
+                "cannot compare a {}x{} image with a {}x{} image",
+                first.width(),
+                first.height(),
+                second.width(),
+                second.height()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Rec. 709 luma, premultiplied so transparent areas count as black
+fn luma(image: &Rgba32FImage) -> Vec<f32> {
+    image
+        .as_raw()
+        .par_chunks(4)
+        .map(|p| (0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]) * p[3])
+        .collect()
+}
+
+/// Mean SSIM over Gaussian windows centered on every pixel
+fn ssim(first: &[f32], second: &[f32], width: usize) -> f64 {
+    let product =
+        |a: &[f32], b: &[f32]| -> Vec<f32> { a.iter().zip(b).map(|(a, b)| a * b).collect() };
+    let kernel = gaussian_kernel(SSIM_SIGMA);
+
+    let mean_first = gaussian_blur(first, width, &kernel);
+    let mean_second = gaussian_blur(second, width, &kernel);
+    let first_squared = gaussian_blur(&product(first, first), width, &kernel);
+    let second_squared = gaussian_blur(&product(second, second), width, &kernel);
+    let cross = gaussian_blur(&product(first, second), width, &kernel);
+
+    let total = (0..first.len())
+        .map(|i| {
+            let (mx, my) = (mean_first[i], mean_second[i]);
+            let variance_first = first_squared[i] - mx * mx;
+            let variance_second = second_squared[i] - my * my;
+            let covariance = cross[i] - mx * my;
+
+            ((2.0 * mx * my + SSIM_C1) * (2.0 * covariance + SSIM_C2)
+                / ((mx * mx + my * my + SSIM_C1) * (variance_first + variance_second + SSIM_C2)))
+                as f64
+        })
+        .sum::<f64>();
+
+    total / first.len().max(1) as f64
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil() as i32;
+    let kernel = (-radius..=radius)
+        .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<f32>>();
+    let sum = kernel.iter().sum::<f32>();
+    kernel.iter().map(|weight| weight / sum).collect()
+}
+
+/// Separable Gaussian blur of a single channel, clamping at the edges
+fn gaussian_blur(values: &[f32], width: usize, kernel: &[f32]) -> Vec<f32> {
+    let height = values.len() / width.max(1);
+    let radius = (kernel.len() / 2) as isize;
+    let clamp = |i: isize, len: usize| i.clamp(0, len as isize - 1) as usize;
+
+    let mut horizontal = vec![0.0f32; values.len()];
+    horizontal
+        .par_chunks_mut(width)
+        .zip(values.par_chunks(width))
+        .for_each(|(out, row)| {
+            for (x, value) in out.iter_mut().enumerate() {
+                *value = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| weight * row[clamp(x as isize + k as isize - radius, width)])
+                    .sum();
+            }
+        });
+
+    let mut vertical = vec![0.0f32; values.len()];
+    vertical
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, out)| {
+            for (x, value) in out.iter_mut().enumerate() {
+                *value = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        let row = clamp(y as isize + k as isize - radius, height);
+                        weight * horizontal[row * width + x]
+                    })
+                    .sum();
+            }
+        });
+    vertical
+}
+
+fn delta_e_2000(first: &Rgb32FImage, second: &Rgb32FImage) -> DeltaE {
+    let differences = first
+        .as_raw()
+        .par_chunks(3)
+        .zip(second.as_raw().par_chunks(3))
+        .map(|(a, b)| ciede2000(srgb_to_lab(a), srgb_to_lab(b)))
+        .collect::<Vec<f64>>();
+
+    DeltaE {
+        mean: differences.iter().sum::<f64>() / differences.len().max(1) as f64,
+        max: differences.iter().copied().fold(0.0, f64::max),
+    }
+}
+
+/// Converts sRGB values to CIE L*a*b* under the D65 white point
+fn srgb_to_lab(rgb: &[f32]) -> [f64; 3] {
+    let linear = |value: f32| {
+        let value = value.clamp(0.0, 1.0) as f64;
+        match value <= 0.04045 {
+            true => value / 12.92,
+            false => ((value + 0.055) / 1.055).powf(2.4),
+        }
+    };
+    let (r, g, b) = (linear(rgb[0]), linear(rgb[1]), linear(rgb[2]));
+
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    let f = |t: f64| {
+        const DELTA: f64 = 6.0 / 29.0;
+        match t > DELTA.powi(3) {
+            true => t.cbrt(),
+            false => t / (3.0 * DELTA * DELTA) + 4.0 / 29.0,
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// CIEDE2000 difference between two L*a*b* colors
+pub fn ciede2000(first: [f64; 3], second: [f64; 3]) -> f64 {
+    let [l1, a1, b1] = first;
+    let [l2, a2, b2] = second;
+    let pow7 = |value: f64| value.powi(7);
+    let twenty_five_7 = pow7(25.0);
+
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + twenty_five_7)).sqrt());
+    let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+
+    let hue = |b: f64, a: f64| match (a, b) {
+        (0.0, 0.0) => 0.0,
+        _ => b.atan2(a).to_degrees().rem_euclid(360.0),
+    };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = match c1 * c2 {
+        0.0 => 0.0,
+        _ => match h2 - h1 {
+            d if d > 180.0 => d - 360.0,
+            d if d < -180.0 => d + 360.0,
+            d => d,
+        },
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = match (c1 * c2, (h1 - h2).abs()) {
+        (0.0, _) => h1 + h2,
+        (_, difference) if difference <= 180.0 => (h1 + h2) / 2.0,
+        _ if h1 + h2 < 360.0 => (h1 + h2 + 360.0) / 2.0,
+        _ => (h1 + h2 - 360.0) / 2.0,
+    };
+
+    let cos = |degrees: f64| degrees.to_radians().cos();
+    let t =
+        1.0 - 0.17 * cos(h_bar - 30.0) + 0.24 * cos(2.0 * h_bar) + 0.32 * cos(3.0 * h_bar + 6.0)
+            - 0.20 * cos(4.0 * h_bar - 63.0);
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_bar) / (pow7(c_bar) + twenty_five_7)).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
@@ -1,87 +1,579 @@
-use image::{DynamicImage, ImageFormat, load_from_memory};
+use super::color::BitDepth;
+use super::metadata::{Metadata, supports_metadata};
+use super::quantize::IndexedImage;
+use crate::error::{Error, Result};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{self, PngEncoder};
+use image::error::EncodingError;
+use image::{DynamicImage, ImageError, ImageFormat, load_from_memory};
+use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufWriter, Cursor};
+use std::io::{BufWriter, Cursor, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tiff::encoder::compression::{
+    Compression as TiffCompression, Deflate, DeflateLevel, Lzw, Packbits, Uncompressed,
+};
+use tiff::encoder::{TiffEncoder, colortype};
 
-fn image_format(format: Option<&str>, path: Option<&Path>) -> Result<ImageFormat, String> {
-    if let Some(format_extension) = format {
-        match ImageFormat::from_extension(format_extension) {
-            Some(format) => Ok(format),
-            _ => Err(format!(
-                "Couldn't get image format from extension: {}",
-                format_extension
+/// Compression used by lossless encoders
+///
+/// PNG supports `Default`, `Fast` and `Best`.
+/// TIFF supports every variant, `Fast` and `Best` selecting Deflate levels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    Default,
+    Fast,
+    Best,
+    None,
+    Lzw,
+    Deflate,
+    Packbits,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(Compression::Default),
+            "fast" => Ok(Compression::Fast),
+            "best" => Ok(Compression::Best),
+            "none" => Ok(Compression::None),
+            "lzw" => Ok(Compression::Lzw),
+            "deflate" => Ok(Compression::Deflate),
+            "packbits" => Ok(Compression::Packbits),
+            _ => Err(Error::operation(
+                None,
+                &format!("unknown compression {s:?}"),
             )),
         }
-    } else {
-        match ImageFormat::from_path(path.unwrap()) {
-            Ok(format) => Ok(format),
-            Err(_) => Err("Could not obtain image format from output path".to_string()),
+    }
+}
+
+/// Row filter used by the PNG encoder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    #[default]
+    Adaptive,
+}
+
+impl FromStr for PngFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PngFilter::None),
+            "sub" => Ok(PngFilter::Sub),
+            "up" => Ok(PngFilter::Up),
+            "avg" => Ok(PngFilter::Avg),
+            "paeth" => Ok(PngFilter::Paeth),
+            "adaptive" => Ok(PngFilter::Adaptive),
+            _ => Err(Error::operation(None, &format!("unknown png filter {s:?}"))),
         }
     }
 }
 
-pub fn convert_image(image: DynamicImage, format: Option<&str>) -> Result<DynamicImage, String> {
-    let image_format = if let Some(format_extension) = format {
-        match ImageFormat::from_extension(format_extension) {
-            Some(format) => match format {
-                // Avif cannot be decoded in memory yet,
-                // hence we return and leave it to save_image_format()
-                ImageFormat::Avif => return Ok(image),
-                other_fmt => other_fmt,
-            },
-            _ => {
-                return Err(format!(
-                    "Could not get image format from extension: {format_extension}"
-                ));
+/// Settings passed to the encoder when saving an image
+///
+/// Options left as `None` use the encoder defaults.
+/// Setting an option the output format has no use for is an error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// Quality from 1 to 100, for JPEG and AVIF
+    pub quality: Option<u8>,
+    /// Compression for PNG and TIFF
+    pub compression: Option<Compression>,
+    /// Row filter for PNG
+    pub png_filter: Option<PngFilter>,
+    /// Speed from 1 (slowest) to 10 (fastest), for AVIF
+    pub speed: Option<u8>,
+    /// Write PNG and GIF with a palette, the image must have at most 256 colors
+    pub indexed: bool,
+}
+
+impl EncodeOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Checks that every option that is set can be used with the format
+    pub fn validate(&self, format: ImageFormat) -> Result<()> {
+        let unsupported = |option: &str| {
+            Err(Error::operation(
+                None,
+                &format!("{option} is not supported when saving to {format:?}"),
+            ))
+        };
+
+        if let Some(quality) = self.quality {
+            if !matches!(format, ImageFormat::Jpeg | ImageFormat::Avif) {
+                return unsupported("quality");
+            }
+            if !(1..=100).contains(&quality) {
+                return Err(Error::operation(None, "quality must be between 1 and 100"));
+            }
+        }
+
+        if let Some(speed) = self.speed {
+            if format != ImageFormat::Avif {
+                return unsupported("speed");
+            }
+            if !(1..=10).contains(&speed) {
+                return Err(Error::operation(None, "speed must be between 1 and 10"));
             }
         }
-    } else {
-        return Ok(image);
+
+        if self.png_filter.is_some() && format != ImageFormat::Png {
+            return unsupported("png filter");
+        }
+
+        if self.indexed && !matches!(format, ImageFormat::Png | ImageFormat::Gif) {
+            return unsupported("indexed color");
+        }
+
+        match (self.compression, format) {
+            (None, _) | (Some(_), ImageFormat::Tiff) => Ok(()),
+            (
+                Some(Compression::Default | Compression::Fast | Compression::Best),
+                ImageFormat::Png,
+            ) => Ok(()),
+            (Some(compression), _) => unsupported(&format!("{compression} compression")),
+        }
+    }
+}
+
+fn image_format(format: Option<&str>, path: Option<&Path>) -> Result<ImageFormat> {
+    match (format, path) {
+        (Some(format_extension), _) => ImageFormat::from_extension(format_extension)
+            .ok_or_else(|| Error::unsupported_format(path, format_extension)),
+        (None, Some(path)) => ImageFormat::from_path(path).map_err(|e| Error::UnsupportedFormat {
+            path: Some(path.to_path_buf()),
+            format: path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            source: Some(e),
+        }),
+        (None, None) => Err(Error::unsupported_format(None, "")),
+    }
+}
+
+pub fn convert_image(image: DynamicImage, format: Option<&str>) -> Result<DynamicImage> {
+    let image_format = match image_format(format, None) {
+        // Avif cannot be decoded in memory yet,
+        // hence we return and leave it to save_image_format()
+        Ok(ImageFormat::Avif) => return Ok(image),
+        Ok(format) => format,
+        Err(_) if format.is_none() => return Ok(image),
+        Err(e) => return Err(e),
     };
 
     let mut writer = Cursor::new(Vec::with_capacity(image.as_bytes().len() + 1));
 
-    match image.write_to(&mut writer, image_format) {
-        Ok(()) => (),
-        Err(e) => return Err(e.to_string()),
-    };
+    image
+        .write_to(&mut writer, image_format)
+        .map_err(|e| Error::encode(None, e))?;
 
-    let result = load_from_memory(&writer.into_inner());
+    load_from_memory(&writer.into_inner()).map_err(|e| Error::decode(None, e))
+}
 
-    match result {
-        Ok(image) => Ok(image),
-        Err(e) => Err(e.to_string()),
-    }
+pub fn save_image_format(image: &DynamicImage, out: &Path, format: Option<&str>) -> Result<()> {
+    save_image(
+        image,
+        out,
+        format,
+        &EncodeOptions::default(),
+        &Metadata::default(),
+    )
 }
 
-pub fn save_image_format(
+/// Saves the image like `save_image_format`, writing the metadata into the file.
+/// Fails when the output format cannot hold metadata.
+pub fn save_image_with_metadata(
     image: &DynamicImage,
+    metadata: &Metadata,
     out: &Path,
     format: Option<&str>,
-) -> Result<(), String> {
-    let mut out_path = PathBuf::from(out);
-    let image_format = image_format(format, Some(out))?;
+) -> Result<()> {
+    save_image(image, out, format, &EncodeOptions::default(), metadata)
+}
 
-    let extension = image_format.extensions_str();
+/// Saves the image using the encoder options, writing the metadata into the file.
+/// Fails when the output format cannot hold metadata, see `supports_metadata`.
+///
+/// The extension of the output path is replaced to match the format.
+pub fn save_image(
+    image: &DynamicImage,
+    out: &Path,
+    format: Option<&str>,
+    options: &EncodeOptions,
+    metadata: &Metadata,
+) -> Result<()> {
+    let (out_path, image_format) = output_path(out, format)?;
 
-    if extension.is_empty() {
-        return Err("Image format has no valid file extension".to_string());
+    options
+        .validate(image_format)
+        .map_err(|e| e.with_path(&out_path))?;
+
+    if !metadata.is_empty() && !supports_metadata(image_format) {
+        return Err(Error::unsupported_format(
+            Some(&out_path),
+            &format!("{image_format:?} cannot hold EXIF, ICC or XMP metadata"),
+        ));
     }
 
-    out_path.set_extension(extension[0]);
+    if metadata.is_empty() {
+        let output_file = File::create(&out_path).map_err(|e| Error::io(Some(&out_path), e))?;
+
+        let mut buffer = BufWriter::with_capacity(image.as_bytes().len() + 1, output_file);
+
+        return encode_image(image, &mut buffer, image_format, options)
+            .map_err(|e| Error::encode(Some(&out_path), e));
+    }
+
+    let mut writer = Cursor::new(Vec::with_capacity(image.as_bytes().len() + 1));
+
+    encode_image(image, &mut writer, image_format, options)
+        .map_err(|e| Error::encode(Some(&out_path), e))?;
+
+    let encoded = metadata
+        .embed(writer.into_inner(), image_format)
+        .map_err(|e| e.with_path(&out_path))?;
+
+    std::fs::write(&out_path, encoded).map_err(|e| Error::io(Some(&out_path), e))
+}
+
+fn encode_image<W: Write + Seek>(
+    image: &DynamicImage,
+    writer: &mut W,
+    format: ImageFormat,
+    options: &EncodeOptions,
+) -> image::ImageResult<()> {
+    if options.indexed {
+        let indexed = IndexedImage::from_image(image)
+            .map_err(|e| ImageError::Encoding(EncodingError::new(format.into(), e)))?;
+
+        return match format {
+            ImageFormat::Gif => encode_indexed_gif(&indexed, writer),
+            _ => encode_indexed_png(&indexed, writer, options),
+        };
+    }
 
-    let output_file = match File::create(&out_path) {
-        Ok(file) => file,
-        Err(io_error) => return Err(format!("Error saving image {:?}: {}", out_path, io_error)),
+    if options.is_default() {
+        return image.write_to(writer, format);
+    }
+
+    match format {
+        ImageFormat::Jpeg => image.write_with_encoder(JpegEncoder::new_with_quality(
+            writer,
+            options.quality.unwrap_or(75),
+        )),
+        ImageFormat::Png => {
+            let compression = match options.compression.unwrap_or_default() {
+                Compression::Fast => png::CompressionType::Fast,
+                Compression::Best => png::CompressionType::Best,
+                _ => png::CompressionType::Default,
+            };
+            let filter = match options.png_filter.unwrap_or_default() {
+                PngFilter::None => png::FilterType::NoFilter,
+                PngFilter::Sub => png::FilterType::Sub,
+                PngFilter::Up => png::FilterType::Up,
+                PngFilter::Avg => png::FilterType::Avg,
+                PngFilter::Paeth => png::FilterType::Paeth,
+                PngFilter::Adaptive => png::FilterType::Adaptive,
+            };
+            image.write_with_encoder(PngEncoder::new_with_quality(writer, compression, filter))
+        }
+        ImageFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+            writer,
+            options.speed.unwrap_or(4),
+            options.quality.unwrap_or(80),
+        )),
+        ImageFormat::Tiff => match options.compression.unwrap_or_default() {
+            Compression::Default | Compression::None => encode_tiff(image, writer, Uncompressed),
+            Compression::Lzw => encode_tiff(image, writer, Lzw),
+            Compression::Deflate => encode_tiff(image, writer, Deflate::default()),
+            Compression::Fast => {
+                encode_tiff(image, writer, Deflate::with_level(DeflateLevel::Fast))
+            }
+            Compression::Best => {
+                encode_tiff(image, writer, Deflate::with_level(DeflateLevel::Best))
+            }
+            Compression::Packbits => encode_tiff(image, writer, Packbits),
+        },
+        _ => image.write_to(writer, format),
+    }
+}
+
+/// Encodes a TIFF image through the tiff crate, which unlike the image crate
+/// encoder allows choosing the compression
+fn encode_tiff<W, D>(image: &DynamicImage, writer: &mut W, compression: D) -> image::ImageResult<()>
+where
+    W: Write + Seek,
+    D: TiffCompression,
+{
+    let mut encoder = TiffEncoder::new(writer).map_err(tiff_error)?;
+    let (width, height) = (image.width(), image.height());
+
+    let result = match image {
+        DynamicImage::ImageLuma8(buffer) => encoder
+            .write_image_with_compression::<colortype::Gray8, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        DynamicImage::ImageLuma16(buffer) => encoder
+            .write_image_with_compression::<colortype::Gray16, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        DynamicImage::ImageRgb8(buffer) => encoder
+            .write_image_with_compression::<colortype::RGB8, D>(width, height, compression, buffer),
+        DynamicImage::ImageRgb16(buffer) => encoder
+            .write_image_with_compression::<colortype::RGB16, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        DynamicImage::ImageRgb32F(buffer) => encoder
+            .write_image_with_compression::<colortype::RGB32Float, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        DynamicImage::ImageRgba32F(buffer) => encoder
+            .write_image_with_compression::<colortype::RGBA32Float, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        DynamicImage::ImageRgba16(buffer) => encoder
+            .write_image_with_compression::<colortype::RGBA16, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        DynamicImage::ImageRgba8(buffer) => encoder
+            .write_image_with_compression::<colortype::RGBA8, D>(
+                width,
+                height,
+                compression,
+                buffer,
+            ),
+        // Luma with alpha has no TIFF color type, it is widened to RGBA
+        DynamicImage::ImageLumaA16(_) => encoder
+            .write_image_with_compression::<colortype::RGBA16, D>(
+                width,
+                height,
+                compression,
+                &image.to_rgba16(),
+            ),
+        _ => encoder.write_image_with_compression::<colortype::RGBA8, D>(
+            width,
+            height,
+            compression,
+            &image.to_rgba8(),
+        ),
+    };
+
+    result.map_err(tiff_error)
+}
+
+/// Writes a palette PNG at the smallest bit depth holding the palette,
+/// with transparent colors first so the alpha table stays short
+fn encode_indexed_png<W: Write>(
+    indexed: &IndexedImage,
+    writer: &mut W,
+    options: &EncodeOptions,
+) -> image::ImageResult<()> {
+    let mut order = (0..indexed.palette.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&index| indexed.palette[index][3] == u8::MAX);
+
+    let mut remap = vec![0u8; indexed.palette.len()];
+    for (new, &old) in order.iter().enumerate() {
+        remap[old] = new as u8;
+    }
+    let palette = order
+        .iter()
+        .map(|&index| indexed.palette[index])
+        .collect::<Vec<[u8; 4]>>();
+
+    let bits = match indexed.color_info().bit_depth {
+        BitDepth::B1 => ::png::BitDepth::One,
+        BitDepth::B2 => ::png::BitDepth::Two,
+        BitDepth::B4 => ::png::BitDepth::Four,
+        _ => ::png::BitDepth::Eight,
     };
+    let per_byte = 8 / bits as usize;
+    let width = indexed.width as usize;
+
+    let mut data = Vec::with_capacity(width.div_ceil(per_byte) * indexed.height as usize);
+    for row in indexed.indices.chunks(width.max(1)) {
+        for pixels in row.chunks(per_byte) {
+            let byte = pixels.iter().enumerate().fold(0u8, |byte, (i, &index)| {
+                byte | remap[index as usize] << (8 - bits as usize * (i + 1))
+            });
+            data.push(byte);
+        }
+    }
 
-    let mut buffer = BufWriter::with_capacity(image.as_bytes().len() + 1, output_file);
+    let mut encoder = ::png::Encoder::new(writer, indexed.width, indexed.height);
+    encoder.set_color(::png::ColorType::Indexed);
+    encoder.set_depth(bits);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<u8>>(),
+    );
+
+    let transparent = palette.iter().take_while(|c| c[3] < u8::MAX);
+    let trns = transparent.map(|c| c[3]).collect::<Vec<u8>>();
+    if !trns.is_empty() {
+        encoder.set_trns(trns);
+    }
 
-    match image.write_to(&mut buffer, image_format) {
-        Ok(()) => Ok(()),
-        Err(save_error) => Err(format!(
-            "Error saving image file {:?}: {}",
-            out_path, save_error
+    set_png_options(&mut encoder, options);
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&data).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+/// Writes a GIF with the palette as its global color table
+fn encode_indexed_gif<W: Write>(indexed: &IndexedImage, writer: &mut W) -> image::ImageResult<()> {
+    let (width, height) = gif_size(indexed.width, indexed.height)?;
+    let (palette, frame) = indexed_gif_frame(indexed, width, height);
+
+    let mut encoder = gif::Encoder::new(writer, width, height, &palette).map_err(gif_error)?;
+    encoder.write_frame(&frame).map_err(gif_error)
+}
+
+/// Checks that the image fits in the 16-bit dimensions of GIF
+pub(crate) fn gif_size(width: u32, height: u32) -> image::ImageResult<(u16, u16)> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(ImageError::Encoding(EncodingError::new(
+            ImageFormat::Gif.into(),
+            "GIF images cannot be larger than 65535x65535",
+        ))),
+    }
+}
+
+/// Builds a GIF frame from an indexed image, returning the RGB palette it refers to.
+/// GIF transparency is on or off, so every color under half opacity
+/// becomes the single transparent color.
+pub(crate) fn indexed_gif_frame(
+    indexed: &IndexedImage,
+    width: u16,
+    height: u16,
+) -> (Vec<u8>, gif::Frame<'static>) {
+    let transparent = indexed.palette.iter().position(|c| c[3] < 128);
+    let indices = match transparent {
+        None => indexed.indices.clone(),
+        Some(transparent) => indexed
+            .indices
+            .iter()
+            .map(|&index| match indexed.palette[index as usize][3] < 128 {
+                true => transparent as u8,
+                false => index,
+            })
+            .collect(),
+    };
+
+    let palette = indexed
+        .palette
+        .iter()
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect::<Vec<u8>>();
+
+    let frame = gif::Frame {
+        width,
+        height,
+        buffer: indices.into(),
+        transparent: transparent.map(|index| index as u8),
+        ..Default::default()
+    };
+    (palette, frame)
+}
+
+/// Applies the compression and row filter options to a png crate encoder
+pub(crate) fn set_png_options<W: Write>(encoder: &mut ::png::Encoder<W>, options: &EncodeOptions) {
+    encoder.set_compression(match options.compression.unwrap_or_default() {
+        Compression::Fast => ::png::Compression::Fast,
+        Compression::Best => ::png::Compression::Best,
+        _ => ::png::Compression::Default,
+    });
+    match options.png_filter.unwrap_or_default() {
+        PngFilter::Adaptive => encoder.set_adaptive_filter(::png::AdaptiveFilterType::Adaptive),
+        PngFilter::None => encoder.set_filter(::png::FilterType::NoFilter),
+        PngFilter::Sub => encoder.set_filter(::png::FilterType::Sub),
+        PngFilter::Up => encoder.set_filter(::png::FilterType::Up),
+        PngFilter::Avg => encoder.set_filter(::png::FilterType::Avg),
+        PngFilter::Paeth => encoder.set_filter(::png::FilterType::Paeth),
+    }
+}
+
+pub(crate) fn png_error(error: ::png::EncodingError) -> ImageError {
+    match error {
+        ::png::EncodingError::IoError(io_error) => ImageError::IoError(io_error),
+        other => ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), other)),
+    }
+}
+
+pub(crate) fn gif_error(error: gif::EncodingError) -> ImageError {
+    match error {
+        gif::EncodingError::Io(io_error) => ImageError::IoError(io_error),
+        other => ImageError::Encoding(EncodingError::new(ImageFormat::Gif.into(), other)),
+    }
+}
+
+fn tiff_error(error: tiff::TiffError) -> ImageError {
+    match error {
+        tiff::TiffError::IoError(io_error) => ImageError::IoError(io_error),
+        other => ImageError::Encoding(image::error::EncodingError::new(
+            ImageFormat::Tiff.into(),
+            other,
         )),
     }
 }
+
+/// Resolves the format of the output image and sets the matching extension on its path
+pub fn output_path(out: &Path, format: Option<&str>) -> Result<(PathBuf, ImageFormat)> {
+    let mut out_path = PathBuf::from(out);
+    let image_format = image_format(format, Some(out))?;
+
+    let extension = image_format.extensions_str();
+
+    if extension.is_empty() {
+        return Err(Error::unsupported_format(
+            Some(out),
+            &format!("{image_format:?} has no valid file extension"),
+        ));
+    }
+
+    out_path.set_extension(extension[0]);
+
+    Ok((out_path, image_format))
+}
@@ -0,0 +1,460 @@
+use crate::error::{Error, Result};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A length in pixels or as a percentage of the image size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixels(u32),
+    Percent(f32),
+}
+
+impl Length {
+    /// Length in pixels relative to a side of `total` pixels
+    pub fn resolve(self, total: u32) -> u32 {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Percent(percent) => (total as f64 * percent as f64 / 100.0).round() as u32,
+        }
+    }
+}
+
+impl Display for Length {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Length::Pixels(pixels) => write!(f, "{pixels}"),
+            Length::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::operation(None, &format!("invalid length {s:?}"));
+
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse::<f32>() {
+                Ok(percent) if percent.is_finite() && percent >= 0.0 => {
+                    Ok(Length::Percent(percent))
+                }
+                _ => Err(invalid()),
+            },
+            None => s.parse::<u32>().map(Length::Pixels).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Size of a region, either as lengths or as the largest region
+/// with a given aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Exact { width: Length, height: Length },
+    Aspect { width: u32, height: u32 },
+}
+
+/// Point of the image a region is placed relative to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Gravity {
+    #[default]
+    NorthWest,
+    North,
+    NorthEast,
+    West,
+    Center,
+    East,
+    SouthWest,
+    South,
+    SouthEast,
+}
+
+impl FromStr for Gravity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.to_lowercase().replace(['-', '_', ' '], "");
+
+        match name.as_str() {
+            "northwest" | "nw" | "topleft" => Ok(Gravity::NorthWest),
+            "north" | "n" | "top" => Ok(Gravity::North),
+            "northeast" | "ne" | "topright" => Ok(Gravity::NorthEast),
+            "west" | "w" | "left" => Ok(Gravity::West),
+            "center" | "centre" | "c" => Ok(Gravity::Center),
+            "east" | "e" | "right" => Ok(Gravity::East),
+            "southwest" | "sw" | "bottomleft" => Ok(Gravity::SouthWest),
+            "south" | "s" | "bottom" => Ok(Gravity::South),
+            "southeast" | "se" | "bottomright" => Ok(Gravity::SouthEast),
+            _ => Err(Error::operation(None, &format!("unknown gravity {s:?}"))),
+        }
+    }
+}
+
+impl Gravity {
+    /// Position of the anchor along each axis: 0 for the start, 1 for the middle, 2 for the end
+    fn anchor(self) -> (i64, i64) {
+        match self {
+            Gravity::NorthWest => (0, 0),
+            Gravity::North => (1, 0),
+            Gravity::NorthEast => (2, 0),
+            Gravity::West => (0, 1),
+            Gravity::Center => (1, 1),
+            Gravity::East => (2, 1),
+            Gravity::SouthWest => (0, 2),
+            Gravity::South => (1, 2),
+            Gravity::SouthEast => (2, 2),
+        }
+    }
+}
+
+/// A rectangle inside an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// ImageMagick style region geometry.
+///
+/// Accepts `WxH`, `WxH+X+Y`, percentages such as `50%x25%` or `50%`,
+/// and aspect ratios such as `16:9` for the largest region with that shape.
+/// Offsets move the region away from the gravity anchor, towards the center
+/// of the image for edge anchors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geometry {
+    pub size: Size,
+    pub x: i64,
+    pub y: i64,
+}
+
+impl Geometry {
+    /// Places the region inside an image of the given size.
+    /// Fails when the region is empty or does not fit inside the image.
+    pub fn rect(&self, width: u32, height: u32, gravity: Gravity) -> Result<Rect> {
+        let (region_width, region_height) = match self.size {
+            Size::Exact {
+                width: w,
+                height: h,
+            } => (w.resolve(width), h.resolve(height)),
+            Size::Aspect {
+                width: aspect_w,
+                height: aspect_h,
+            } => {
+                let (w, h, aw, ah) = (
+                    width as u64,
+                    height as u64,
+                    aspect_w as u64,
+                    aspect_h as u64,
+                );
+                if w * ah >= h * aw {
+                    ((h * aw / ah) as u32, height)
+                } else {
+                    (width, (w * ah / aw) as u32)
+                }
+            }
+        };
+
+        let (anchor_x, anchor_y) = gravity.anchor();
+        let place = |total: u32, size: u32, anchor: i64, offset: i64| {
+            let free = total as i64 - size as i64;
+            match anchor {
+                0 => offset,
+                1 => free / 2 + offset,
+                _ => free - offset,
+            }
+        };
+
+        let x = place(width, region_width, anchor_x, self.x);
+        let y = place(height, region_height, anchor_y, self.y);
+
+        let fits = region_width > 0
+            && region_height > 0
+            && x >= 0
+            && y >= 0
+            && x + region_width as i64 <= width as i64
+            && y + region_height as i64 <= height as i64;
+
+        if !fits {
+            return Err(Error::operation(
+                None,
+                &format!(
+                    "region {self} ({region_width}x{region_height} at {x},{y}) does not fit inside the {width}x{height} image"
+                ),
+            ));
+        }
+
+        Ok(Rect {
+            x: x as u32,
+            y: y as u32,
+            width: region_width,
+            height: region_height,
+        })
+    }
+}
+
+impl Display for Geometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Size::Exact { width, height } => write!(f, "{width}x{height}")?,
+            Size::Aspect { width, height } => write!(f, "{width}:{height}")?,
+        }
+        if self.x != 0 || self.y != 0 {
+            write!(f, "{:+}{:+}", self.x, self.y)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Geometry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::operation(
+                None,
+                &format!("invalid geometry {s:?}, expected WxH+X+Y, W%xH% or W:H"),
+            )
+        };
+
+        let s = s.trim();
+        let offset_start = s.find(['+', '-']).unwrap_or(s.len());
+        let (size, offsets) = s.split_at(offset_start);
+
+        let size = match size.split_once(':') {
+            Some((width, height)) => {
+                let width = width.parse::<u32>().map_err(|_| invalid())?;
+                let height = height.parse::<u32>().map_err(|_| invalid())?;
+                if width == 0 || height == 0 {
+                    return Err(invalid());
+                }
+                Size::Aspect { width, height }
+            }
+            None => match size.split_once(['x', 'X']) {
+                Some((width, height)) => Size::Exact {
+                    width: width.parse()?,
+                    height: height.parse()?,
+                },
+                // A single percentage applies to both sides
+                None if size.ends_with('%') => {
+                    let length = size.parse()?;
+                    Size::Exact {
+                        width: length,
+                        height: length,
+                    }
+                }
+                None => return Err(invalid()),
+            },
+        };
+
+        let (x, y) = match offsets {
+            "" => (0, 0),
+            offsets => {
+                let split = offsets[1..]
+                    .find(['+', '-'])
+                    .map(|i| i + 1)
+                    .ok_or_else(invalid)?;
+                let (x, y) = offsets.split_at(split);
+                (
+                    x.parse::<i64>().map_err(|_| invalid())?,
+                    y.parse::<i64>().map_err(|_| invalid())?,
+                )
+            }
+        };
+
+        Ok(Geometry { size, x, y })
+    }
+}
+
+/// How a resize treats the aspect ratio of the image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Scales the image to fit inside the box, keeping its aspect ratio
+    #[default]
+    Fit,
+    /// Stretches the image to the box
+    Exact,
+    /// Scales the image to cover the box, keeping its aspect ratio,
+    /// then crops the overflow around the center
+    Cover,
+}
+
+/// ImageMagick style resize geometry.
+///
+/// Accepts `WxH`, `Wx` or `W` for a width alone, `xH` for a height alone
+/// and percentages such as `50%`. The box can be followed by `!` to ignore
+/// the aspect ratio, `^` to cover the box and `>` to never enlarge the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizeSpec {
+    pub width: Option<Length>,
+    pub height: Option<Length>,
+    pub mode: ResizeMode,
+    /// Leaves the image unchanged on any side it would have to grow
+    pub shrink_only: bool,
+}
+
+impl ResizeSpec {
+    /// Stretches images to exactly the given size
+    pub fn exact(width: u32, height: u32) -> Self {
+        Self {
+            width: Some(Length::Pixels(width)),
+            height: Some(Length::Pixels(height)),
+            mode: ResizeMode::Exact,
+            shrink_only: false,
+        }
+    }
+
+    /// Shrinks images so their longest side is at most `edge` pixels
+    pub fn max_edge(edge: u32) -> Self {
+        Self {
+            width: Some(Length::Pixels(edge)),
+            height: Some(Length::Pixels(edge)),
+            mode: ResizeMode::Fit,
+            shrink_only: true,
+        }
+    }
+
+    /// Dimensions an image of the given size is scaled to,
+    /// before the overflow of a cover resize is cropped
+    pub fn scaled_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let empty = || {
+            Error::operation(
+                None,
+                &format!("resizing the {width}x{height} image to {self} gives an empty image"),
+            )
+        };
+        if width == 0 || height == 0 {
+            return Err(empty());
+        }
+
+        let box_width = self.width.map(|length| length.resolve(width));
+        let box_height = self.height.map(|length| length.resolve(height));
+        let scale_x = box_width.map(|w| w as f64 / width as f64);
+        let scale_y = box_height.map(|h| h as f64 / height as f64);
+
+        let (mut scale_x, mut scale_y) = match (scale_x, scale_y, self.mode) {
+            (Some(x), Some(y), ResizeMode::Exact) => (x, y),
+            (Some(x), Some(y), ResizeMode::Fit) => (x.min(y), x.min(y)),
+            (Some(x), Some(y), ResizeMode::Cover) => (x.max(y), x.max(y)),
+            (Some(scale), None, _) | (None, Some(scale), _) => (scale, scale),
+            (None, None, _) => (1.0, 1.0),
+        };
+
+        if self.shrink_only {
+            scale_x = scale_x.min(1.0);
+            scale_y = scale_y.min(1.0);
+        }
+
+        // Sides given in the box are used as is to avoid rounding drift
+        let side = |total: u32, scale: f64, target: Option<u32>| match target {
+            Some(target) if (target as f64 - total as f64 * scale).abs() < 1.0 => target,
+            _ => (total as f64 * scale).round() as u32,
+        };
+        let scaled = (
+            side(width, scale_x, box_width),
+            side(height, scale_y, box_height),
+        );
+
+        match scaled {
+            (0, _) | (_, 0) => Err(empty()),
+            scaled => Ok(scaled),
+        }
+    }
+
+    /// Final dimensions of an image of the given size
+    pub fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let (scaled_width, scaled_height) = self.scaled_size(width, height)?;
+
+        match self.mode {
+            ResizeMode::Cover => Ok((
+                self.width
+                    .map_or(scaled_width, |w| w.resolve(width).min(scaled_width)),
+                self.height
+                    .map_or(scaled_height, |h| h.resolve(height).min(scaled_height)),
+            )),
+            _ => Ok((scaled_width, scaled_height)),
+        }
+    }
+}
+
+impl Display for ResizeSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.width, self.height) {
+            (Some(Length::Percent(w)), Some(Length::Percent(h))) if w == h => write!(f, "{w}%")?,
+            (width, height) => {
+                if let Some(width) = width {
+                    write!(f, "{width}")?;
+                }
+                write!(f, "x")?;
+                if let Some(height) = height {
+                    write!(f, "{height}")?;
+                }
+            }
+        }
+        match self.mode {
+            ResizeMode::Fit => (),
+            ResizeMode::Exact => write!(f, "!")?,
+            ResizeMode::Cover => write!(f, "^")?,
+        }
+        if self.shrink_only {
+            write!(f, ">")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ResizeSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::operation(
+                None,
+                &format!(
+                    "invalid resize geometry {s:?}, expected WxH, Wx, xH or N% followed by !, ^ or >"
+                ),
+            )
+        };
+
+        let mut mode = ResizeMode::Fit;
+        let mut shrink_only = false;
+        let mut size = s.trim();
+
+        while let Some(flag) = size.chars().last().filter(|c| matches!(c, '!' | '^' | '>')) {
+            match flag {
+                '!' if mode == ResizeMode::Fit => mode = ResizeMode::Exact,
+                '^' if mode == ResizeMode::Fit => mode = ResizeMode::Cover,
+                '>' if !shrink_only => shrink_only = true,
+                _ => return Err(invalid()),
+            }
+            size = &size[..size.len() - 1];
+        }
+
+        let side = |side: &str| match side {
+            "" => Ok(None),
+            side => match side.parse::<Length>()? {
+                Length::Pixels(0) => Err(invalid()),
+                length => Ok(Some(length)),
+            },
+        };
+
+        let (width, height) = match size.split_once(['x', 'X']) {
+            Some((width, height)) => (side(width)?, side(height)?),
+            // A single percentage scales both sides, a single number sets the width
+            None if size.ends_with('%') => (side(size)?, side(size)?),
+            None => (side(size)?, None),
+        };
+
+        if width.is_none() && height.is_none() {
+            return Err(invalid());
+        }
+
+        Ok(ResizeSpec {
+            width,
+            height,
+            mode,
+            shrink_only,
+        })
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::{Error, Result};
+use image::DynamicImage;
+use image::imageops::thumbnail;
+use std::f32::consts::PI;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Side of the grayscale image the DCT of the perceptual hash is computed on
+const DCT_SIZE: usize = 32;
+
+/// Side of the block of low frequencies, and of the average hash grid
+const HASH_SIZE: usize = 8;
+
+/// Algorithm used to hash an image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Pixels brighter than the mean, fast but sensitive to tone changes
+    Average,
+    /// Whether each pixel is brighter than its right neighbour,
+    /// follows gradients so survives tone changes
+    Difference,
+    /// Low frequencies of the discrete cosine transform above their median,
+    /// the most robust to scaling, compression and small edits
+    #[default]
+    Perceptual,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "ahash" | "average" => Ok(HashAlgorithm::Average),
+            "dhash" | "difference" => Ok(HashAlgorithm::Difference),
+            "phash" | "perceptual" => Ok(HashAlgorithm::Perceptual),
+            _ => Err(Error::operation(
+                None,
+                &format!("unknown hash algorithm {s:?}, must be ahash, dhash or phash"),
+            )),
+        }
+    }
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Average => write!(f, "ahash"),
+            HashAlgorithm::Difference => write!(f, "dhash"),
+            HashAlgorithm::Perceptual => write!(f, "phash"),
+        }
+    }
+}
+
+/// 64 bit perceptual hash, similar images have hashes a small Hamming distance apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Hashes the image, ignoring its size, format and bit depth
+    pub fn compute(image: &DynamicImage, algorithm: HashAlgorithm) -> Self {
+        let bits = match algorithm {
+            HashAlgorithm::Average => {
+                let pixels = gray(image, HASH_SIZE, HASH_SIZE);
+                let mean = pixels.iter().sum::<f32>() / pixels.len() as f32;
+                pixels
+                    .iter()
+                    .map(|&pixel| pixel > mean)
+                    .collect::<Vec<bool>>()
+            }
+            HashAlgorithm::Difference => {
+                let pixels = gray(image, HASH_SIZE + 1, HASH_SIZE);
+                pixels
+                    .chunks_exact(HASH_SIZE + 1)
+                    .flat_map(|row| row.windows(2).map(|pair| pair[0] < pair[1]))
+                    .collect()
+            }
+            HashAlgorithm::Perceptual => {
+                let pixels = gray(image, DCT_SIZE, DCT_SIZE);
+                let coefficients = low_frequencies(&pixels);
+                let mut sorted = coefficients.clone();
+                sorted.sort_by(f32::total_cmp);
+                let median = (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0;
+                coefficients.iter().map(|&value| value > median).collect()
+            }
+        };
+
+        Self(
+            bits.iter()
+                .fold(0u64, |hash, &bit| (hash << 1) | bit as u64),
+        )
+    }
+
+    /// Number of differing bits, from 0 for identical hashes to 64
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl Display for ImageHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ImageHash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        u64::from_str_radix(s.trim(), 16)
+            .map(ImageHash)
+            .map_err(|_| Error::operation(None, &format!("{s:?} is not a hexadecimal hash")))
+    }
+}
+
+/// Groups the hashes that are at most `max_distance` bits apart, directly or through
+/// other hashes in the group.
+///
+/// Returns the indices of every group with more than one hash, in the order
+/// their first hash appears.
+pub fn group_duplicates(hashes: &[ImageHash], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut parents = (0..hashes.len()).collect::<Vec<usize>>();
+
+    fn root(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+
+    for first in 0..hashes.len() {
+        for second in first + 1..hashes.len() {
+            if hashes[first].distance(&hashes[second]) <= max_distance {
+                let (a, b) = (root(&mut parents, first), root(&mut parents, second));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_root: Vec<Option<usize>> = vec![None; hashes.len()];
+    for index in 0..hashes.len() {
+        let root = root(&mut parents, index);
+        match group_of_root[root] {
+            Some(group) => groups[group].push(index),
+            None => {
+                group_of_root[root] = Some(groups.len());
+                groups.push(vec![index]);
+            }
+        }
+    }
+
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+/// Luma of the image shrunk to the given size, from 0 to 1.
+/// The luma is taken first so the bit depth makes no difference.
+fn gray(image: &DynamicImage, width: usize, height: usize) -> Vec<f32> {
+    thumbnail(&image.to_luma32f(), width as u32, height as u32).into_raw()
+}
+
+/// Top left block of the two dimensional DCT-II of a `DCT_SIZE` square image
+fn low_frequencies(pixels: &[f32]) -> Vec<f32> {
+    let cosines = (0..HASH_SIZE)
+        .map(|frequency| {
+            (0..DCT_SIZE)
+                .map(|x| ((2 * x + 1) as f32 * frequency as f32 * PI / (2 * DCT_SIZE) as f32).cos())
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<Vec<f32>>>();
+
+    // Transform the rows first, then the columns of the low frequencies
+    let rows = pixels
+        .chunks_exact(DCT_SIZE)
+        .map(|row| {
+            cosines
+                .iter()
+                .map(|cosine| row.iter().zip(cosine).map(|(p, c)| p * c).sum::<f32>())
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<Vec<f32>>>();
+
+    let mut coefficients = Vec::with_capacity(HASH_SIZE * HASH_SIZE);
+    for cosine in &cosines {
+        for u in 0..HASH_SIZE {
+            coefficients.push(
+                rows.iter()
+                    .zip(cosine)
+                    .map(|(row, c)| row[u] * c)
+                    .sum::<f32>(),
+            );
+        }
+    }
+    coefficients
+}
@@ -0,0 +1,802 @@
+//! Boxes of the ISO base media file format as used by AVIF images, to read and write
+//! the EXIF and XMP items and the ICC profile of the primary image
+
+use super::metadata::Metadata;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+const EXIF_ITEM: [u8; 4] = *b"Exif";
+const MIME_ITEM: [u8; 4] = *b"mime";
+const XMP_CONTENT_TYPE: &[u8] = b"application/rdf+xml";
+
+/// Reference from a metadata item to the image it describes
+const CONTENT_DESCRIBES: [u8; 4] = *b"cdsc";
+
+/// Color properties holding an ICC profile rather than color coefficients
+const ICC_COLOR_TYPES: [&[u8]; 2] = [b"prof", b"rICC"];
+
+/// Position of a box inside the data it was read from
+#[derive(Debug, Clone)]
+struct Span {
+    kind: [u8; 4],
+    start: usize,
+    body: Range<usize>,
+}
+
+/// An item listed in the `iinf` box
+#[derive(Debug, Clone)]
+struct Item {
+    id: u32,
+    kind: [u8; 4],
+    content_type: Vec<u8>,
+    /// Contents of the `infe` box, written back unchanged
+    entry: Vec<u8>,
+}
+
+impl Item {
+    fn new(id: u32, kind: [u8; 4], content_type: &[u8]) -> Self {
+        let mut entry = match u16::try_from(id) {
+            Ok(id) => [&[2, 0, 0, 0][..], &id.to_be_bytes()].concat(),
+            Err(_) => [&[3, 0, 0, 0][..], &id.to_be_bytes()].concat(),
+        };
+        // No protection and an empty name
+        entry.extend([0, 0]);
+        entry.extend(kind);
+        entry.push(0);
+        if !content_type.is_empty() {
+            entry.extend(content_type);
+            entry.push(0);
+        }
+
+        Self {
+            id,
+            kind,
+            content_type: content_type.to_vec(),
+            entry,
+        }
+    }
+
+    fn is_metadata(&self) -> bool {
+        self.kind == EXIF_ITEM || (self.kind == MIME_ITEM && self.content_type == XMP_CONTENT_TYPE)
+    }
+}
+
+/// Part of an item, its offset counted from the file start or the `idat` box
+#[derive(Debug, Clone)]
+struct Extent {
+    index: u64,
+    offset: u64,
+    length: u64,
+}
+
+/// Where the data of an item is stored, from the `iloc` box
+#[derive(Debug, Clone)]
+struct Location {
+    id: u32,
+    method: u16,
+    reference: u16,
+    extents: Vec<Extent>,
+}
+
+#[derive(Debug, Clone)]
+struct Locations {
+    version: u8,
+    index_size: u8,
+    items: Vec<Location>,
+}
+
+/// The parts of a `meta` box read and rewritten, other boxes are kept as they are
+#[derive(Debug, Clone)]
+struct Meta {
+    header: [u8; 4],
+    children: Vec<([u8; 4], Vec<u8>)>,
+    primary: u32,
+    items: Vec<Item>,
+    locations: Locations,
+    /// Boxes of the `ipco` box, referenced by their position counted from 1
+    properties: Vec<([u8; 4], Vec<u8>)>,
+    /// Properties of every item, flagged when essential
+    associations: Vec<(u32, Vec<(bool, u16)>)>,
+    reference_version: u8,
+    references: Vec<([u8; 4], Vec<u8>)>,
+}
+
+/// Whether the data starts like an ISO base media file, such as an AVIF image
+pub(crate) fn is_heif(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp")
+}
+
+/// Reads the EXIF and XMP items and the ICC profile of the primary image
+pub(crate) fn read_metadata(data: &[u8]) -> Option<Metadata> {
+    let span = spans(data)?
+        .into_iter()
+        .find(|span| &span.kind == b"meta")?;
+    let meta = Meta::read(&data[span.body])?;
+
+    let item = |metadata: fn(&Item) -> bool| {
+        let item = meta.items.iter().find(|item| metadata(item))?;
+        meta.item_data(data, item.id)
+    };
+    let exif = item(|item| item.kind == EXIF_ITEM).and_then(|payload| exif_payload(&payload));
+    let xmp = item(|item| item.kind == MIME_ITEM && item.content_type == XMP_CONTENT_TYPE);
+
+    let icc_profile = meta
+        .primary_properties()
+        .find(|&index| meta.is_icc(index))
+        .and_then(|index| meta.properties[index as usize - 1].1.get(4..))
+        .map(<[u8]>::to_vec);
+
+    Some(Metadata {
+        exif,
+        icc_profile,
+        xmp,
+    })
+}
+
+/// Reads the EXIF item of an AVIF image, seeking past the boxes before
+/// the `meta` box and reading nothing but the item data after it
+pub(crate) fn read_exif<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid AVIF box");
+
+    let meta = loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            // The box reaches the end of the file, no meta box follows
+            0 => return Ok(None),
+            1 => {
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                u64::from_be_bytes(size).checked_sub(16)
+            }
+            size => (size as u64).checked_sub(8),
+        };
+        let size = size.ok_or_else(invalid)?;
+
+        if &header[4..] == b"meta" {
+            let mut body = Vec::new();
+            reader.by_ref().take(size).read_to_end(&mut body)?;
+            break Meta::read(&body).ok_or_else(invalid)?;
+        }
+        reader.seek(SeekFrom::Current(
+            i64::try_from(size).map_err(|_| invalid())?,
+        ))?;
+    };
+
+    let Some(item) = meta.items.iter().find(|item| item.kind == EXIF_ITEM) else {
+        return Ok(None);
+    };
+    let payload = meta.read_item(item.id, |offset, length| {
+        reader.seek(SeekFrom::Start(offset)).ok()?;
+        let mut extent = Vec::new();
+        // A length of zero reaches the end of the file
+        let length = match length {
+            0 => u64::MAX,
+            length => length,
+        };
+        reader.by_ref().take(length).read_to_end(&mut extent).ok()?;
+        Some(extent)
+    });
+
+    Ok(payload.and_then(|payload| exif_payload(&payload)))
+}
+
+/// Replaces the EXIF and XMP items and the ICC profile of the primary image.
+///
+/// New items are stored in an `mdat` box appended to the file, the offsets of the
+/// data after the `meta` box are moved by as much as the box grows.
+/// Returns `None` when the boxes cannot be read.
+pub(crate) fn write_metadata(data: &[u8], metadata: &Metadata) -> Option<Vec<u8>> {
+    let span = spans(data)?
+        .into_iter()
+        .find(|span| &span.kind == b"meta")?;
+    let mut meta = Meta::read(&data[span.body.clone()])?;
+
+    let replaced = (meta.items.iter())
+        .filter(|item| item.is_metadata())
+        .map(|item| item.id)
+        .collect::<Vec<u32>>();
+    meta.items.retain(|item| !replaced.contains(&item.id));
+    meta.locations
+        .items
+        .retain(|location| !replaced.contains(&location.id));
+    let reference_version = meta.reference_version;
+    meta.references.retain(|(kind, body)| {
+        *kind != CONTENT_DESCRIBES
+            || Reader::new(body)
+                .id(reference_version == 0)
+                .is_none_or(|from| !replaced.contains(&from))
+    });
+    let icc = (1..=meta.properties.len() as u16)
+        .filter(|&index| meta.is_icc(index))
+        .collect::<Vec<u16>>();
+    for (id, properties) in &mut meta.associations {
+        if *id == meta.primary {
+            properties.retain(|(_, index)| !icc.contains(index));
+        }
+    }
+
+    let items = [
+        (
+            EXIF_ITEM,
+            &b""[..],
+            metadata
+                .exif
+                .as_ref()
+                .map(|exif| [&[0; 4], &exif[..]].concat()),
+        ),
+        (MIME_ITEM, XMP_CONTENT_TYPE, metadata.xmp.clone()),
+    ];
+    let mut next_id = (meta.items.iter().map(|item| item.id))
+        .chain(meta.locations.items.iter().map(|location| location.id))
+        .max()
+        .unwrap_or(0);
+    let mut appended = Vec::new();
+    let mut payload = Vec::new();
+
+    for (kind, content_type, item_data) in items {
+        let Some(item_data) = item_data else {
+            continue;
+        };
+        next_id = next_id.checked_add(1)?;
+        meta.items.push(Item::new(next_id, kind, content_type));
+        meta.references
+            .push((CONTENT_DESCRIBES, meta.reference(next_id)?));
+        meta.locations.items.push(Location {
+            id: next_id,
+            method: 0,
+            reference: 0,
+            extents: vec![Extent {
+                index: 0,
+                offset: 0,
+                length: item_data.len() as u64,
+            }],
+        });
+        appended.push((next_id, payload.len() as u64));
+        payload.extend(item_data);
+    }
+
+    if let Some(icc) = &metadata.icc_profile {
+        meta.properties
+            .push((*b"colr", [&b"prof"[..], icc].concat()));
+        let index = u16::try_from(meta.properties.len()).ok()?;
+        match meta
+            .associations
+            .iter_mut()
+            .find(|(id, _)| *id == meta.primary)
+        {
+            Some((_, properties)) => properties.push((false, index)),
+            None => meta.associations.push((meta.primary, vec![(false, index)])),
+        }
+    }
+
+    // Sizes in the rewritten boxes do not depend on the offsets,
+    // so the growth of the meta box is known from a first pass
+    let write = |growth: i64| {
+        let mut locations = meta.locations.clone();
+        for location in &mut locations.items {
+            let new = appended.iter().find(|(id, _)| *id == location.id);
+            for extent in &mut location.extents {
+                extent.offset = match new {
+                    Some((_, position)) => {
+                        (data.len() as u64 + 8 + position).checked_add_signed(growth)?
+                    }
+                    None if location.method == 0 && extent.offset >= span.body.end as u64 => {
+                        extent.offset.checked_add_signed(growth)?
+                    }
+                    None => extent.offset,
+                };
+            }
+        }
+        meta.write(&locations)
+    };
+    let growth = write(0)?.len() as i64 - (span.body.end - span.start) as i64;
+
+    let mut out = data[..span.start].to_vec();
+    out.extend(write(growth)?);
+    out.extend(&data[span.body.end..]);
+    if !payload.is_empty() {
+        push_box(&mut out, *b"mdat", &payload);
+    }
+    Some(out)
+}
+
+impl Meta {
+    fn read(body: &[u8]) -> Option<Self> {
+        let children = spans(body.get(4..)?)?
+            .into_iter()
+            .map(|span| (span.kind, body[4..][span.body].to_vec()))
+            .collect::<Vec<_>>();
+        let child = |kind: &[u8; 4]| {
+            (children.iter())
+                .find(|(child, _)| child == kind)
+                .map(|(_, body)| body.as_slice())
+        };
+
+        let mut pitm = Reader::new(child(b"pitm")?);
+        let version = pitm.u8()?;
+        pitm.bytes(3)?;
+        let primary = pitm.id(version == 0)?;
+
+        let (properties, associations) = match child(b"iprp") {
+            Some(iprp) => read_properties(iprp)?,
+            None => Default::default(),
+        };
+        let (reference_version, references) = match child(b"iref") {
+            Some(iref) => (
+                *iref.first()?,
+                spans(iref.get(4..)?)?
+                    .into_iter()
+                    .map(|span| (span.kind, iref[4..][span.body].to_vec()))
+                    .collect(),
+            ),
+            None => (0, Vec::new()),
+        };
+
+        Some(Self {
+            header: body.get(..4)?.try_into().ok()?,
+            primary,
+            items: read_items(child(b"iinf")?)?,
+            locations: Locations::read(child(b"iloc")?)?,
+            properties,
+            associations,
+            reference_version,
+            references,
+            children,
+        })
+    }
+
+    /// Concatenated extents of an item, stored in the file or in the `idat` box
+    fn item_data(&self, data: &[u8], id: u32) -> Option<Vec<u8>> {
+        self.read_item(id, |offset, length| {
+            slice(data, offset, length).map(<[u8]>::to_vec)
+        })
+    }
+
+    /// Concatenated extents of an item, those stored in the file read through `read`
+    /// with their offset and length
+    fn read_item(
+        &self,
+        id: u32,
+        mut read: impl FnMut(u64, u64) -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let location = (self.locations.items.iter()).find(|location| location.id == id)?;
+        let idat = (self.children.iter())
+            .find(|(kind, _)| kind == b"idat")
+            .map(|(_, body)| body.as_slice());
+
+        let mut item = Vec::new();
+        for extent in &location.extents {
+            match location.method {
+                0 => item.extend(read(extent.offset, extent.length)?),
+                1 => item.extend(slice(idat?, extent.offset, extent.length)?),
+                _ => return None,
+            }
+        }
+        Some(item)
+    }
+
+    fn primary_properties(&self) -> impl Iterator<Item = u16> + '_ {
+        (self.associations.iter())
+            .filter(|(id, _)| *id == self.primary)
+            .flat_map(|(_, properties)| properties.iter().map(|&(_, index)| index))
+    }
+
+    fn is_icc(&self, index: u16) -> bool {
+        (index as usize)
+            .checked_sub(1)
+            .and_then(|index| self.properties.get(index))
+            .is_some_and(|(kind, body)| {
+                kind == b"colr"
+                    && body
+                        .get(..4)
+                        .is_some_and(|kind| ICC_COLOR_TYPES.contains(&kind))
+            })
+    }
+
+    /// Contents of a reference from an item to the primary image
+    fn reference(&self, from: u32) -> Option<Vec<u8>> {
+        Some(match self.reference_version {
+            0 => [
+                u16::try_from(from).ok()?.to_be_bytes(),
+                1u16.to_be_bytes(),
+                u16::try_from(self.primary).ok()?.to_be_bytes(),
+            ]
+            .concat(),
+            _ => [
+                &from.to_be_bytes()[..],
+                &1u16.to_be_bytes(),
+                &self.primary.to_be_bytes(),
+            ]
+            .concat(),
+        })
+    }
+
+    /// Serializes the whole `meta` box with the given item locations
+    fn write(&self, locations: &Locations) -> Option<Vec<u8>> {
+        let mut body = self.header.to_vec();
+        let mut has_references = false;
+
+        for (kind, child) in &self.children {
+            let child = match kind {
+                b"iinf" => self.write_items()?,
+                b"iloc" => locations.write()?,
+                b"iprp" => self.write_properties(child)?,
+                b"iref" => {
+                    has_references = true;
+                    self.write_references()
+                }
+                _ => child.clone(),
+            };
+            push_box(&mut body, *kind, &child);
+        }
+        if !has_references && !self.references.is_empty() {
+            push_box(&mut body, *b"iref", &self.write_references());
+        }
+
+        let mut meta = Vec::new();
+        push_box(&mut meta, *b"meta", &body);
+        Some(meta)
+    }
+
+    fn write_items(&self) -> Option<Vec<u8>> {
+        let mut body = vec![0, 0, 0, 0];
+        body.extend(u16::try_from(self.items.len()).ok()?.to_be_bytes());
+        for item in &self.items {
+            push_box(&mut body, *b"infe", &item.entry);
+        }
+        Some(body)
+    }
+
+    /// Rewrites the `iprp` box, its `ipma` boxes merged into one
+    fn write_properties(&self, iprp: &[u8]) -> Option<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut associations_written = false;
+
+        for span in spans(iprp)? {
+            match &span.kind {
+                b"ipco" => {
+                    let mut ipco = Vec::new();
+                    for (kind, property) in &self.properties {
+                        push_box(&mut ipco, *kind, property);
+                    }
+                    push_box(&mut body, span.kind, &ipco);
+                }
+                b"ipma" if associations_written => (),
+                b"ipma" => {
+                    associations_written = true;
+                    push_box(&mut body, span.kind, &self.write_associations()?);
+                }
+                _ => push_box(&mut body, span.kind, &iprp[span.body]),
+            }
+        }
+        Some(body)
+    }
+
+    fn write_associations(&self) -> Option<Vec<u8>> {
+        let wide_ids = self.associations.iter().any(|(id, _)| *id > 0xFFFF);
+        let wide_indices = (self.associations.iter())
+            .flat_map(|(_, properties)| properties)
+            .any(|&(_, index)| index > 0x7F);
+
+        let mut associations = self.associations.clone();
+        associations.sort_by_key(|(id, _)| *id);
+
+        let mut body = vec![wide_ids as u8, 0, 0, wide_indices as u8];
+        body.extend((associations.len() as u32).to_be_bytes());
+        for (id, properties) in associations {
+            match wide_ids {
+                true => body.extend(id.to_be_bytes()),
+                false => body.extend((id as u16).to_be_bytes()),
+            }
+            body.push(u8::try_from(properties.len()).ok()?);
+            for (essential, index) in properties {
+                match wide_indices {
+                    true => body.extend((index | (essential as u16) << 15).to_be_bytes()),
+                    false => body.push(index as u8 | (essential as u8) << 7),
+                }
+            }
+        }
+        Some(body)
+    }
+
+    fn write_references(&self) -> Vec<u8> {
+        let mut body = vec![self.reference_version, 0, 0, 0];
+        for (kind, reference) in &self.references {
+            push_box(&mut body, *kind, reference);
+        }
+        body
+    }
+}
+
+impl Locations {
+    fn read(body: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(body);
+        let version = reader.u8()?;
+        reader.bytes(3)?;
+        let sizes = reader.u8()?;
+        let (offset_size, length_size) = (sizes >> 4, sizes & 0x0F);
+        let sizes = reader.u8()?;
+        let base_offset_size = sizes >> 4;
+        let index_size = match version {
+            0 => 0,
+            _ => sizes & 0x0F,
+        };
+        let count = match version {
+            0 | 1 => reader.u16()? as u32,
+            _ => reader.u32()?,
+        };
+
+        let mut items = Vec::new();
+        for _ in 0..count {
+            let id = reader.id(version < 2)?;
+            let method = match version {
+                0 => 0,
+                _ => reader.u16()? & 0x0F,
+            };
+            let reference = reader.u16()?;
+            let base_offset = reader.sized(base_offset_size)?;
+
+            let mut extents = Vec::new();
+            for _ in 0..reader.u16()? {
+                let index = reader.sized(index_size)?;
+                let offset = base_offset.checked_add(reader.sized(offset_size)?)?;
+                let length = reader.sized(length_size)?;
+                extents.push(Extent {
+                    index,
+                    offset,
+                    length,
+                });
+            }
+            items.push(Location {
+                id,
+                method,
+                reference,
+                extents,
+            });
+        }
+
+        Some(Self {
+            version,
+            index_size,
+            items,
+        })
+    }
+
+    /// Serializes the box with 32-bit offsets and lengths, without base offsets
+    fn write(&self) -> Option<Vec<u8>> {
+        let version = match self.items.iter().any(|location| location.id > 0xFFFF) {
+            true => 2,
+            false => self.version,
+        };
+        let index_size = match (version, self.index_size) {
+            (0, _) | (_, 0) => 0,
+            _ => 4,
+        };
+
+        let mut body = vec![version, 0, 0, 0, 0x44, index_size];
+        match version {
+            0 | 1 => body.extend(u16::try_from(self.items.len()).ok()?.to_be_bytes()),
+            _ => body.extend((self.items.len() as u32).to_be_bytes()),
+        }
+        for location in &self.items {
+            match version {
+                0 | 1 => body.extend((location.id as u16).to_be_bytes()),
+                _ => body.extend(location.id.to_be_bytes()),
+            }
+            if version > 0 {
+                body.extend(location.method.to_be_bytes());
+            }
+            body.extend(location.reference.to_be_bytes());
+            body.extend(u16::try_from(location.extents.len()).ok()?.to_be_bytes());
+            for extent in &location.extents {
+                if index_size > 0 {
+                    body.extend(u32::try_from(extent.index).ok()?.to_be_bytes());
+                }
+                body.extend(u32::try_from(extent.offset).ok()?.to_be_bytes());
+                body.extend(u32::try_from(extent.length).ok()?.to_be_bytes());
+            }
+        }
+        Some(body)
+    }
+}
+
+fn read_items(iinf: &[u8]) -> Option<Vec<Item>> {
+    let header = match iinf.first()? {
+        0 => 6,
+        _ => 8,
+    };
+
+    spans(iinf.get(header..)?)?
+        .into_iter()
+        .filter(|span| &span.kind == b"infe")
+        .map(|span| {
+            let entry = &iinf[header..][span.body];
+            let mut reader = Reader::new(entry);
+            let version = reader.u8()?;
+            reader.bytes(3)?;
+            let id = reader.id(version < 3)?;
+
+            // Entries before version 2 have no item type
+            let (kind, content_type) = match version {
+                0 | 1 => ([0; 4], Vec::new()),
+                _ => {
+                    reader.u16()?;
+                    let kind = reader.bytes(4)?.try_into().ok()?;
+                    reader.string()?;
+                    match kind == MIME_ITEM {
+                        true => (kind, reader.string()?.to_vec()),
+                        false => (kind, Vec::new()),
+                    }
+                }
+            };
+
+            Some(Item {
+                id,
+                kind,
+                content_type,
+                entry: entry.to_vec(),
+            })
+        })
+        .collect()
+}
+
+type Properties = (Vec<([u8; 4], Vec<u8>)>, Vec<(u32, Vec<(bool, u16)>)>);
+
+fn read_properties(iprp: &[u8]) -> Option<Properties> {
+    let mut properties = Vec::new();
+    let mut associations = Vec::new();
+
+    for span in spans(iprp)? {
+        let body = &iprp[span.body];
+        match &span.kind {
+            b"ipco" => {
+                properties = spans(body)?
+                    .into_iter()
+                    .map(|span| (span.kind, body[span.body].to_vec()))
+                    .collect();
+            }
+            b"ipma" => {
+                let mut reader = Reader::new(body);
+                let version = reader.u8()?;
+                let wide_indices = reader.bytes(3)?[2] & 1 == 1;
+
+                for _ in 0..reader.u32()? {
+                    let id = reader.id(version == 0)?;
+                    let mut item = Vec::new();
+                    for _ in 0..reader.u8()? {
+                        item.push(match wide_indices {
+                            true => reader
+                                .u16()
+                                .map(|value| (value >> 15 == 1, value & 0x7FFF))?,
+                            false => reader
+                                .u8()
+                                .map(|value| (value >> 7 == 1, (value & 0x7F) as u16))?,
+                        });
+                    }
+                    associations.push((id, item));
+                }
+            }
+            _ => (),
+        }
+    }
+    Some((properties, associations))
+}
+
+/// EXIF items start with the offset of the TIFF header
+fn exif_payload(payload: &[u8]) -> Option<Vec<u8>> {
+    let offset = u32::from_be_bytes(payload.get(..4)?.try_into().ok()?) as usize;
+    payload
+        .get(4usize.checked_add(offset)?..)
+        .map(<[u8]>::to_vec)
+}
+
+/// Part of the data at an offset, a length of zero reaching the end
+fn slice(data: &[u8], offset: u64, length: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok()?;
+    let end = match length {
+        0 => data.len(),
+        length => start.checked_add(usize::try_from(length).ok()?)?,
+    };
+    data.get(start..end)
+}
+
+/// Positions of the boxes following each other in the data
+fn spans(data: &[u8]) -> Option<Vec<Span>> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        let mut reader = Reader::new(&data[start..]);
+        let size = reader.u32()? as u64;
+        let kind = reader.bytes(4)?.try_into().ok()?;
+        let (header, size) = match size {
+            // The last box may reach the end of the data
+            0 => (8, (data.len() - start) as u64),
+            1 => (16, reader.u64()?),
+            size => (8, size),
+        };
+        let end = start.checked_add(usize::try_from(size).ok()?)?;
+        if end > data.len() || start + header > end {
+            return None;
+        }
+
+        spans.push(Span {
+            kind,
+            start,
+            body: start + header..end,
+        });
+        start = end;
+    }
+    Some(spans)
+}
+
+fn push_box(out: &mut Vec<u8>, kind: [u8; 4], body: &[u8]) {
+    out.extend((body.len() as u32 + 8).to_be_bytes());
+    out.extend(kind);
+    out.extend(body);
+}
+
+/// Reads big endian numbers one after the other
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(count)?;
+        let bytes = self.data.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    /// An item id, stored in 16 bits by older box versions
+    fn id(&mut self, short: bool) -> Option<u32> {
+        match short {
+            true => self.u16().map(u32::from),
+            false => self.u32(),
+        }
+    }
+
+    /// A number stored in 0, 4 or 8 bytes
+    fn sized(&mut self, size: u8) -> Option<u64> {
+        match size {
+            0 => Some(0),
+            4 => self.u32().map(u64::from),
+            8 => self.u64(),
+            _ => None,
+        }
+    }
+
+    /// A NUL terminated string, without the terminator
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let rest = self.data.get(self.position..)?;
+        let length = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(rest.len());
+        self.position += (length + 1).min(rest.len());
+        Some(&rest[..length])
+    }
+}
@@ -0,0 +1,227 @@
+//! TIFF image file directories, the layout shared by EXIF data and TIFF files
+
+/// Tags pointing to the EXIF, GPS and interoperability directories
+const POINTER_TAGS: [u16; 3] = [0x8769, 0x8825, 0xA005];
+
+/// Sub-directories nest at most twice, interoperability data sits inside the EXIF directory
+const MAX_DEPTH: usize = 2;
+
+const TYPE_LONG: u16 = 4;
+
+/// A directory entry, its value stored in little endian order
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+    pub value: Vec<u8>,
+}
+
+/// A directory with the sub-directories its pointer tags lead to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Directory {
+    pub entries: Vec<Entry>,
+    pub children: Vec<(u16, Directory)>,
+}
+
+impl Entry {
+    /// An entry holding a byte string, such as an ICC profile or an XMP packet
+    pub fn bytes(tag: u16, kind: u16, value: &[u8]) -> Self {
+        Self {
+            tag,
+            kind,
+            count: value.len() as u32,
+            value: value.to_vec(),
+        }
+    }
+}
+
+impl Directory {
+    /// Reads the first directory of TIFF structured data, such as raw EXIF data or a TIFF file
+    pub fn first(data: &[u8]) -> Option<Self> {
+        let little_endian = byte_order(data)?;
+        let offset = read_u32(data, 4, little_endian)? as usize;
+        Self::read(data, offset, little_endian, 0)
+    }
+
+    fn read(data: &[u8], offset: usize, little_endian: bool, depth: usize) -> Option<Self> {
+        let count = read_u16(data, offset, little_endian)? as usize;
+        let mut directory = Self::default();
+
+        for position in (0..count).map(|entry| offset + 2 + entry * 12) {
+            // Entries of unknown types or pointing outside the data are skipped
+            let (Some(tag), Some(kind), Some(count)) = (
+                read_u16(data, position, little_endian),
+                read_u16(data, position + 2, little_endian),
+                read_u32(data, position + 4, little_endian),
+            ) else {
+                break;
+            };
+            let Some(length) = type_size(kind).and_then(|size| size.checked_mul(count as usize))
+            else {
+                continue;
+            };
+            // Values of up to four bytes are stored in the entry itself
+            let start = match length {
+                0..=4 => Some(position + 8),
+                _ => read_u32(data, position + 8, little_endian).map(|start| start as usize),
+            };
+            let Some(value) = start.and_then(|start| data.get(start..start.checked_add(length)?))
+            else {
+                continue;
+            };
+
+            if POINTER_TAGS.contains(&tag) {
+                let child = (depth < MAX_DEPTH)
+                    .then(|| read_u32(data, position + 8, little_endian))
+                    .flatten()
+                    .and_then(|child| Self::read(data, child as usize, little_endian, depth + 1));
+                if let Some(child) = child {
+                    directory.children.push((tag, child));
+                }
+                continue;
+            }
+
+            let mut value = value.to_vec();
+            if !little_endian {
+                swap_order(kind, &mut value);
+            }
+            directory.entries.push(Entry {
+                tag,
+                kind,
+                count,
+                value,
+            });
+        }
+
+        Some(directory)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.children.is_empty()
+    }
+
+    pub fn entry(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    /// Serializes the directory as raw EXIF data, in little endian order
+    pub fn to_exif(&self) -> Vec<u8> {
+        let mut exif = b"II*\0\0\0\0\0".to_vec();
+        let offset = self.write(&mut exif, true);
+        exif[4..8].copy_from_slice(&offset.to_le_bytes());
+        exif
+    }
+
+    /// Appends the directory and its sub-directories, returning the offset of the directory.
+    /// Offsets are counted from the start of `out`, which holds the TIFF header.
+    pub fn write(&self, out: &mut Vec<u8>, little_endian: bool) -> u32 {
+        let mut entries = self.entries.clone();
+        for (tag, child) in &self.children {
+            let offset = child.write(out, little_endian);
+            entries.push(Entry {
+                tag: *tag,
+                kind: TYPE_LONG,
+                count: 1,
+                value: offset.to_le_bytes().to_vec(),
+            });
+        }
+        entries.sort_by_key(|entry| entry.tag);
+
+        // Directories and values start on a word boundary
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        let start = out.len();
+        let values_start = start + 2 + entries.len() * 12 + 4;
+        let mut values = Vec::new();
+
+        push_u16(out, entries.len() as u16, little_endian);
+        for entry in &entries {
+            push_u16(out, entry.tag, little_endian);
+            push_u16(out, entry.kind, little_endian);
+            push_u32(out, entry.count, little_endian);
+
+            let mut value = entry.value.clone();
+            if !little_endian {
+                swap_order(entry.kind, &mut value);
+            }
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                out.extend(value);
+            } else {
+                push_u32(out, (values_start + values.len()) as u32, little_endian);
+                values.extend(value);
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+            }
+        }
+        // No next directory
+        push_u32(out, 0, little_endian);
+        out.extend(values);
+
+        start as u32
+    }
+}
+
+/// Whether TIFF structured data is little endian, `None` without a valid header
+pub(crate) fn byte_order(data: &[u8]) -> Option<bool> {
+    match data.get(..4)? {
+        b"II*\0" => Some(true),
+        b"MM\0*" => Some(false),
+        _ => None,
+    }
+}
+
+/// Size in bytes of a single value of an entry type
+fn type_size(kind: u16) -> Option<usize> {
+    match kind {
+        // Bytes, ASCII, signed bytes and undefined
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+/// Reverses the bytes of every number in a value
+fn swap_order(kind: u16, value: &mut [u8]) {
+    let size = match kind {
+        // Rationals are pairs of 32-bit numbers
+        5 | 10 => 4,
+        _ => type_size(kind).unwrap_or(1),
+    };
+    value.chunks_exact_mut(size).for_each(<[u8]>::reverse);
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?.try_into().ok()?;
+    Some(match little_endian {
+        true => u16::from_le_bytes(bytes),
+        false => u16::from_be_bytes(bytes),
+    })
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(match little_endian {
+        true => u32::from_le_bytes(bytes),
+        false => u32::from_be_bytes(bytes),
+    })
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16, little_endian: bool) {
+    out.extend(match little_endian {
+        true => value.to_le_bytes(),
+        false => value.to_be_bytes(),
+    });
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32, little_endian: bool) {
+    out.extend(match little_endian {
+        true => value.to_le_bytes(),
+        false => value.to_be_bytes(),
+    });
+}
@@ -1,25 +1,219 @@
 use super::color::ColorInfo;
-use image::{DynamicImage, ImageFormat};
-use std::fs::metadata;
-use std::path::PathBuf;
+use super::manipulator::probe_image;
+use crate::error::{Error, Result};
+use image::{ImageError, ImageFormat};
+use std::fmt::Display;
+use std::fs::{File, metadata};
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 
-// TODO: Pretty displaying
-pub fn print_info(image: &DynamicImage, path: PathBuf, do_short: bool) {
-    let (height, width) = (image.height(), image.width());
-    let format = ImageFormat::from_path(&path).expect("Error decoding image format: ");
+/// Information about an image file
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ImageInfo {
+    pub path: PathBuf,
+    /// File size in bytes
+    pub file_size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    /// Color type of the decoded image
+    pub color: ColorInfo,
+    /// Color type as stored in the file, such as 1-bit luma
+    pub source_color: ColorInfo,
+    /// Number of frames, 1 for still images
+    pub frames: usize,
+    pub has_alpha: bool,
+}
+
+impl ImageInfo {
+    /// Reads the information of an image file.
+    ///
+    /// Only the header is read, along with the block and chunk headers
+    /// of animated images to count their frames.
+    pub fn read(path: &Path) -> Result<Self> {
+        let probe = probe_image(path)?;
+
+        let file_size = metadata(path).map_err(|e| Error::io(Some(path), e))?.len();
+
+        let frames = count_frames(path, probe.format).map_err(|e| e.with_path(path))?;
+
+        Ok(Self {
+            path: probe.path,
+            file_size,
+            width: probe.width,
+            height: probe.height,
+            format: probe.format,
+            color: ColorInfo::from(probe.color_type),
+            source_color: probe.original_color,
+            frames,
+            has_alpha: probe.color_type.has_alpha(),
+        })
+    }
+
+    /// Human readable summary, leaving out the color details when `short` is set
+    pub fn to_text(&self, short: bool) -> String {
+        let mut text = format!(
+            "Image file: {:?}\nFile size: {} bytes\nDimensions: {}x{}\nFormat: {}",
+            self.path.as_os_str(),
+            self.file_size,
+            self.width,
+            self.height,
+            self.format.to_mime_type()
+        );
+
+        if !short {
+            text.push_str(&format!(
+                "\nColor space: {}\nBit depth: {}\nAlpha: {}\nFrames: {}",
+                self.color.color_space,
+                self.color.bit_depth,
+                if self.has_alpha { "yes" } else { "no" },
+                self.frames
+            ));
+
+            if self.source_color != self.color {
+                text.push_str(&format!(
+                    "\nStored as: {} {}-bit",
+                    self.source_color.color_space, self.source_color.bit_depth
+                ));
+            }
+        }
+        text
+    }
+}
+
+impl Display for ImageInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_text(false))
+    }
+}
+
+/// Counts the frames of GIF, APNG and animated WebP images from their blocks
+/// and chunks, skipping the image data instead of decoding it.
+/// Fails at the first block or chunk that is cut short or malformed.
+pub(crate) fn count_frames(path: &Path, format: ImageFormat) -> Result<usize> {
+    let count = match format {
+        ImageFormat::Gif => count_gif_frames,
+        ImageFormat::Png => count_png_frames,
+        ImageFormat::WebP => count_webp_frames,
+        _ => return Ok(1),
+    };
+
+    let mut reader = File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Error::io(Some(path), e))?;
+    count(&mut reader).map_err(|e| Error::decode(None, ImageError::IoError(e)))
+}
 
-    let meta = metadata(&path).unwrap();
-    let size = meta.len();
+/// Counts the image descriptors between the GIF header and trailer
+fn count_gif_frames(reader: &mut BufReader<File>) -> io::Result<usize> {
+    let mut header = [0; 13];
+    reader.read_exact(&mut header)?;
+    reader.seek_relative(gif_color_table_size(header[10]))?;
 
-    let color_info = ColorInfo::from_image(image);
+    let mut frames = 0;
+    loop {
+        let mut block = [0];
+        match reader.read_exact(&mut block) {
+            // Some encoders leave out the trailer
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(frames),
+            result => result?,
+        }
 
-    println!("Image file: {:?}", path.as_os_str());
-    println!("File size: {} bytes", size);
-    println!("Dimensions: {}x{}", width, height);
-    println!("Format: {}", format.to_mime_type());
+        match block[0] {
+            // Extension label followed by its data
+            0x21 => {
+                reader.seek_relative(1)?;
+                skip_gif_sub_blocks(reader)?;
+            }
+            // Image descriptor, local color table, LZW code size and the image data
+            0x2C => {
+                let mut descriptor = [0; 9];
+                reader.read_exact(&mut descriptor)?;
+                reader.seek_relative(gif_color_table_size(descriptor[8]) + 1)?;
+                skip_gif_sub_blocks(reader)?;
+                frames += 1;
+            }
+            0x3B => return Ok(frames),
+            block => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown GIF block {block:#04x}"),
+                ));
+            }
+        }
+    }
+}
+
+/// Size in bytes of the color table announced by GIF flags
+fn gif_color_table_size(flags: u8) -> i64 {
+    match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 0x07) + 1),
+    }
+}
 
-    if !do_short {
-        println!("Color space: {}", color_info.color_space);
-        println!("Bit depth: {}", color_info.bit_depth);
+fn skip_gif_sub_blocks(reader: &mut BufReader<File>) -> io::Result<()> {
+    loop {
+        let mut length = [0];
+        reader.read_exact(&mut length)?;
+        match length[0] {
+            0 => return Ok(()),
+            length => reader.seek_relative(length as i64)?,
+        }
     }
 }
+
+/// Reads the frame count of the animation control chunk, which APNG images
+/// place before their image data
+fn count_png_frames(reader: &mut BufReader<File>) -> io::Result<usize> {
+    reader.seek_relative(8)?;
+
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+
+        match &header[4..] {
+            b"acTL" => {
+                let mut frames = [0; 4];
+                reader.read_exact(&mut frames)?;
+                return Ok(u32::from_be_bytes(frames) as usize);
+            }
+            b"IDAT" | b"IEND" => return Ok(1),
+            // Chunk data and CRC
+            _ => reader.seek_relative(length as i64 + 4)?,
+        }
+    }
+}
+
+/// Counts the animation frame chunks of a WebP image, 1 for still images
+fn count_webp_frames(reader: &mut BufReader<File>) -> io::Result<usize> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WEBP" {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "missing WebP RIFF header",
+        ));
+    }
+    let end = 8 + u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+
+    let mut position = 12;
+    let mut frames = 0;
+    while position + 8 <= end {
+        let mut chunk = [0; 8];
+        reader.read_exact(&mut chunk)?;
+        // Chunks are padded to an even size
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+        let size = size + size % 2;
+
+        if &chunk[..4] == b"ANMF" {
+            frames += 1;
+        }
+        reader.seek_relative(size as i64)?;
+        position += 8 + size;
+    }
+
+    Ok(frames.max(1))
+}
@@ -0,0 +1,408 @@
+use crate::error::{Error, Result};
+use image::ImageFormat;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Shell style pattern matched against paths.
+///
+/// Supports `*` and `?` within a path component, `**` across components,
+/// classes such as `[abc]`, `[a-z]` or `[!0-9]`, alternatives such as `{png,jpg}`
+/// and `\` to escape. Patterns without a `/` are matched against the file name,
+/// others against the path relative to the directory being searched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    pattern: String,
+    /// The pattern with every alternative expanded
+    expanded: Vec<Vec<char>>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let expanded = expand_braces(pattern)?
+            .into_iter()
+            .map(|pattern| pattern.chars().collect::<Vec<char>>())
+            .collect::<Vec<Vec<char>>>();
+
+        for alternative in &expanded {
+            check_classes(alternative).map_err(|reason| {
+                Error::operation(None, &format!("invalid glob {pattern:?}: {reason}"))
+            })?;
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            expanded,
+        })
+    }
+
+    /// Whether the path, relative to the searched directory, matches the pattern
+    pub fn is_match(&self, path: &Path) -> bool {
+        let text = match self.pattern.contains('/') {
+            true => slash_path(path),
+            false => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        let text = text.chars().collect::<Vec<char>>();
+
+        self.expanded.iter().any(|pattern| matches(pattern, &text))
+    }
+}
+
+impl FromStr for Glob {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Glob::new(s)
+    }
+}
+
+impl Display for Glob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+/// Which files are taken from directories
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Search subdirectories too
+    pub recursive: bool,
+    /// Only take files matching one of these, every file when empty
+    pub include: Vec<Glob>,
+    /// Skip files and directories matching any of these
+    pub exclude: Vec<Glob>,
+}
+
+impl InputOptions {
+    fn includes(&self, relative: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.is_match(relative)))
+            && !self.excludes(relative)
+    }
+
+    fn excludes(&self, relative: &Path) -> bool {
+        self.exclude.iter().any(|glob| glob.is_match(relative))
+    }
+}
+
+/// Whether the extension of the path is one rimlib can decode
+pub fn is_decodable(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Expands the input paths into the list of images to process.
+///
+/// Files are kept as given unless their path, as given, matches an exclude pattern.
+/// Directories are replaced by the images in them, found by extension and filtered
+/// by the include and exclude patterns, sorted by path.
+/// Paths given more than once are only kept the first time.
+pub fn collect_inputs(paths: &[PathBuf], options: &InputOptions) -> Result<Vec<PathBuf>> {
+    let mut images = Vec::new();
+
+    for path in paths {
+        match path.is_dir() {
+            true => walk(path, path, options, &mut images)?,
+            false if options.excludes(path) => (),
+            false => images.push(path.to_path_buf()),
+        }
+    }
+
+    let mut seen = HashSet::new();
+    images.retain(|path| seen.insert(path.clone()));
+    Ok(images)
+}
+
+fn walk(
+    root: &Path,
+    directory: &Path,
+    options: &InputOptions,
+    images: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(directory)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<fs::DirEntry>>>())
+        .map_err(|e| Error::io(Some(directory), e))?;
+    entries.sort_by_key(|entry| entry.path());
+
+    for entry in entries {
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        // Symbolic links to directories are not followed, so links cannot loop
+        let file_type = entry.file_type().map_err(|e| Error::io(Some(&path), e))?;
+
+        if file_type.is_dir() {
+            if options.recursive && !options.excludes(relative) {
+                walk(root, &path, options, images)?;
+            }
+        } else if path.is_file() && is_decodable(&path) && options.includes(relative) {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Directory of every image relative to the inputs it was found through,
+/// to recreate the input tree under an output directory.
+///
+/// Images are placed relative to the deepest directory holding every given input.
+/// A single given directory is the root itself, while sibling directories such as
+/// `shoots/a` and `shoots/b` keep their names like with `cp -r`. Files given directly
+/// count through the directory they are in.
+pub fn relative_dirs(images: &[PathBuf], given: &[PathBuf]) -> Vec<PathBuf> {
+    let root = given
+        .iter()
+        .map(|path| {
+            let path = normalize(path);
+            match path.is_dir() {
+                true => path,
+                false => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            }
+        })
+        .reduce(|common, path| {
+            common
+                .components()
+                .zip(path.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    images
+        .iter()
+        .map(|image| {
+            normalize(image)
+                .parent()
+                .and_then(|parent| parent.strip_prefix(&root).ok())
+                .map(Path::to_path_buf)
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Absolute path with `.` and `..` resolved, so inputs given through `..`
+/// cannot end up outside the output directory
+fn normalize(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Reads a list of paths, one per line or separated by NUL bytes as written by
+/// `find -print0`. Empty entries are skipped.
+pub fn read_path_list<R: Read>(mut reader: R) -> Result<Vec<PathBuf>> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| Error::io(None, e))?;
+
+    let separator = match bytes.contains(&0) {
+        true => b'\0',
+        false => b'\n',
+    };
+
+    Ok(bytes
+        .split(|&byte| byte == separator)
+        .map(|entry| match separator {
+            b'\n' => entry.strip_suffix(b"\r").unwrap_or(entry),
+            _ => entry,
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).to_string())
+}
+
+/// The path with `/` separators whatever the platform
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            Component::ParentDir => Some("..".into()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Expands `{a,b}` alternatives into one pattern each
+fn expand_braces(pattern: &str) -> Result<Vec<String>> {
+    let chars = pattern.chars().collect::<Vec<char>>();
+    let unbalanced = || {
+        Error::operation(
+            None,
+            &format!("invalid glob {pattern:?}: unbalanced braces"),
+        )
+    };
+
+    let mut depth = 0;
+    let mut open = None;
+    let mut commas = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        match chars[index] {
+            '\\' => index += 1,
+            // Braces and commas in a class are characters to match
+            '[' => index = class_end(&chars, index).unwrap_or(index),
+            '{' => {
+                if depth == 0 {
+                    open = Some(index);
+                }
+                depth += 1;
+            }
+            ',' if depth == 1 => commas.push(index),
+            '}' if depth == 0 => return Err(unbalanced()),
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    let open = open.unwrap_or_default();
+                    let prefix = chars[..open].iter().collect::<String>();
+                    let suffix = chars[index + 1..].iter().collect::<String>();
+
+                    let mut bounds = vec![open];
+                    bounds.extend(&commas);
+                    bounds.push(index);
+
+                    let mut expanded = Vec::new();
+                    for pair in bounds.windows(2) {
+                        let alternative = chars[pair[0] + 1..pair[1]].iter().collect::<String>();
+                        expanded.extend(expand_braces(&format!("{prefix}{alternative}{suffix}"))?);
+                    }
+                    return Ok(expanded);
+                }
+            }
+            _ => (),
+        }
+        index += 1;
+    }
+
+    match depth {
+        0 => Ok(vec![pattern.to_string()]),
+        _ => Err(unbalanced()),
+    }
+}
+
+/// Checks that every class is closed
+fn check_classes(pattern: &[char]) -> std::result::Result<(), &'static str> {
+    let mut index = 0;
+    while index < pattern.len() {
+        match pattern[index] {
+            '\\' => index += 1,
+            '[' => match class_end(pattern, index) {
+                Some(end) => index = end,
+                None => return Err("unclosed ["),
+            },
+            _ => (),
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Index of the `]` closing the class opened at `start`
+fn class_end(pattern: &[char], start: usize) -> Option<usize> {
+    let mut index = start + 1;
+    if matches!(pattern.get(index), Some('!' | '^')) {
+        index += 1;
+    }
+    // A `]` right after the opening bracket is part of the class
+    if pattern.get(index) == Some(&']') {
+        index += 1;
+    }
+    (index..pattern.len()).find(|&i| pattern[i] == ']')
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!' | '^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+
+    let mut index = 0;
+    let mut found = false;
+    while index < class.len() {
+        if class.get(index + 1) == Some(&'-') && index + 2 < class.len() {
+            found |= (class[index]..=class[index + 2]).contains(&c);
+            index += 3;
+        } else {
+            found |= class[index] == c;
+            index += 1;
+        }
+    }
+    found != negated
+}
+
+/// Whether the whole text matches the pattern.
+///
+/// Results are remembered for every pattern and text position, so patterns
+/// with many wildcards such as `*a*a*a*b` cannot backtrack exponentially.
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    let mut known = vec![None; (pattern.len() + 1) * (text.len() + 1)];
+    matches_from(pattern, text, 0, 0, &mut known)
+}
+
+fn matches_from(
+    pattern: &[char],
+    text: &[char],
+    p: usize,
+    t: usize,
+    known: &mut [Option<bool>],
+) -> bool {
+    let key = p * (text.len() + 1) + t;
+    if let Some(matched) = known[key] {
+        return matched;
+    }
+    let mut rest = |p: usize, t: usize| matches_from(pattern, text, p, t, known);
+
+    let matched = match pattern.get(p) {
+        None => t == text.len(),
+        Some('*') if pattern.get(p + 1) == Some(&'*') => {
+            // `**/` also matches no directory at all
+            (pattern.get(p + 2) == Some(&'/') && rest(p + 3, t))
+                || (t..=text.len()).any(|start| rest(p + 2, start))
+        }
+        Some('*') => (t..=text.len())
+            .take_while(|&end| end == t || text[end - 1] != '/')
+            .any(|start| rest(p + 1, start)),
+        Some('?') => text.get(t).is_some_and(|&c| c != '/') && rest(p + 1, t + 1),
+        Some('[') => match class_end(pattern, p) {
+            Some(end) => {
+                text.get(t)
+                    .is_some_and(|&c| c != '/' && class_matches(&pattern[p + 1..end], c))
+                    && rest(end + 1, t + 1)
+            }
+            None => text.get(t) == Some(&'[') && rest(p + 1, t + 1),
+        },
+        Some('\\') if p + 1 < pattern.len() => {
+            text.get(t) == Some(&pattern[p + 1]) && rest(p + 2, t + 1)
+        }
+        Some(&c) => text.get(t) == Some(&c) && rest(p + 1, t + 1),
+    };
+
+    known[key] = Some(matched);
+    matched
+}
@@ -1,39 +1,275 @@
-use image::{DynamicImage, ImageReader};
+use super::color::{BitDepth, ColorInfo, ColorSpace};
+use super::geometry::{Geometry, Gravity};
+use super::metadata::{self, Metadata};
+use crate::error::{Error, Result};
+use image::error::{DecodingError, ImageFormatHint};
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{ColorType, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+use img_parts::Bytes;
 use std::fs::File;
-use std::io::{Cursor, Read};
-use std::path::Path;
+use std::io::{BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
 
-pub fn open_image(image_path: &Path) -> Result<DynamicImage, String> {
-    let mut file = match File::open(image_path) {
-        Ok(file) => file,
-        Err(file_error) => return Err(file_error.to_string()),
+/// Image information read from the file header without decoding any pixels
+#[derive(Debug, Clone)]
+pub struct ImageProbe {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Color type the image will have once decoded
+    pub color_type: ColorType,
+    /// Color layout as stored in the file, such as 1-bit luma or a palette
+    pub original_color: ColorInfo,
+    /// Orientation the pixels must be turned to before display
+    pub orientation: Orientation,
+}
+
+impl ImageProbe {
+    /// Dimensions of the image once turned upright according to its orientation
+    pub fn upright_size(&self) -> (u32, u32) {
+        match self.orientation {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (self.height, self.width),
+            _ => (self.width, self.height),
+        }
+    }
+}
+
+pub fn open_image(image_path: &Path) -> Result<DynamicImage> {
+    open_image_with_metadata(image_path).map(|(image, _)| image)
+}
+
+/// Decodes an image along with its EXIF, ICC and XMP metadata
+pub fn open_image_with_metadata(image_path: &Path) -> Result<(DynamicImage, Metadata)> {
+    let mut file = File::open(image_path).map_err(|e| Error::io(Some(image_path), e))?;
+
+    let len = file
+        .metadata()
+        .map_err(|e| Error::io(Some(image_path), e))?
+        .len();
+
+    let mut buffer = Vec::with_capacity(len as usize + 1);
+
+    file.read_to_end(&mut buffer)
+        .map_err(|e| Error::io(Some(image_path), e))?;
+
+    let buffer = Bytes::from(buffer);
+    let container_metadata = Metadata::from_bytes(buffer.clone());
+
+    let mut decoder = ImageReader::new(Cursor::new(&buffer[..]))
+        .with_guessed_format()
+        .map_err(|e| Error::io(Some(image_path), e))?
+        .into_decoder()
+        .map_err(|e| Error::decode(Some(image_path), e))?;
+
+    decoder
+        .set_limits(Limits::default())
+        .map_err(|e| Error::decode(Some(image_path), e))?;
+
+    let metadata = match container_metadata {
+        Some(metadata) => metadata,
+        None => Metadata::from_decoder(&mut decoder),
     };
 
-    let len = match file.metadata() {
-        Ok(data) => data.len(),
-        Err(metadata_error) => return Err(metadata_error.to_string()),
+    let image =
+        DynamicImage::from_decoder(decoder).map_err(|e| Error::decode(Some(image_path), e))?;
+
+    Ok((image, metadata))
+}
+
+/// Reads the EXIF, ICC and XMP metadata of an image without decoding its pixels
+pub fn read_metadata(image_path: &Path) -> Result<Metadata> {
+    let buffer =
+        Bytes::from(std::fs::read(image_path).map_err(|e| Error::io(Some(image_path), e))?);
+
+    if let Some(metadata) = Metadata::from_bytes(buffer.clone()) {
+        return Ok(metadata);
+    }
+
+    let mut decoder = ImageReader::new(Cursor::new(&buffer[..]))
+        .with_guessed_format()
+        .map_err(|e| Error::io(Some(image_path), e))?
+        .into_decoder()
+        .map_err(|e| Error::decode(Some(image_path), e))?;
+
+    Ok(Metadata::from_decoder(&mut decoder))
+}
+
+/// Reads the EXIF data of an image without reading its pixel data.
+///
+/// JPEG, PNG, WebP and AVIF images are only read where they hold EXIF data.
+/// TIFF files, whose tags may be stored anywhere, are read whole.
+pub fn read_exif(image_path: &Path) -> Result<Option<Vec<u8>>> {
+    let reader = ImageReader::open(image_path)
+        .map_err(|e| Error::io(Some(image_path), e))?
+        .with_guessed_format()
+        .map_err(|e| Error::io(Some(image_path), e))?;
+
+    match reader.format() {
+        Some(ImageFormat::Tiff) => Ok(read_metadata(image_path)?.exif),
+        Some(format) => metadata::read_exif(&mut reader.into_inner(), format)
+            .map_err(|e| Error::decode(Some(image_path), ImageError::IoError(e))),
+        None => Ok(None),
+    }
+}
+
+/// Decodes an image with its metadata and turns it upright
+/// according to its EXIF orientation.
+///
+/// The orientation in the returned metadata is reset so viewers
+/// do not rotate the image a second time.
+pub fn open_image_oriented(image_path: &Path) -> Result<(DynamicImage, Metadata)> {
+    let (mut image, mut metadata) = open_image_with_metadata(image_path)?;
+
+    if let Some(orientation) = metadata.orientation() {
+        image.apply_orientation(orientation);
+        metadata.reset_orientation();
+    }
+
+    Ok((image, metadata))
+}
+
+/// Reads the dimensions, format and color type of an image.
+/// Only the header is read, the pixel data is left untouched.
+pub fn probe_image(image_path: &Path) -> Result<ImageProbe> {
+    let reader = ImageReader::open(image_path)
+        .map_err(|e| Error::io(Some(image_path), e))?
+        .with_guessed_format()
+        .map_err(|e| Error::io(Some(image_path), e))?;
+
+    let format = match reader.format() {
+        Some(format) => format,
+        None => {
+            return Err(Error::unsupported_format(
+                Some(image_path),
+                &image_path
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            ));
+        }
     };
 
-    let mut buffer = Vec::with_capacity(len as usize + 1);
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| Error::decode(Some(image_path), e))?;
+
+    let (width, height) = decoder.dimensions();
+    let orientation = match decoder.orientation() {
+        Ok(orientation) if orientation != Orientation::NoTransforms => orientation,
+        // The PNG decoder does not expose EXIF data, so the chunk is read from the container
+        _ if format == ImageFormat::Png => std::fs::read(image_path)
+            .ok()
+            .and_then(|bytes| Metadata::from_bytes(Bytes::from(bytes)))
+            .and_then(|metadata| metadata.orientation())
+            .unwrap_or(Orientation::NoTransforms),
+        _ => Orientation::NoTransforms,
+    };
 
-    match file.read_to_end(&mut buffer) {
-        Ok(_) => (),
-        Err(read_error) => return Err(read_error.to_string()),
+    Ok(ImageProbe {
+        path: image_path.to_path_buf(),
+        format,
+        width,
+        height,
+        color_type: decoder.color_type(),
+        original_color: stored_color(image_path, format, &decoder)?,
+        orientation,
+    })
+}
+
+/// Color layout as stored in the file.
+///
+/// The PNG and GIF decoders report the color type the pixels are expanded to,
+/// so palettes and packed pixels are read from the headers of these formats.
+fn stored_color(
+    image_path: &Path,
+    format: ImageFormat,
+    decoder: &impl ImageDecoder,
+) -> Result<ColorInfo> {
+    let open = || {
+        File::open(image_path)
+            .map(BufReader::new)
+            .map_err(|e| Error::io(Some(image_path), e))
+    };
+    let header_error = |error: Box<dyn std::error::Error + Send + Sync>| {
+        Error::decode(
+            Some(image_path),
+            ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), error)),
+        )
+    };
+
+    match format {
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(open()?)
+                .read_info()
+                .map_err(|e| header_error(e.into()))?;
+            let info = reader.info();
+
+            let color_space = match info.color_type {
+                png::ColorType::Grayscale => ColorSpace::Luma,
+                png::ColorType::GrayscaleAlpha => ColorSpace::LumaA,
+                png::ColorType::Rgb => ColorSpace::Rgb,
+                png::ColorType::Rgba => ColorSpace::RgbA,
+                png::ColorType::Indexed => ColorSpace::Indexed,
+            };
+            let bit_depth = BitDepth::try_from(info.bit_depth as u8)?;
+
+            Ok(ColorInfo::new(&color_space, &bit_depth))
+        }
+        ImageFormat::Gif => {
+            let mut options = gif::DecodeOptions::new();
+            options.set_color_output(gif::ColorOutput::Indexed);
+            let mut reader = options
+                .read_info(open()?)
+                .map_err(|e| header_error(e.into()))?;
+
+            // Frames without a palette of their own use the global one
+            let colors = match reader.global_palette() {
+                Some(palette) => palette.len() / 3,
+                None => reader
+                    .next_frame_info()
+                    .map_err(|e| header_error(e.into()))?
+                    .and_then(|frame| frame.palette.as_ref())
+                    .map_or(0, |palette| palette.len() / 3),
+            };
+            Ok(ColorInfo::new(
+                &ColorSpace::Indexed,
+                &BitDepth::for_palette(colors),
+            ))
+        }
+        _ => {
+            ColorInfo::try_from(decoder.original_color_type()).map_err(|e| e.with_path(image_path))
+        }
     }
+}
 
-    let reader = Cursor::new(buffer);
-
-    match ImageReader::new(reader).with_guessed_format() {
-        Ok(reader) => match reader.decode() {
-            Ok(image) => Ok(image),
-            Err(decode_error) => Err(format!(
-                "Error decoding image {:?}: {}",
-                image_path, decode_error
-            )),
-        },
-        Err(decode_error) => Err(format!(
-            "Error decoding image {:?}: {}",
-            image_path, decode_error
+/// Obtains an image sampling filter from its name, ignoring case
+pub fn filter_type(name: &str) -> Result<FilterType> {
+    match name.to_lowercase().as_str() {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" | "linear" => Ok(FilterType::Triangle),
+        "catmullrom" | "cubic" => Ok(FilterType::CatmullRom),
+        "gaussian" => Ok(FilterType::Gaussian),
+        "lanczos" | "lanczos3" => Ok(FilterType::Lanczos3),
+        _ => Err(Error::operation(
+            None,
+            &format!("unknown sampling filter {name:?}"),
         )),
     }
 }
+
+/// Crops the image to the region described by the geometry,
+/// placed relative to the gravity anchor
+pub fn crop_image(
+    image: DynamicImage,
+    geometry: &Geometry,
+    gravity: Gravity,
+) -> Result<DynamicImage> {
+    let rect = geometry.rect(image.width(), image.height(), gravity)?;
+
+    Ok(image.crop_imm(rect.x, rect.y, rect.width, rect.height))
+}
@@ -0,0 +1,613 @@
+use image::error::{EncodingError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageError, ImageFormat};
+use img_parts::jpeg::{Jpeg, JpegSegment, markers};
+use img_parts::png::{Png, PngChunk};
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::{
+    CHUNK_ALPH, CHUNK_ANIM, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP, WebP,
+};
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+
+use super::heif;
+use super::ifd::{Directory, Entry, byte_order, read_u16, read_u32};
+use crate::error::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const XMP_JPEG_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+const PNG_CHUNK_ITXT: [u8; 4] = *b"iTXt";
+
+const EXIF_TAG_ORIENTATION: u16 = 0x0112;
+const EXIF_TAG_EXIF_IFD: u16 = 0x8769;
+
+const TIFF_TAG_XMP: u16 = 0x02BC;
+const TIFF_TAG_ICC: u16 = 0x8773;
+const TIFF_TYPE_BYTE: u16 = 1;
+const TIFF_TYPE_UNDEFINED: u16 = 7;
+
+/// Tags of the first IFD that EXIF data shares with TIFF files, describing
+/// the image rather than the layout of its pixels
+const TIFF_EXIF_TAGS: [u16; 11] = [
+    0x010D, // DocumentName
+    0x010E, // ImageDescription
+    0x010F, // Make
+    0x0110, // Model
+    0x0112, // Orientation
+    0x011D, // PageName
+    0x0131, // Software
+    0x0132, // DateTime
+    0x013B, // Artist
+    0x013C, // HostComputer
+    0x8298, // Copyright
+];
+
+const WEBP_FLAG_ICC: u8 = 0x20;
+const WEBP_FLAG_ALPHA: u8 = 0x10;
+const WEBP_FLAG_EXIF: u8 = 0x08;
+const WEBP_FLAG_XMP: u8 = 0x04;
+const WEBP_FLAG_ANIMATION: u8 = 0x02;
+
+/// Metadata stored alongside the pixel data of an image
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// Raw EXIF data, without the `Exif\0\0` header
+    pub exif: Option<Vec<u8>>,
+    /// Embedded ICC color profile
+    pub icc_profile: Option<Vec<u8>>,
+    /// XMP packet as UTF-8 XML
+    pub xmp: Option<Vec<u8>>,
+}
+
+/// Whether metadata can be written back into images of this format.
+/// Saving metadata to any other format fails, it has to be stripped first.
+pub fn supports_metadata(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Jpeg
+            | ImageFormat::Png
+            | ImageFormat::WebP
+            | ImageFormat::Tiff
+            | ImageFormat::Avif
+    )
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc_profile.is_none() && self.xmp.is_none()
+    }
+
+    /// Reads metadata from the container of an encoded JPEG, PNG, WebP, TIFF or AVIF image.
+    /// Returns `None` for any other format.
+    pub fn from_bytes(bytes: Bytes) -> Option<Self> {
+        if byte_order(&bytes).is_some() {
+            return Self::from_tiff(&bytes);
+        }
+        if heif::is_heif(&bytes) {
+            return heif::read_metadata(&bytes);
+        }
+
+        let image = DynImage::from_bytes(bytes).ok()??;
+
+        let xmp = match &image {
+            DynImage::Jpeg(jpeg) => jpeg
+                .segments_by_marker(markers::APP1)
+                .find_map(|segment| segment.contents().strip_prefix(XMP_JPEG_PREFIX))
+                .map(<[u8]>::to_vec),
+            DynImage::Png(png) => png
+                .chunks_by_type(PNG_CHUNK_ITXT)
+                .find_map(|chunk| png_xmp(chunk.contents())),
+            DynImage::WebP(webp) => webp
+                .chunk_by_id(CHUNK_XMP)
+                .and_then(|chunk| chunk.content().data())
+                .map(|data| data.to_vec()),
+        };
+
+        let exif = match &image {
+            // WebP stores raw EXIF data, some writers still add the JPEG header
+            DynImage::WebP(webp) => webp
+                .chunk_by_id(CHUNK_EXIF)
+                .and_then(|chunk| chunk.content().data())
+                .map(|data| data.strip_prefix(EXIF_PREFIX).unwrap_or(data).to_vec()),
+            _ => image.exif().map(|exif| exif.to_vec()),
+        };
+
+        Some(Self {
+            exif,
+            icc_profile: image.icc_profile().map(|icc| icc.to_vec()),
+            xmp,
+        })
+    }
+
+    /// Reads the metadata stored in the tags of the first image of a TIFF file
+    fn from_tiff(bytes: &[u8]) -> Option<Self> {
+        let directory = Directory::first(bytes)?;
+        let value = |tag| directory.entry(tag).map(|entry| entry.value.clone());
+
+        // The EXIF tags of the first IFD sit next to the image ones, the EXIF IFD is linked the same way
+        let exif = Directory {
+            entries: (directory.entries.iter())
+                .filter(|entry| TIFF_EXIF_TAGS.contains(&entry.tag))
+                .cloned()
+                .collect(),
+            children: directory.children.clone(),
+        };
+
+        Some(Self {
+            exif: (!exif.is_empty()).then(|| exif.to_exif()),
+            icc_profile: value(TIFF_TAG_ICC),
+            xmp: value(TIFF_TAG_XMP),
+        })
+    }
+
+    /// Reads the metadata exposed by an image decoder
+    pub fn from_decoder(decoder: &mut impl ImageDecoder) -> Self {
+        Self {
+            exif: decoder.exif_metadata().ok().flatten(),
+            icc_profile: decoder.icc_profile().ok().flatten(),
+            xmp: None,
+        }
+    }
+
+    /// Orientation stored in the EXIF data, if any
+    pub fn orientation(&self) -> Option<Orientation> {
+        let exif = self.exif.as_deref()?;
+        let offset = orientation_offset(exif)?;
+        let value = read_u16(exif, offset, exif.starts_with(b"II"))?;
+        Orientation::from_exif(value as u8)
+    }
+
+    /// Value of an EXIF tag as text, looked up in the first IFD then in the EXIF IFD.
+    ///
+    /// Strings are trimmed and numbers written in decimal, rationals with a numerator
+    /// of one are kept as a fraction such as `1/250`.
+    pub fn exif_field(&self, tag: u16) -> Option<String> {
+        let exif = self.exif.as_deref()?;
+        let little_endian = match exif.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+
+        let first = read_u32(exif, 4, little_endian)? as usize;
+        let entry = ifd_entry(exif, first, tag, little_endian).or_else(|| {
+            let pointer = ifd_entry(exif, first, EXIF_TAG_EXIF_IFD, little_endian)?;
+            let exif_ifd = read_u32(exif, pointer + 8, little_endian)? as usize;
+            ifd_entry(exif, exif_ifd, tag, little_endian)
+        })?;
+
+        entry_text(exif, entry, little_endian)
+    }
+
+    /// Marks the pixels as stored upright, once the orientation has been applied to them
+    pub fn reset_orientation(&mut self) {
+        let Some(exif) = self.exif.as_mut() else {
+            return;
+        };
+        if let Some(offset) = orientation_offset(exif) {
+            let value = if exif.starts_with(b"II") {
+                1u16.to_le_bytes()
+            } else {
+                1u16.to_be_bytes()
+            };
+            exif[offset..offset + 2].copy_from_slice(&value);
+        }
+    }
+
+    /// Writes the metadata into an encoded image.
+    ///
+    /// Any metadata already present in the image is replaced.
+    /// Fails for formats that cannot hold metadata, unless there is none to write.
+    pub fn embed(&self, encoded: Vec<u8>, format: ImageFormat) -> Result<Vec<u8>> {
+        if self.is_empty() {
+            return Ok(encoded);
+        }
+        if !supports_metadata(format) {
+            return Err(metadata_error(
+                format,
+                &format!("{format:?} images cannot hold EXIF, ICC or XMP metadata"),
+            ));
+        }
+        match format {
+            ImageFormat::Tiff => return self.embed_tiff(encoded),
+            ImageFormat::Avif => return self.embed_avif(&encoded),
+            _ => (),
+        }
+
+        let bytes = Bytes::from(encoded);
+
+        let image = match format {
+            ImageFormat::Jpeg => {
+                let mut jpeg = Jpeg::from_bytes(bytes).map_err(|e| container_error(format, e))?;
+                self.embed_jpeg(&mut jpeg);
+                DynImage::Jpeg(jpeg)
+            }
+            ImageFormat::Png => {
+                let mut png = Png::from_bytes(bytes).map_err(|e| container_error(format, e))?;
+                self.embed_png(&mut png);
+                DynImage::Png(png)
+            }
+            _ => {
+                let mut webp = WebP::from_bytes(bytes).map_err(|e| container_error(format, e))?;
+                self.embed_webp(&mut webp)?;
+                DynImage::WebP(webp)
+            }
+        };
+
+        Ok(image.encoder().bytes().to_vec())
+    }
+
+    fn embed_jpeg(&self, jpeg: &mut Jpeg) {
+        jpeg.set_icc_profile(self.icc_profile.clone().map(Bytes::from));
+        jpeg.set_exif(self.exif.clone().map(Bytes::from));
+
+        jpeg.segments_mut().retain(|segment| {
+            segment.marker() != markers::APP1 || !segment.contents().starts_with(XMP_JPEG_PREFIX)
+        });
+
+        if let Some(xmp) = &self.xmp {
+            let contents = [XMP_JPEG_PREFIX, xmp].concat();
+            // Keep the JFIF header as the first segment
+            let position = match jpeg.segments().first() {
+                Some(segment) if segment.marker() == markers::APP0 => 1,
+                _ => 0,
+            };
+            jpeg.segments_mut().insert(
+                position,
+                JpegSegment::new_with_contents(markers::APP1, Bytes::from(contents)),
+            );
+        }
+    }
+
+    fn embed_png(&self, png: &mut Png) {
+        png.set_icc_profile(self.icc_profile.clone().map(Bytes::from));
+        png.set_exif(self.exif.clone().map(Bytes::from));
+
+        png.chunks_mut()
+            .retain(|chunk| chunk.kind() != PNG_CHUNK_ITXT || png_xmp(chunk.contents()).is_none());
+
+        if let Some(xmp) = &self.xmp {
+            // Uncompressed, with empty language and translated keyword
+            let contents = [XMP_PNG_KEYWORD, &[0, 0, 0, 0], xmp].concat();
+            let position = png.chunks().len() - 1;
+            png.chunks_mut().insert(
+                position,
+                PngChunk::new(PNG_CHUNK_ITXT, Bytes::from(contents)),
+            );
+        }
+    }
+
+    /// Rewrites the first IFD of a TIFF file with the metadata tags added.
+    ///
+    /// The new IFD is appended and the header pointed to it, the pixel data stays in place.
+    fn embed_tiff(&self, mut encoded: Vec<u8>) -> Result<Vec<u8>> {
+        let little_endian = byte_order(&encoded);
+        let directory = Directory::first(&encoded);
+        let (Some(little_endian), Some(mut directory)) = (little_endian, directory) else {
+            return Err(metadata_error(
+                ImageFormat::Tiff,
+                "could not read the TIFF directory",
+            ));
+        };
+
+        directory.entries.retain(|entry| {
+            !TIFF_EXIF_TAGS.contains(&entry.tag)
+                && ![TIFF_TAG_ICC, TIFF_TAG_XMP].contains(&entry.tag)
+        });
+        directory.children.clear();
+
+        if let Some(exif) = self.exif.as_deref().and_then(Directory::first) {
+            directory.entries.extend(
+                (exif.entries.into_iter()).filter(|entry| TIFF_EXIF_TAGS.contains(&entry.tag)),
+            );
+            directory.children = exif.children;
+        }
+        if let Some(icc) = &self.icc_profile {
+            directory
+                .entries
+                .push(Entry::bytes(TIFF_TAG_ICC, TIFF_TYPE_UNDEFINED, icc));
+        }
+        if let Some(xmp) = &self.xmp {
+            directory
+                .entries
+                .push(Entry::bytes(TIFF_TAG_XMP, TIFF_TYPE_BYTE, xmp));
+        }
+
+        let offset = directory.write(&mut encoded, little_endian);
+        let offset = match little_endian {
+            true => offset.to_le_bytes(),
+            false => offset.to_be_bytes(),
+        };
+        encoded[4..8].copy_from_slice(&offset);
+
+        Ok(encoded)
+    }
+
+    /// Adds EXIF and XMP items describing the primary image, and its ICC profile
+    /// as a color property
+    fn embed_avif(&self, encoded: &[u8]) -> Result<Vec<u8>> {
+        heif::write_metadata(encoded, self)
+            .ok_or_else(|| metadata_error(ImageFormat::Avif, "could not read the AVIF boxes"))
+    }
+
+    fn embed_webp(&self, webp: &mut WebP) -> Result<()> {
+        // img_parts reads the canvas size of an extended header at the wrong offset
+        let canvas = webp
+            .chunk_by_id(CHUNK_VP8X)
+            .and_then(|chunk| chunk.content().data())
+            .and_then(|data| data.get(4..10))
+            .map(|size| {
+                let read = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) + 1;
+                (read(&size[..3]), read(&size[3..]))
+            });
+        let (width, height) = canvas
+            .or_else(|| webp.dimensions())
+            .ok_or_else(|| metadata_error(ImageFormat::WebP, "could not read WebP dimensions"))?;
+
+        // Frames of animations are not looked into, they are assumed to have alpha
+        let animated = webp.has_chunk(CHUNK_ANIM);
+        let has_alpha = animated
+            || webp.has_chunk(CHUNK_ALPH)
+            || webp
+                .chunk_by_id(CHUNK_VP8L)
+                .and_then(|chunk| chunk.content().data())
+                // The alpha hint is bit 28 of the VP8L header
+                .is_some_and(|data| data.len() > 4 && data[4] & 0x10 != 0);
+
+        for id in [CHUNK_VP8X, CHUNK_ICCP, CHUNK_EXIF, CHUNK_XMP] {
+            webp.remove_chunks_by_id(id);
+        }
+
+        let mut flags = 0;
+        if has_alpha {
+            flags |= WEBP_FLAG_ALPHA;
+        }
+        if animated {
+            flags |= WEBP_FLAG_ANIMATION;
+        }
+
+        let chunks = webp.chunks_mut();
+
+        if let Some(icc) = &self.icc_profile {
+            flags |= WEBP_FLAG_ICC;
+            chunks.insert(0, data_chunk(CHUNK_ICCP, icc));
+        }
+        if let Some(exif) = &self.exif {
+            flags |= WEBP_FLAG_EXIF;
+            chunks.push(data_chunk(CHUNK_EXIF, exif));
+        }
+        if let Some(xmp) = &self.xmp {
+            flags |= WEBP_FLAG_XMP;
+            chunks.push(data_chunk(CHUNK_XMP, xmp));
+        }
+
+        let mut vp8x = vec![flags, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunks.insert(0, data_chunk(CHUNK_VP8X, &vp8x));
+
+        Ok(())
+    }
+}
+
+/// Reads the EXIF data of a JPEG, PNG, WebP or AVIF image, seeking past the other
+/// segments, chunks and boxes. JPEG images are only read up to their image data.
+/// Returns `None` for other formats.
+pub(crate) fn read_exif(
+    reader: &mut BufReader<File>,
+    format: ImageFormat,
+) -> io::Result<Option<Vec<u8>>> {
+    match format {
+        ImageFormat::Jpeg => jpeg_exif(reader),
+        ImageFormat::Png => png_exif(reader),
+        ImageFormat::WebP => webp_exif(reader),
+        ImageFormat::Avif => heif::read_exif(reader),
+        _ => Ok(None),
+    }
+}
+
+fn jpeg_exif(reader: &mut BufReader<File>) -> io::Result<Option<Vec<u8>>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid JPEG marker");
+
+    let mut start = [0; 2];
+    reader.read_exact(&mut start)?;
+    if start != [0xFF, markers::SOI] {
+        return Err(invalid());
+    }
+
+    loop {
+        let mut marker = [0; 2];
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Err(invalid());
+        }
+
+        match marker[1] {
+            // Fill bytes before a marker
+            0xFF => reader.seek_relative(-1)?,
+            // Markers without contents
+            0x01 | markers::RST0..=markers::RST7 => (),
+            // EXIF data comes before the image data
+            markers::SOS | markers::EOI => return Ok(None),
+            marker => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                let length = u16::from_be_bytes(length)
+                    .checked_sub(2)
+                    .ok_or_else(invalid)?;
+
+                if marker != markers::APP1 {
+                    reader.seek_relative(length as i64)?;
+                    continue;
+                }
+                let mut contents = vec![0; length as usize];
+                reader.read_exact(&mut contents)?;
+                if let Some(exif) = contents.strip_prefix(EXIF_PREFIX) {
+                    return Ok(Some(exif.to_vec()));
+                }
+            }
+        }
+    }
+}
+
+fn png_exif(reader: &mut BufReader<File>) -> io::Result<Option<Vec<u8>>> {
+    // Signature
+    reader.seek_relative(8)?;
+
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+
+        match &header[4..] {
+            b"eXIf" => {
+                let mut exif = vec![0; length as usize];
+                reader.read_exact(&mut exif)?;
+                return Ok(Some(exif));
+            }
+            b"IEND" => return Ok(None),
+            // Chunk data and CRC
+            _ => reader.seek_relative(length as i64 + 4)?,
+        }
+    }
+}
+
+fn webp_exif(reader: &mut BufReader<File>) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WEBP" {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "missing WebP RIFF header",
+        ));
+    }
+
+    loop {
+        let mut chunk = [0; 8];
+        match reader.read_exact(&mut chunk) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+        if chunk[..4] == CHUNK_EXIF {
+            let mut exif = vec![0; size as usize];
+            reader.read_exact(&mut exif)?;
+            // Some writers add the JPEG header
+            return Ok(Some(
+                exif.strip_prefix(EXIF_PREFIX).unwrap_or(&exif).to_vec(),
+            ));
+        }
+        // Chunks are padded to an even size
+        reader.seek_relative(size as i64 + (size % 2) as i64)?;
+    }
+}
+
+/// Position of the Orientation value in the first IFD of raw EXIF data
+fn orientation_offset(exif: &[u8]) -> Option<usize> {
+    let little_endian = match exif.get(..4)? {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+
+    let ifd = read_u32(exif, 4, little_endian)? as usize;
+
+    ifd_entry(exif, ifd, EXIF_TAG_ORIENTATION, little_endian)
+        // Tag, type and count come before the value
+        .map(|entry| entry + 8)
+        .filter(|&offset| offset + 2 <= exif.len())
+}
+
+/// Position of the entry of a tag in the IFD starting at `ifd`
+fn ifd_entry(exif: &[u8], ifd: usize, tag: u16, little_endian: bool) -> Option<usize> {
+    let entries = read_u16(exif, ifd, little_endian)? as usize;
+    (0..entries)
+        .map(|entry| ifd + 2 + entry * 12)
+        .find(|&entry| read_u16(exif, entry, little_endian) == Some(tag))
+}
+
+/// Reads the value of an IFD entry as text
+fn entry_text(exif: &[u8], entry: usize, little_endian: bool) -> Option<String> {
+    let kind = read_u16(exif, entry + 2, little_endian)?;
+    let count = read_u32(exif, entry + 4, little_endian)? as usize;
+
+    let size = match kind {
+        // ASCII and undefined bytes
+        2 | 7 => 1,
+        3 => 2,
+        4 | 9 => 4,
+        5 | 10 => 8,
+        _ => return None,
+    };
+    // Values of up to four bytes are stored in the entry itself
+    let offset = match size * count {
+        0..=4 => entry + 8,
+        _ => read_u32(exif, entry + 8, little_endian)? as usize,
+    };
+
+    let text = match kind {
+        2 | 7 => {
+            let bytes = exif.get(offset..offset + count)?;
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+        }
+        3 => read_u16(exif, offset, little_endian)?.to_string(),
+        4 => read_u32(exif, offset, little_endian)?.to_string(),
+        9 => (read_u32(exif, offset, little_endian)? as i32).to_string(),
+        _ => {
+            let numerator = read_u32(exif, offset, little_endian)?;
+            let denominator = read_u32(exif, offset + 4, little_endian)?;
+            let (numerator, denominator) = match kind {
+                10 => (numerator as i32 as i64, denominator as i32 as i64),
+                _ => (numerator as i64, denominator as i64),
+            };
+            match denominator {
+                0 => return None,
+                _ if numerator % denominator == 0 => (numerator / denominator).to_string(),
+                // Exposure times read better as a fraction
+                _ if numerator == 1 => format!("1/{denominator}"),
+                _ => format!("{}", numerator as f64 / denominator as f64),
+            }
+        }
+    };
+
+    (!text.is_empty()).then_some(text)
+}
+
+/// Extracts an uncompressed XMP packet from the contents of a PNG iTXt chunk
+fn png_xmp(contents: &[u8]) -> Option<Vec<u8>> {
+    let rest = contents.strip_prefix(XMP_PNG_KEYWORD)?;
+    let (&compressed, rest) = rest.split_first()?;
+    if compressed != 0 {
+        return None;
+    }
+
+    // Skip the compression method, language tag and translated keyword
+    let rest = rest.get(1..)?;
+    let language_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[language_end + 1..];
+    let translated_end = rest.iter().position(|&b| b == 0)?;
+
+    Some(rest[translated_end + 1..].to_vec())
+}
+
+fn data_chunk(id: [u8; 4], data: &[u8]) -> RiffChunk {
+    RiffChunk::new(id, RiffContent::Data(Bytes::copy_from_slice(data)))
+}
+
+fn container_error(format: ImageFormat, error: img_parts::Error) -> Error {
+    metadata_error(format, &format!("could not write metadata: {error}"))
+}
+
+fn metadata_error(format: ImageFormat, message: &str) -> Error {
+    Error::encode(
+        None,
+        ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(format),
+            message.to_string(),
+        )),
+    )
+}
@@ -0,0 +1,160 @@
+use super::font;
+use super::transform::Background;
+use crate::error::{Error, Result};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Layout of a contact sheet
+#[derive(Debug, Clone, Copy)]
+pub struct MontageOptions {
+    /// Number of images per row, close to a square grid when `None`
+    pub columns: Option<u32>,
+    /// Width of the box every image is fitted in
+    pub cell_width: u32,
+    /// Height of the box every image is fitted in, not counting the caption
+    pub cell_height: u32,
+    /// Gap between cells and around the sheet
+    pub spacing: u32,
+    pub background: Background,
+    /// Draw the caption of every tile under its image
+    pub captions: bool,
+    pub filter: FilterType,
+}
+
+impl Default for MontageOptions {
+    fn default() -> Self {
+        Self {
+            columns: None,
+            cell_width: 256,
+            cell_height: 256,
+            spacing: 8,
+            background: Background([1.0; 4]),
+            captions: false,
+            filter: FilterType::Triangle,
+        }
+    }
+}
+
+impl MontageOptions {
+    /// Scale of the caption font, growing with the cell width
+    fn caption_scale(&self) -> u32 {
+        (self.cell_width / 256).clamp(1, 4)
+    }
+
+    /// Height of the caption area under every image, zero without captions
+    fn caption_height(&self) -> u32 {
+        match self.captions {
+            true => font::line_height(self.caption_scale()) + self.caption_scale() * 4,
+            false => 0,
+        }
+    }
+
+    fn columns_for(&self, count: u32) -> u32 {
+        self.columns
+            .unwrap_or_else(|| (count as f64).sqrt().ceil() as u32)
+            .clamp(1, count.max(1))
+    }
+
+    /// Dimensions of the sheet holding `count` images
+    pub fn sheet_size(&self, count: u32) -> Result<(u32, u32)> {
+        if count == 0 {
+            return Err(Error::operation(None, "a montage needs at least one image"));
+        }
+        if self.cell_width == 0 || self.cell_height == 0 {
+            return Err(Error::operation(None, "montage cells cannot be empty"));
+        }
+
+        let columns = self.columns_for(count);
+        let rows = count.div_ceil(columns);
+        let cell_height = self.cell_height + self.caption_height();
+
+        let side = |cells: u32, size: u32| {
+            (cells as u64 * (size as u64 + self.spacing as u64) + self.spacing as u64)
+                .try_into()
+                .ok()
+        };
+
+        match (side(columns, self.cell_width), side(rows, cell_height)) {
+            (Some(width), Some(height)) => Ok((width, height)),
+            _ => Err(Error::operation(
+                None,
+                &format!("a montage of {count} images is too large"),
+            )),
+        }
+    }
+}
+
+/// An image shrunk to fit a montage cell, with its caption
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub image: RgbaImage,
+    pub caption: Option<String>,
+}
+
+impl Tile {
+    /// Fits the image inside a cell as soon as it is decoded,
+    /// so full size images do not have to be kept until the sheet is drawn
+    pub fn new(image: &DynamicImage, caption: Option<String>, options: &MontageOptions) -> Self {
+        let fits = image.width() <= options.cell_width && image.height() <= options.cell_height;
+        let image = match fits {
+            true => image.to_rgba8(),
+            false => image
+                .resize(options.cell_width, options.cell_height, options.filter)
+                .to_rgba8(),
+        };
+        Self { image, caption }
+    }
+}
+
+/// Lays tiles out in a grid, left to right then top to bottom,
+/// each image centered in its cell over the background.
+///
+/// The sheet is 8-bit RGBA, images at other depths are converted.
+pub fn montage(tiles: &[Tile], options: &MontageOptions) -> Result<DynamicImage> {
+    let (width, height) = options.sheet_size(tiles.len() as u32)?;
+
+    let Background(background) = options.background;
+    let background = Rgba(background.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8));
+    let mut sheet = RgbaImage::from_pixel(width, height, background);
+
+    // Dark captions on light backgrounds, light captions on dark ones
+    let luma = 0.2126 * background[0] as f32
+        + 0.7152 * background[1] as f32
+        + 0.0722 * background[2] as f32;
+    let text_color = match luma > 127.0 || background[3] < 128 {
+        true => Rgba([0, 0, 0, 255]),
+        false => Rgba([255, 255, 255, 255]),
+    };
+
+    let columns = options.columns_for(tiles.len() as u32);
+    let scale = options.caption_scale();
+    let cell_height = options.cell_height + options.caption_height();
+
+    for (index, tile) in tiles.iter().enumerate() {
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let left = options.spacing + column * (options.cell_width + options.spacing);
+        let top = options.spacing + row * (cell_height + options.spacing);
+
+        let x = left + (options.cell_width.saturating_sub(tile.image.width())) / 2;
+        let y = top + (options.cell_height.saturating_sub(tile.image.height())) / 2;
+        imageops::overlay(&mut sheet, &tile.image, x as i64, y as i64);
+
+        if let (true, Some(caption)) = (options.captions, &tile.caption) {
+            let caption = fit_caption(caption, font::chars_fitting(options.cell_width, scale));
+            let text_x = left + (options.cell_width - font::text_width(&caption, scale)) / 2;
+            let text_y = top + options.cell_height + scale * 2;
+            font::draw_text(&mut sheet, text_x, text_y, &caption, scale, text_color);
+        }
+    }
+
+    Ok(DynamicImage::ImageRgba8(sheet))
+}
+
+/// Shortens a caption to at most `max` characters, marking the cut with `..`
+fn fit_caption(caption: &str, max: usize) -> String {
+    match caption.chars().count() <= max {
+        true => caption.to_string(),
+        false if max > 2 => caption.chars().take(max - 2).chain("..".chars()).collect(),
+        false => caption.chars().take(max).collect(),
+    }
+}
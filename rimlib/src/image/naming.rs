@@ -0,0 +1,460 @@
+use super::metadata::Metadata;
+use crate::error::{Error, Result};
+use image::ImageFormat;
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// EXIF tags that can be used as `{exif:Name}`, names are matched ignoring case
+const EXIF_FIELDS: &[(&str, u16)] = &[
+    ("Make", 0x010f),
+    ("Model", 0x0110),
+    ("Software", 0x0131),
+    ("DateTime", 0x0132),
+    ("Artist", 0x013b),
+    ("Copyright", 0x8298),
+    ("ExposureTime", 0x829a),
+    ("FNumber", 0x829d),
+    ("ISO", 0x8827),
+    ("DateTimeOriginal", 0x9003),
+    ("FocalLength", 0x920a),
+    ("LensModel", 0xa434),
+];
+
+const EXIF_TAG_DATE_TIME: u16 = 0x0132;
+const EXIF_TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Characters replaced in the values of placeholders so they stay in the file name
+const RESERVED: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Stem,
+    Ext,
+    Index,
+    Width,
+    Height,
+    Format,
+    Parent,
+    Date,
+    Exif(&'static str, u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// A placeholder with what follows the `:`, if anything
+    Field(Field, Option<String>),
+}
+
+/// Template output file names are built from, without the extension.
+///
+/// Placeholders in braces are replaced for every image:
+/// - `{stem}`, `{ext}`: name and extension of the input file
+/// - `{parent}`: name of the directory holding the input file
+/// - `{index}`: position of the input in natural order from 1, `{index:04}` pads it with zeros
+/// - `{width}`, `{height}`: dimensions of the output image
+/// - `{format}`: extension of the output format
+/// - `{date}`: date the photo was taken, or the file last modified, `{date:%Y%m%d}`
+///   takes `%Y`, `%m`, `%d`, `%H`, `%M` and `%S`
+/// - `{exif:Model}`: an EXIF field such as `Make`, `Model`, `LensModel`, `ISO`,
+///   `FNumber`, `ExposureTime`, `FocalLength` or `DateTimeOriginal`
+///
+/// `{{` and `}}` write a brace. A template without placeholders is a prefix
+/// followed by the index, such as `name_1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+/// Values the placeholders of a template are replaced with for one image
+#[derive(Debug, Clone)]
+pub struct NameFields<'a> {
+    /// Input image
+    pub path: &'a Path,
+    /// Position of the input in natural order, from 1
+    pub index: usize,
+    /// Dimensions of the output image
+    pub width: u32,
+    pub height: u32,
+    /// Output format
+    pub format: ImageFormat,
+    /// Metadata of the input, only read for templates that use it
+    pub metadata: Option<&'a Metadata>,
+}
+
+impl NameTemplate {
+    pub fn new(template: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            Error::operation(
+                None,
+                &format!("invalid name template {template:?}: {reason}"),
+            )
+        };
+
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(invalid("unclosed {")),
+                        }
+                    }
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec.to_string())),
+                        None => (placeholder.as_str(), None),
+                    };
+                    let field = field(name, spec.as_deref()).map_err(|reason| invalid(&reason))?;
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(match field {
+                        Field::Exif(..) => Part::Field(field, None),
+                        _ => Part::Field(field, spec),
+                    });
+                }
+                '}' => return Err(invalid("unmatched }")),
+                '/' | '\\' => return Err(invalid("names cannot contain path separators")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        if parts.is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+        if !parts.iter().any(|part| matches!(part, Part::Field(..))) {
+            parts.push(Part::Text("_".to_string()));
+            parts.push(Part::Field(Field::Index, None));
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+            parts,
+        })
+    }
+
+    /// Whether the metadata of the input is needed to render the template
+    pub fn uses_metadata(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(Field::Date | Field::Exif(..), _)))
+    }
+
+    /// File name of an output image, without the extension
+    pub fn render(&self, fields: &NameFields) -> Result<String> {
+        let mut name = String::new();
+
+        for part in &self.parts {
+            let (field, spec) = match part {
+                Part::Text(text) => {
+                    name.push_str(text);
+                    continue;
+                }
+                Part::Field(field, spec) => (field, spec.as_deref()),
+            };
+
+            let value = match field {
+                Field::Stem => lossy(fields.path.file_stem()),
+                Field::Ext => lossy(fields.path.extension()),
+                Field::Index => padded(fields.index as u64, spec),
+                Field::Width => padded(fields.width as u64, spec),
+                Field::Height => padded(fields.height as u64, spec),
+                Field::Format => fields.format.extensions_str()[0].to_string(),
+                Field::Parent => std::path::absolute(fields.path)
+                    .ok()
+                    .and_then(|path| path.parent().map(|parent| lossy(parent.file_name())))
+                    .unwrap_or_default(),
+                Field::Date => date(fields)?.format(spec.unwrap_or(DEFAULT_DATE_FORMAT)),
+                Field::Exif(name, tag) => fields
+                    .metadata
+                    .and_then(|metadata| metadata.exif_field(*tag))
+                    .ok_or_else(|| {
+                        Error::operation(
+                            Some(fields.path),
+                            &format!("no {name} in the EXIF data to name the output with"),
+                        )
+                    })?,
+            };
+
+            name.extend(
+                value
+                    .chars()
+                    .map(|c| match RESERVED.contains(&c) || c.is_control() {
+                        true => '_',
+                        false => c,
+                    }),
+            );
+        }
+
+        match name.trim().is_empty() {
+            true => Err(Error::operation(
+                Some(fields.path),
+                &format!("name template {:?} gives an empty name", self.template),
+            )),
+            false => Ok(name),
+        }
+    }
+}
+
+impl Default for NameTemplate {
+    /// Keeps the name of the input file
+    fn default() -> Self {
+        Self {
+            template: "{stem}".to_string(),
+            parts: vec![Part::Field(Field::Stem, None)],
+        }
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        NameTemplate::new(s)
+    }
+}
+
+impl Display for NameTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+/// Compares paths the way people sort numbered files, `img2` before `img10`.
+///
+/// Runs of digits compare by value and letters ignoring case, paths that
+/// still compare equal are ordered by their text so the order is always the same.
+pub fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+    let (mut left, mut right) = (a.chars().peekable(), b.chars().peekable());
+
+    loop {
+        let ordering = match (left.peek(), right.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(l), Some(r)) if l.is_ascii_digit() && r.is_ascii_digit() => {
+                let digits = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(digit);
+                    }
+                    digits
+                };
+                let (l, r) = (digits(&mut left), digits(&mut right));
+                let (l_value, r_value) = (l.trim_start_matches('0'), r.trim_start_matches('0'));
+                l_value
+                    .len()
+                    .cmp(&r_value.len())
+                    .then_with(|| l_value.cmp(r_value))
+                    .then_with(|| l.len().cmp(&r.len()))
+            }
+            (Some(l), Some(r)) => {
+                let ordering = l.to_lowercase().cmp(r.to_lowercase());
+                left.next();
+                right.next();
+                ordering
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    a.cmp(&b)
+}
+
+/// Numbers the paths in natural order from 1, returning the number of each path
+pub fn natural_indices(paths: &[PathBuf]) -> Vec<usize> {
+    let mut order = (0..paths.len()).collect::<Vec<usize>>();
+    order.sort_by(|&a, &b| natural_cmp(&paths[a], &paths[b]));
+
+    let mut indices = vec![0; paths.len()];
+    for (position, &path) in order.iter().enumerate() {
+        indices[path] = position + 1;
+    }
+    indices
+}
+
+fn field(name: &str, spec: Option<&str>) -> std::result::Result<Field, String> {
+    let field = match name.trim().to_lowercase().as_str() {
+        "stem" => Field::Stem,
+        "ext" => Field::Ext,
+        "index" => Field::Index,
+        "width" => Field::Width,
+        "height" => Field::Height,
+        "format" => Field::Format,
+        "parent" => Field::Parent,
+        "date" => Field::Date,
+        "exif" => {
+            let spec = spec.unwrap_or_default();
+            return EXIF_FIELDS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(spec.trim()))
+                .map(|&(name, tag)| Field::Exif(name, tag))
+                .ok_or_else(|| format!("unknown EXIF field {spec:?}"));
+        }
+        _ => return Err(format!("unknown placeholder {{{name}}}")),
+    };
+
+    match (field, spec) {
+        (_, None) => Ok(field),
+        (Field::Index | Field::Width | Field::Height, Some(spec))
+            if spec.parse::<usize>().is_ok() =>
+        {
+            Ok(field)
+        }
+        (Field::Date, Some(spec)) => {
+            let mut chars = spec.chars();
+            while let Some(c) = chars.next() {
+                if c == '%'
+                    && !matches!(chars.next(), Some('Y' | 'm' | 'd' | 'H' | 'M' | 'S' | '%'))
+                {
+                    return Err(format!("unknown date format {spec:?}"));
+                }
+            }
+            Ok(field)
+        }
+        (_, Some(spec)) => Err(format!("invalid format {spec:?} for {{{name}}}")),
+    }
+}
+
+/// The number with at least as many digits as the format, padded with zeros
+fn padded(value: u64, spec: Option<&str>) -> String {
+    let width = spec
+        .and_then(|spec| spec.parse::<usize>().ok())
+        .unwrap_or(0);
+    format!("{value:0width$}")
+}
+
+fn lossy(name: Option<&std::ffi::OsStr>) -> String {
+    name.map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Date and time, in UTC for file times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    /// Parses an EXIF date such as `2024:06:01 14:30:00`
+    fn from_exif(text: &str) -> Option<Self> {
+        let numbers = text
+            .split([':', ' ', '-', 'T'])
+            .map(|number| number.trim().parse::<u32>().ok())
+            .collect::<Option<Vec<u32>>>()?;
+
+        match numbers[..] {
+            [year, month @ 1..=12, day @ 1..=31, hour, minute, second, ..] => Some(Self {
+                year: year as i64,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+            }),
+            _ => None,
+        }
+    }
+
+    fn from_system(time: SystemTime) -> Option<Self> {
+        let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+        // Civil date from the number of days since 1970-01-01
+        let shifted = days + 719468;
+        let era = shifted.div_euclid(146097);
+        let day_of_era = shifted.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+
+        Some(Self {
+            year: year_of_era + era * 400 + (month <= 2) as i64,
+            month: month as u32,
+            day: day as u32,
+            hour: (time / 3600) as u32,
+            minute: (time / 60 % 60) as u32,
+            second: (time % 60) as u32,
+        })
+    }
+
+    fn format(&self, spec: &str) -> String {
+        let mut text = String::new();
+        let mut chars = spec.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '%' => match chars.next() {
+                    Some('Y') => text.push_str(&format!("{:04}", self.year)),
+                    Some('m') => text.push_str(&format!("{:02}", self.month)),
+                    Some('d') => text.push_str(&format!("{:02}", self.day)),
+                    Some('H') => text.push_str(&format!("{:02}", self.hour)),
+                    Some('M') => text.push_str(&format!("{:02}", self.minute)),
+                    Some('S') => text.push_str(&format!("{:02}", self.second)),
+                    _ => text.push('%'),
+                },
+                c => text.push(c),
+            }
+        }
+        text
+    }
+}
+
+/// Date the photo was taken according to its EXIF data, or the date the file was last modified
+fn date(fields: &NameFields) -> Result<DateTime> {
+    let taken = fields.metadata.and_then(|metadata| {
+        [EXIF_TAG_DATE_TIME_ORIGINAL, EXIF_TAG_DATE_TIME]
+            .iter()
+            .find_map(|&tag| DateTime::from_exif(&metadata.exif_field(tag)?))
+    });
+    if let Some(taken) = taken {
+        return Ok(taken);
+    }
+
+    let modified = fs::metadata(fields.path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| Error::io(Some(fields.path), e))?;
+
+    DateTime::from_system(modified)
+        .ok_or_else(|| Error::operation(Some(fields.path), "the modification date is before 1970"))
+}
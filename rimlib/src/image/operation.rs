@@ -0,0 +1,490 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgba32FImage};
+use std::sync::Arc;
+
+use super::adjust::Adjustment;
+use super::animation::Animation;
+use super::color::{BitDepth, ColorInfo, ColorSpace};
+use super::formats::convert_image;
+use super::geometry::{Geometry, Gravity, ResizeMode, ResizeSpec};
+use super::manipulator::crop_image;
+use super::overlay::{OverlayOptions, overlay_image};
+use super::quantize::{QuantizeOptions, quantize};
+use super::transform::{Background, FlipDirection, flip, normalize_angle, rotate, rotated_size};
+use super::transparency::{BackgroundOptions, Transparenize};
+use crate::error::Result;
+
+/// A single transformation applied to a decoded image
+///
+/// Operations must be `Send` and `Sync` so one instance can be shared
+/// across all workers when processing images in parallel.
+pub trait Operation: Send + Sync {
+    /// Applies the operation, returning the new image
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage>;
+
+    /// Short description of the operation, used in progress messages
+    fn describe(&self) -> String;
+
+    /// Dimensions of an image of the given size after the operation.
+    ///
+    /// Used to validate images from their header before decoding them,
+    /// fails if the operation cannot be applied to an image of that size.
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok((width, height))
+    }
+
+    /// Color layout of an image of the given layout after the operation, as it would
+    /// be saved. Used to show what a run would produce without decoding anything.
+    fn output_color(&self, color: ColorInfo) -> ColorInfo {
+        color
+    }
+}
+
+/// An ordered chain of operations run over one decoded image
+#[derive(Default)]
+pub struct Pipeline {
+    operations: Vec<Box<dyn Operation>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an operation to the end of the pipeline
+    pub fn then<O>(mut self, operation: O) -> Self
+    where
+        O: Operation + 'static,
+    {
+        self.operations.push(Box::new(operation));
+        self
+    }
+
+    /// Appends a boxed operation to the end of the pipeline
+    pub fn push(&mut self, operation: Box<dyn Operation>) {
+        self.operations.push(operation);
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Iterates over the operations in the order they are applied
+    pub fn operations(&self) -> impl Iterator<Item = &dyn Operation> {
+        self.operations.iter().map(|operation| operation.as_ref())
+    }
+
+    /// Checks every operation against an image of the given size,
+    /// returning the size of the final image
+    pub fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        self.operations
+            .iter()
+            .try_fold((width, height), |(width, height), operation| {
+                operation.output_size(width, height)
+            })
+    }
+
+    /// Color layout of the final image
+    pub fn output_color(&self, color: ColorInfo) -> ColorInfo {
+        self.operations
+            .iter()
+            .fold(color, |color, operation| operation.output_color(color))
+    }
+
+    /// Runs every operation in order, stopping at the first error
+    pub fn run(&self, image: DynamicImage) -> Result<DynamicImage> {
+        self.operations
+            .iter()
+            .try_fold(image, |image, operation| operation.apply(image))
+    }
+
+    /// Runs every operation on each frame of an animation
+    pub fn run_animation(&self, animation: Animation) -> Result<Animation> {
+        animation.map_frames(|frame| self.run(frame))
+    }
+}
+
+impl Operation for Pipeline {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        self.run(image)
+    }
+
+    fn describe(&self) -> String {
+        self.operations()
+            .map(|operation| operation.describe())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Pipeline::output_size(self, width, height)
+    }
+
+    fn output_color(&self, color: ColorInfo) -> ColorInfo {
+        Pipeline::output_color(self, color)
+    }
+}
+
+/// Resizes the image according to a resize geometry,
+/// computed from the dimensions of each image
+#[derive(Debug, Clone, Copy)]
+pub struct Resize {
+    pub spec: ResizeSpec,
+    pub filter: FilterType,
+}
+
+impl Resize {
+    /// Stretches the image to the given dimensions
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::with_spec(ResizeSpec::exact(width, height))
+    }
+
+    pub fn with_spec(spec: ResizeSpec) -> Self {
+        Self {
+            spec,
+            filter: FilterType::Nearest,
+        }
+    }
+
+    pub fn filter(mut self, filter: FilterType) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Fit the image within the dimensions instead of stretching it
+    pub fn preserve_aspect(mut self, preserve_aspect: bool) -> Self {
+        self.spec.mode = match preserve_aspect {
+            true => ResizeMode::Fit,
+            false => ResizeMode::Exact,
+        };
+        self
+    }
+}
+
+impl Operation for Resize {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        let (width, height) = self.spec.scaled_size(image.width(), image.height())?;
+        let (output_width, output_height) = self.spec.output_size(image.width(), image.height())?;
+
+        let image = match (width, height) == (image.width(), image.height()) {
+            true => image,
+            false => image.resize_exact(width, height, self.filter),
+        };
+
+        match (output_width, output_height) == (width, height) {
+            true => Ok(image),
+            false => Ok(image.crop_imm(
+                (width - output_width) / 2,
+                (height - output_height) / 2,
+                output_width,
+                output_height,
+            )),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("Resizing to {}", self.spec)
+    }
+
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        self.spec.output_size(width, height)
+    }
+}
+
+/// Crops the image to a region placed relative to a gravity anchor
+#[derive(Debug, Clone, Copy)]
+pub struct Crop {
+    pub geometry: Geometry,
+    pub gravity: Gravity,
+}
+
+impl Crop {
+    pub fn new(geometry: Geometry, gravity: Gravity) -> Self {
+        Self { geometry, gravity }
+    }
+}
+
+impl Operation for Crop {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        crop_image(image, &self.geometry, self.gravity)
+    }
+
+    fn describe(&self) -> String {
+        format!("Cropping to {}", self.geometry)
+    }
+
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let rect = self.geometry.rect(width, height, self.gravity)?;
+        Ok((rect.width, rect.height))
+    }
+}
+
+/// Rotates the image clockwise by an angle in degrees
+#[derive(Debug, Clone, Copy)]
+pub struct Rotate {
+    pub degrees: f32,
+    /// Fills the corners uncovered by angles that are not a multiple of 90 degrees
+    pub background: Background,
+}
+
+impl Rotate {
+    pub fn new(degrees: f32) -> Self {
+        Self {
+            degrees,
+            background: Background::default(),
+        }
+    }
+
+    pub fn background(mut self, background: Background) -> Self {
+        self.background = background;
+        self
+    }
+}
+
+impl Operation for Rotate {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        rotate(image, self.degrees, self.background)
+    }
+
+    fn describe(&self) -> String {
+        format!("Rotating by {} degrees", self.degrees)
+    }
+
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok(match normalize_angle(self.degrees)? {
+            0.0 | 180.0 => (width, height),
+            90.0 | 270.0 => (height, width),
+            degrees => rotated_size(width, height, degrees),
+        })
+    }
+
+    fn output_color(&self, color: ColorInfo) -> ColorInfo {
+        match normalize_angle(self.degrees) {
+            Ok(0.0 | 90.0 | 180.0 | 270.0) => color,
+            _ => color.with_alpha(color.has_alpha() || self.background.0[3] < 1.0),
+        }
+    }
+}
+
+/// Mirrors the image horizontally or vertically
+#[derive(Debug, Clone, Copy)]
+pub struct Flip(pub FlipDirection);
+
+impl Operation for Flip {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        Ok(flip(image, self.0))
+    }
+
+    fn describe(&self) -> String {
+        match self.0 {
+            FlipDirection::Horizontal => "Flipping horizontally".to_string(),
+            FlipDirection::Vertical => "Flipping vertically".to_string(),
+        }
+    }
+}
+
+/// Composites a second image, such as a logo or watermark, onto the image
+///
+/// The overlay is decoded once and shared, so cloning the operation
+/// or running it on many workers does not copy its pixels.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub overlay: Arc<Rgba32FImage>,
+    pub options: OverlayOptions,
+}
+
+impl Overlay {
+    pub fn new(overlay: DynamicImage, options: OverlayOptions) -> Self {
+        Self {
+            overlay: Arc::new(overlay.into_rgba32f()),
+            options,
+        }
+    }
+}
+
+impl Operation for Overlay {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        overlay_image(image, &self.overlay, &self.options)
+    }
+
+    fn describe(&self) -> String {
+        match self.options.tile {
+            true => "Tiling overlay".to_string(),
+            false => "Adding overlay".to_string(),
+        }
+    }
+
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        match self.options.tile {
+            true => self
+                .options
+                .overlay_size(self.overlay.dimensions(), width)?,
+            false => {
+                let rect = self
+                    .options
+                    .placement(self.overlay.dimensions(), (width, height))?;
+                (rect.width, rect.height)
+            }
+        };
+        Ok((width, height))
+    }
+}
+
+/// Applies tonal and spatial adjustments in order
+#[derive(Debug, Clone, Default)]
+pub struct Adjust {
+    pub adjustments: Vec<Adjustment>,
+}
+
+impl Adjust {
+    pub fn new(adjustments: Vec<Adjustment>) -> Self {
+        Self { adjustments }
+    }
+}
+
+impl Operation for Adjust {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        self.adjustments
+            .iter()
+            .try_fold(image, |image, adjustment| adjustment.apply(image))
+    }
+
+    fn describe(&self) -> String {
+        let adjustments = self
+            .adjustments
+            .iter()
+            .map(|adjustment| adjustment.to_string())
+            .collect::<Vec<String>>();
+        format!("Adjusting {}", adjustments.join(", "))
+    }
+
+    fn output_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        for adjustment in &self.adjustments {
+            adjustment.validate()?;
+        }
+        Ok((width, height))
+    }
+}
+
+/// Reduces the image to a palette, ready to be saved as indexed PNG or GIF
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quantize {
+    pub options: QuantizeOptions,
+}
+
+impl Quantize {
+    pub fn new(options: QuantizeOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl Operation for Quantize {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        quantize(&image, &self.options)?.to_image()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Reducing to {} colors with {}",
+            self.options.colors, self.options.quantizer
+        )
+    }
+
+    /// Saved as palette indices, at most as deep as the requested number of colors needs
+    fn output_color(&self, _color: ColorInfo) -> ColorInfo {
+        ColorInfo::new(
+            &ColorSpace::Indexed,
+            &BitDepth::for_palette(self.options.colors),
+        )
+    }
+}
+
+/// Converts the image to another color type
+#[derive(Debug, Clone, Copy)]
+pub struct Recolor(pub ColorInfo);
+
+impl Operation for Recolor {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        self.0.convert_image(image)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Recoloring to {} {}-bit",
+            self.0.color_space, self.0.bit_depth
+        )
+    }
+
+    fn output_color(&self, _color: ColorInfo) -> ColorInfo {
+        self.0
+    }
+}
+
+/// Removes the background from the image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transparentize {
+    pub options: BackgroundOptions,
+}
+
+impl Transparentize {
+    pub fn new(options: BackgroundOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl Operation for Transparentize {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        Ok(image.remove_background(&self.options))
+    }
+
+    fn describe(&self) -> String {
+        "Removing background".to_string()
+    }
+
+    fn output_color(&self, color: ColorInfo) -> ColorInfo {
+        let bit_depth = match color.bit_depth {
+            BitDepth::B16 | BitDepth::B32 => color.bit_depth,
+            _ => BitDepth::B8,
+        };
+        ColorInfo::new(&ColorSpace::RgbA, &bit_depth)
+    }
+}
+
+/// Converts the image in memory to another format
+#[derive(Debug, Clone, Default)]
+pub struct Convert {
+    pub format: Option<String>,
+}
+
+impl Convert {
+    pub fn new(format: Option<&str>) -> Self {
+        Self {
+            format: format.map(str::to_string),
+        }
+    }
+}
+
+impl Operation for Convert {
+    fn apply(&self, image: DynamicImage) -> Result<DynamicImage> {
+        convert_image(image, self.format.as_deref())
+    }
+
+    fn describe(&self) -> String {
+        match &self.format {
+            Some(format) => format!("Converting to {format}"),
+            None => "Converting".to_string(),
+        }
+    }
+
+    /// GIF is the only format the image is widened for, to RGBA
+    fn output_color(&self, color: ColorInfo) -> ColorInfo {
+        match self.format.as_deref().and_then(ImageFormat::from_extension) {
+            Some(ImageFormat::Gif) => ColorInfo::new(&ColorSpace::RgbA, &BitDepth::B8),
+            _ => color,
+        }
+    }
+}
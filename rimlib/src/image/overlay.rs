@@ -0,0 +1,172 @@
+use super::color::ColorInfo;
+use super::geometry::{Geometry, Gravity, Length, Rect, Size};
+use crate::error::{Error, Result};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba32FImage};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+
+/// Placement and blending of an overlay image
+#[derive(Debug, Clone, Copy)]
+pub struct OverlayOptions {
+    /// Corner or edge the overlay is placed against
+    pub gravity: Gravity,
+    /// Distance from the edges, or between tiles when tiling
+    pub margin: Length,
+    /// Opacity from 0.0 to 1.0, multiplied with the alpha of the overlay
+    pub opacity: f32,
+    /// Width of the overlay as a fraction of the target width,
+    /// `None` keeps the overlay at its own size
+    pub scale: Option<f32>,
+    /// Repeat the overlay over the whole target instead of placing it once
+    pub tile: bool,
+    pub filter: FilterType,
+}
+
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        Self {
+            gravity: Gravity::SouthEast,
+            margin: Length::Pixels(0),
+            opacity: 1.0,
+            scale: None,
+            tile: false,
+            filter: FilterType::Triangle,
+        }
+    }
+}
+
+impl OverlayOptions {
+    /// Size of the overlay once scaled for a target of the given width
+    pub fn overlay_size(&self, overlay: (u32, u32), target_width: u32) -> Result<(u32, u32)> {
+        let (width, height) = match self.scale {
+            None => overlay,
+            Some(scale) => {
+                let width = (target_width as f64 * scale as f64).round();
+                let height = width * overlay.1 as f64 / overlay.0.max(1) as f64;
+                (width as u32, height.round() as u32)
+            }
+        };
+
+        match (width, height) {
+            (0, _) | (_, 0) => Err(Error::operation(
+                None,
+                &format!("the overlay is scaled to an empty {width}x{height} image"),
+            )),
+            size => Ok(size),
+        }
+    }
+
+    /// Position of a single overlay on a target of the given size.
+    /// Fails when the overlay does not fit inside the margins.
+    pub fn placement(&self, overlay: (u32, u32), target: (u32, u32)) -> Result<Rect> {
+        let (width, height) = self.overlay_size(overlay, target.0)?;
+        let (margin_x, margin_y) = (self.margin.resolve(target.0), self.margin.resolve(target.1));
+
+        // The margin only pushes away from the edges the overlay is anchored to
+        let (x, y) = match self.gravity {
+            Gravity::Center => (0, 0),
+            Gravity::North | Gravity::South => (0, margin_y),
+            Gravity::West | Gravity::East => (margin_x, 0),
+            _ => (margin_x, margin_y),
+        };
+
+        let geometry = Geometry {
+            size: Size::Exact {
+                width: Length::Pixels(width),
+                height: Length::Pixels(height),
+            },
+            x: x as i64,
+            y: y as i64,
+        };
+
+        geometry
+            .rect(target.0, target.1, self.gravity)
+            .map_err(|_| {
+                Error::operation(
+                    None,
+                    &format!(
+                        "the {width}x{height} overlay does not fit inside the {}x{} image with its margin",
+                        target.0, target.1
+                    ),
+                )
+            })
+    }
+}
+
+/// Composites an overlay, such as a logo or watermark, onto the image.
+///
+/// Blending happens in 32-bit float so 16-bit and float images keep their precision,
+/// and the image keeps its color type.
+pub fn overlay_image(
+    image: DynamicImage,
+    overlay: &Rgba32FImage,
+    options: &OverlayOptions,
+) -> Result<DynamicImage> {
+    let target = (image.width(), image.height());
+    let (width, height) = options.overlay_size(overlay.dimensions(), target.0)?;
+
+    let scaled;
+    let overlay = match (width, height) == overlay.dimensions() {
+        true => overlay,
+        false => {
+            scaled = imageops::resize(overlay, width, height, options.filter);
+            &scaled
+        }
+    };
+
+    let positions = match options.tile {
+        true => {
+            let gap_x = options.margin.resolve(target.0) as usize;
+            let gap_y = options.margin.resolve(target.1) as usize;
+            let xs = (0..target.0).step_by(width as usize + gap_x);
+            let ys = (0..target.1).step_by(height as usize + gap_y);
+            ys.flat_map(|y| xs.clone().map(move |x| (x, y))).collect()
+        }
+        false => {
+            let rect = options.placement(overlay.dimensions(), target)?;
+            vec![(rect.x, rect.y)]
+        }
+    };
+
+    let color = ColorInfo::from_image(&image);
+    let mut canvas = image.into_rgba32f();
+    let row_len = (target.0 as usize * 4).max(1);
+    let opacity = options.opacity.clamp(0.0, 1.0);
+
+    let data: &mut [f32] = &mut canvas;
+    data.par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let y = y as u32;
+            for &(left, top) in positions
+                .iter()
+                .filter(|&&(_, top)| y >= top && y < top + height)
+            {
+                let columns = left..(left + width).min(target.0);
+                for x in columns {
+                    let source = overlay.get_pixel(x - left, y - top).0;
+                    let pixel = &mut row[x as usize * 4..x as usize * 4 + 4];
+                    blend(pixel, source, opacity);
+                }
+            }
+        });
+
+    // Opaque images stay opaque, so the original color type holds the result
+    color.convert_image(DynamicImage::ImageRgba32F(canvas))
+}
+
+/// Source-over blending of straight alpha pixels
+fn blend(pixel: &mut [f32], source: [f32; 4], opacity: f32) {
+    let alpha = source[3] * opacity;
+    if alpha <= 0.0 {
+        return;
+    }
+
+    let out_alpha = alpha + pixel[3] * (1.0 - alpha);
+    for channel in 0..3 {
+        pixel[channel] =
+            (source[channel] * alpha + pixel[channel] * pixel[3] * (1.0 - alpha)) / out_alpha;
+    }
+    pixel[3] = out_alpha;
+}
@@ -1,11 +1,12 @@
-use image::{ColorType, DynamicImage, ImageBuffer, Luma, LumaA, Rgb, Rgba};
+use image::{ColorType, DynamicImage, ImageBuffer, Luma, LumaA, Primitive, Rgb, Rgba};
 
 use super::color::ColorData;
+use crate::error::{Error, Result};
 
 pub enum ImageBufferData {
     Rgb8(ImageBuffer<Rgb<u8>, Vec<u8>>),
     Rgba8(ImageBuffer<Rgba<u8>, Vec<u8>>),
-    Rgb16(ImageBuffer<Rgba<u16>, Vec<u16>>),
+    Rgb16(ImageBuffer<Rgb<u16>, Vec<u16>>),
     Rgba16(ImageBuffer<Rgba<u16>, Vec<u16>>),
     Rgb32f(ImageBuffer<Rgb<f32>, Vec<f32>>),
     Rgba32f(ImageBuffer<Rgba<f32>, Vec<f32>>),
@@ -21,29 +22,69 @@ pub trait PixelConvert {
     /// Converts the image or buffer into one of the chosen type.
     /// Returns an instance of `ImageBufferData` with the buffer as an inner.
     /// Buffers can be obtained through matching or if-statements.
-    fn convert_color_to<C>(&self, color_info: C) -> ImageBufferData
+    /// Fails when the color type has no buffer representation.
+    fn convert_color_to<C>(&self, color_info: C) -> Result<ImageBufferData>
     where
         C: ColorData;
 }
 
 impl PixelConvert for DynamicImage {
-    fn convert_color_to<C>(&self, color_info: C) -> ImageBufferData
+    fn convert_color_to<C>(&self, color_info: C) -> Result<ImageBufferData>
     where
         C: ColorData,
     {
-        let cltype = color_info.color_type();
-        match cltype {
+        let cltype = color_info.color_type()?;
+        Ok(match cltype {
             ColorType::L8 => ImageBufferData::Luma8(self.to_luma8()),
             ColorType::La8 => ImageBufferData::LumaA8(self.to_luma_alpha8()),
             ColorType::L16 => ImageBufferData::Luma16(self.to_luma16()),
             ColorType::La16 => ImageBufferData::LumaA16(self.to_luma_alpha16()),
             ColorType::Rgb8 => ImageBufferData::Rgb8(self.to_rgb8()),
             ColorType::Rgba8 => ImageBufferData::Rgba8(self.to_rgba8()),
-            ColorType::Rgb16 => ImageBufferData::Rgb16(self.to_rgba16()),
+            ColorType::Rgb16 => ImageBufferData::Rgb16(self.to_rgb16()),
             ColorType::Rgba16 => ImageBufferData::Rgba16(self.to_rgba16()),
             ColorType::Rgb32F => ImageBufferData::Rgb32f(self.to_rgb32f()),
             ColorType::Rgba32F => ImageBufferData::Rgba32f(self.to_rgba32f()),
-            _ => unimplemented!(),
-        }
+            _ => {
+                return Err(Error::color(
+                    None,
+                    &format!("{cltype:?} images are not supported"),
+                ));
+            }
+        })
+    }
+}
+
+/// Subpixel types that can be scaled to and from 0.0 to 1.0
+pub(crate) trait Channel: Primitive + Send + Sync {
+    fn to_unit(self) -> f32;
+    /// Converts back from 0.0 to 1.0, saturating integer types outside that range
+    fn from_unit(value: f32) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_unit(self) -> f32 {
+        self as f32 / u8::MAX as f32
+    }
+    fn from_unit(value: f32) -> Self {
+        (value * u8::MAX as f32).round() as u8
+    }
+}
+
+impl Channel for u16 {
+    fn to_unit(self) -> f32 {
+        self as f32 / u16::MAX as f32
+    }
+    fn from_unit(value: f32) -> Self {
+        (value * u16::MAX as f32).round() as u16
+    }
+}
+
+impl Channel for f32 {
+    fn to_unit(self) -> f32 {
+        self
+    }
+    fn from_unit(value: f32) -> Self {
+        value
     }
 }
@@ -0,0 +1,482 @@
+use super::color::{BitDepth, ColorInfo, ColorSpace};
+use crate::error::{Error, Result};
+use color_quant::NeuQuant;
+use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Largest palette indexed formats can hold
+pub const MAX_COLORS: usize = 256;
+
+/// Passes refining the palette when quantizing with k-means
+const KMEANS_ITERATIONS: usize = 8;
+
+/// Bits kept per channel when grouping similar colors before k-means
+const KMEANS_BITS: u8 = 5;
+
+/// Sampling factor of NeuQuant, from 1 (slowest, best) to 30
+const NEUQUANT_SAMPLE: i32 = 10;
+
+/// 8x8 Bayer matrix, thresholds from 0 to 63
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Algorithm choosing the palette
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quantizer {
+    /// Splits the color space around the median of its widest channel
+    #[default]
+    MedianCut,
+    /// Median cut refined by moving each color to the mean of its cluster
+    KMeans,
+    /// Kohonen neural network, good on photos with smooth gradients
+    NeuQuant,
+}
+
+impl FromStr for Quantizer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "mediancut" | "median" => Ok(Quantizer::MedianCut),
+            "kmeans" => Ok(Quantizer::KMeans),
+            "neuquant" | "nq" => Ok(Quantizer::NeuQuant),
+            _ => Err(Error::operation(
+                None,
+                &format!("unknown quantizer {s:?}, must be median-cut, k-means or neuquant"),
+            )),
+        }
+    }
+}
+
+impl Display for Quantizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quantizer::MedianCut => write!(f, "median-cut"),
+            Quantizer::KMeans => write!(f, "k-means"),
+            Quantizer::NeuQuant => write!(f, "neuquant"),
+        }
+    }
+}
+
+/// How the error between a pixel and its palette color is spread
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Every pixel takes its nearest palette color, leaving flat bands
+    None,
+    /// Diffuses the error to the following pixels
+    #[default]
+    FloydSteinberg,
+    /// Offsets pixels with a Bayer matrix, giving a regular pattern that compresses well
+    Ordered,
+}
+
+impl FromStr for Dither {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "none" | "off" => Ok(Dither::None),
+            "floydsteinberg" | "fs" | "diffusion" => Ok(Dither::FloydSteinberg),
+            "ordered" | "bayer" => Ok(Dither::Ordered),
+            _ => Err(Error::operation(
+                None,
+                &format!("unknown dither {s:?}, must be none, floyd-steinberg or ordered"),
+            )),
+        }
+    }
+}
+
+/// Palette reduction settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizeOptions {
+    /// Palette size, from 2 to 256
+    pub colors: usize,
+    pub quantizer: Quantizer,
+    pub dither: Dither,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            colors: MAX_COLORS,
+            quantizer: Quantizer::default(),
+            dither: Dither::default(),
+        }
+    }
+}
+
+/// An 8-bit image stored as palette indices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA colors referenced by the indices
+    pub palette: Vec<[u8; 4]>,
+    /// One palette index per pixel, row by row
+    pub indices: Vec<u8>,
+}
+
+impl IndexedImage {
+    /// Builds the palette of an image that already has at most 256 colors,
+    /// without changing any pixel
+    pub fn from_image(image: &DynamicImage) -> Result<Self> {
+        let rgba = image.to_rgba8();
+        let mut lookup = HashMap::new();
+        let mut palette = Vec::new();
+        let mut indices = Vec::with_capacity(rgba.len() / 4);
+
+        for pixel in rgba.pixels() {
+            let index = *lookup.entry(pixel.0).or_insert_with(|| {
+                palette.push(pixel.0);
+                palette.len() - 1
+            });
+            if palette.len() > MAX_COLORS {
+                return Err(Error::color(
+                    None,
+                    &format!(
+                        "the image has more than {MAX_COLORS} colors, reduce them before saving it with a palette"
+                    ),
+                ));
+            }
+            indices.push(index as u8);
+        }
+
+        Ok(Self {
+            width: rgba.width(),
+            height: rgba.height(),
+            palette,
+            indices,
+        })
+    }
+
+    /// Color layout of the image as stored in an indexed file
+    pub fn color_info(&self) -> ColorInfo {
+        ColorInfo::new(
+            &ColorSpace::Indexed,
+            &BitDepth::for_palette(self.palette.len()),
+        )
+    }
+
+    /// Whether any palette color is not fully opaque
+    pub fn has_alpha(&self) -> bool {
+        self.palette.iter().any(|color| color[3] < u8::MAX)
+    }
+
+    /// Expands the indices back into pixels, RGBA when the palette has transparency.
+    ///
+    /// Fails when there is not one index per pixel or an index is outside the palette.
+    pub fn to_image(&self) -> Result<DynamicImage> {
+        if self.indices.len() as u64 != self.width as u64 * self.height as u64 {
+            return Err(Error::color(
+                None,
+                "the indexed image does not have one index per pixel",
+            ));
+        }
+        if self
+            .indices
+            .iter()
+            .any(|&index| index as usize >= self.palette.len())
+        {
+            return Err(Error::color(
+                None,
+                "the indexed image refers to a color outside its palette",
+            ));
+        }
+
+        let color = |x: u32, y: u32| {
+            self.palette[self.indices[y as usize * self.width as usize + x as usize] as usize]
+        };
+        Ok(match self.has_alpha() {
+            true => {
+                DynamicImage::ImageRgba8(RgbaImage::from_fn(self.width, self.height, |x, y| {
+                    Rgba(color(x, y))
+                }))
+            }
+            false => DynamicImage::ImageRgb8(RgbImage::from_fn(self.width, self.height, |x, y| {
+                let [red, green, blue, _] = color(x, y);
+                Rgb([red, green, blue])
+            })),
+        })
+    }
+}
+
+/// Reduces the image to a palette of at most `options.colors` colors.
+///
+/// Images with that many colors or fewer keep their exact colors.
+/// Pixels are reduced to 8 bits per channel, the depth of indexed formats.
+pub fn quantize(image: &DynamicImage, options: &QuantizeOptions) -> Result<IndexedImage> {
+    if !(2..=MAX_COLORS).contains(&options.colors) {
+        return Err(Error::operation(
+            None,
+            &format!("the palette size must be between 2 and {MAX_COLORS}"),
+        ));
+    }
+
+    let rgba = image.to_rgba8();
+    let mut histogram: HashMap<[u8; 4], u64> = HashMap::new();
+    for pixel in rgba.pixels() {
+        *histogram.entry(pixel.0).or_default() += 1;
+    }
+
+    if histogram.len() <= options.colors {
+        return IndexedImage::from_image(&DynamicImage::ImageRgba8(rgba));
+    }
+
+    // Sorted so the palette does not depend on the hash map order
+    let mut colors = histogram.into_iter().collect::<Vec<([u8; 4], u64)>>();
+    colors.sort_unstable();
+    let palette = match options.quantizer {
+        Quantizer::MedianCut => median_cut(&colors, options.colors),
+        Quantizer::KMeans => kmeans(&colors, median_cut(&colors, options.colors)),
+        Quantizer::NeuQuant => NeuQuant::new(NEUQUANT_SAMPLE, options.colors, rgba.as_raw())
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|color| [color[0], color[1], color[2], color[3]])
+            .collect(),
+    };
+
+    let indices = match options.dither {
+        Dither::None => map_nearest(&rgba, &palette),
+        Dither::FloydSteinberg => floyd_steinberg(&rgba, &palette),
+        Dither::Ordered => ordered(&rgba, &palette),
+    };
+
+    Ok(IndexedImage {
+        width: rgba.width(),
+        height: rgba.height(),
+        palette,
+        indices,
+    })
+}
+
+/// A group of colors in median cut, with the count of pixels of each color
+struct ColorBox<'a> {
+    colors: &'a mut [([u8; 4], u64)],
+}
+
+impl ColorBox<'_> {
+    /// Channel with the widest range and that range
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..4)
+            .map(|channel| {
+                let values = self.colors.iter().map(|(color, _)| color[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (channel, range)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap_or((0, 0))
+    }
+
+    /// Pixel weighted mean color of the box
+    fn mean(&self) -> [u8; 4] {
+        mean_color(self.colors.iter().map(|&(color, count)| (color, count)))
+    }
+}
+
+fn median_cut(colors: &[([u8; 4], u64)], size: usize) -> Vec<[u8; 4]> {
+    let mut colors = colors.to_vec();
+    let mut boxes = vec![ColorBox {
+        colors: &mut colors[..],
+    }];
+
+    while boxes.len() < size {
+        // Splits the box whose widest channel spans the most, weighted by its pixels
+        let Some((index, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, color_box)| color_box.colors.len() > 1)
+            .max_by_key(|(_, color_box)| {
+                let pixels: u64 = color_box.colors.iter().map(|(_, count)| count).sum();
+                color_box.widest_channel().1 as u64 * pixels
+            })
+        else {
+            break;
+        };
+
+        let color_box = boxes.swap_remove(index);
+        let (channel, _) = color_box.widest_channel();
+        color_box.colors.sort_by_key(|(color, _)| color[channel]);
+
+        let total: u64 = color_box.colors.iter().map(|(_, count)| count).sum();
+        let mut seen = 0;
+        let median = color_box
+            .colors
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap_or(0)
+            .clamp(0, color_box.colors.len() - 2);
+
+        let (low, high) = color_box.colors.split_at_mut(median + 1);
+        boxes.push(ColorBox { colors: low });
+        boxes.push(ColorBox { colors: high });
+    }
+
+    boxes.iter().map(ColorBox::mean).collect()
+}
+
+fn kmeans(colors: &[([u8; 4], u64)], mut palette: Vec<[u8; 4]>) -> Vec<[u8; 4]> {
+    // Clusters the mean colors of groups of similar colors, photos having far
+    // too many unique colors to compare each of them with the palette every pass
+    let mut groups: HashMap<[u8; 4], Vec<([u8; 4], u64)>> = HashMap::new();
+    for &(color, count) in colors {
+        groups
+            .entry(color.map(|c| c >> (8 - KMEANS_BITS)))
+            .or_default()
+            .push((color, count));
+    }
+    let mut samples = groups
+        .into_values()
+        .map(|members| {
+            let count = members.iter().map(|(_, count)| count).sum();
+            (mean_color(members.into_iter()), count)
+        })
+        .collect::<Vec<([u8; 4], u64)>>();
+    samples.sort_unstable();
+
+    for _ in 0..KMEANS_ITERATIONS {
+        let clusters = samples
+            .par_iter()
+            .map(|&(color, _)| nearest(&palette, color.map(|c| c as f32)))
+            .collect::<Vec<usize>>();
+
+        let mut members = vec![Vec::new(); palette.len()];
+        for (&sample, cluster) in samples.iter().zip(clusters) {
+            members[cluster].push(sample);
+        }
+
+        let mut moved = false;
+        for (entry, members) in palette.iter_mut().zip(members) {
+            // Colors no sample is closest to keep their place
+            if members.is_empty() {
+                continue;
+            }
+            let mean = mean_color(members.into_iter());
+            if mean != *entry {
+                *entry = mean;
+                moved = true;
+            }
+        }
+
+        if !moved {
+            break;
+        }
+    }
+    palette
+}
+
+/// Mean of colors weighted by their pixel count, black when there are none
+fn mean_color(colors: impl Iterator<Item = ([u8; 4], u64)>) -> [u8; 4] {
+    let (mut sums, mut total) = ([0u64; 4], 0u64);
+    for (color, count) in colors {
+        for (sum, value) in sums.iter_mut().zip(color) {
+            *sum += value as u64 * count;
+        }
+        total += count;
+    }
+    match total {
+        0 => [0; 4],
+        total => sums.map(|sum| ((sum + total / 2) / total) as u8),
+    }
+}
+
+/// Index of the palette color closest to the given color
+fn nearest(palette: &[[u8; 4]], color: [f32; 4]) -> usize {
+    palette
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let distance: f32 = entry
+                .iter()
+                .zip(color)
+                .map(|(&a, b)| (a as f32 - b).powi(2))
+                .sum();
+            (index, distance)
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(index, _)| index)
+}
+
+fn map_nearest(image: &RgbaImage, palette: &[[u8; 4]]) -> Vec<u8> {
+    image
+        .as_raw()
+        .par_chunks_exact(4)
+        .map(|pixel| {
+            nearest(
+                palette,
+                [pixel[0], pixel[1], pixel[2], pixel[3]].map(|c| c as f32),
+            ) as u8
+        })
+        .collect()
+}
+
+fn floyd_steinberg(image: &RgbaImage, palette: &[[u8; 4]]) -> Vec<u8> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut indices = vec![0; width * height];
+
+    // Error carried to the current and the next row, with a spare column on both sides
+    let mut current = vec![[0f32; 4]; width + 2];
+    let mut next = vec![[0f32; 4]; width + 2];
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x as u32, y as u32).0;
+            let mut wanted = [0.0; 4];
+            for channel in 0..4 {
+                wanted[channel] =
+                    (pixel[channel] as f32 + current[x + 1][channel]).clamp(0.0, 255.0);
+            }
+
+            let index = nearest(palette, wanted);
+            indices[y * width + x] = index as u8;
+
+            for channel in 0..4 {
+                let error = wanted[channel] - palette[index][channel] as f32;
+                current[x + 2][channel] += error * 7.0 / 16.0;
+                next[x][channel] += error * 3.0 / 16.0;
+                next[x + 1][channel] += error * 5.0 / 16.0;
+                next[x + 2][channel] += error / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|error| *error = [0.0; 4]);
+    }
+    indices
+}
+
+fn ordered(image: &RgbaImage, palette: &[[u8; 4]]) -> Vec<u8> {
+    let width = image.width() as usize;
+    // Offsets span about the distance between neighbouring palette colors
+    let spread = 255.0 / (palette.len() as f32).cbrt();
+
+    let mut indices = vec![0; width * image.height() as usize];
+    indices
+        .par_chunks_mut(width.max(1))
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, index) in row.iter_mut().enumerate() {
+                let pixel = image.get_pixel(x as u32, y as u32).0;
+                let threshold = (BAYER[y % 8][x % 8] as f32 + 0.5) / 64.0 - 0.5;
+                let mut wanted = pixel.map(|c| c as f32);
+                for value in &mut wanted[..3] {
+                    *value = (*value + threshold * spread).clamp(0.0, 255.0);
+                }
+                *index = nearest(palette, wanted) as u8;
+            }
+        });
+    indices
+}
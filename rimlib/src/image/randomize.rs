@@ -1,10 +1,13 @@
-use image::DynamicImage;
 use image::imageops::FilterType;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use image::{ColorType, DynamicImage, ImageBuffer, Pixel};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
 
 use super::color::ColorData;
 use super::pixels::{ImageBufferData, PixelConvert};
-use rand::random_range;
+use crate::error::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 /// Randomizer
 ///
@@ -14,20 +17,31 @@ use rand::random_range;
 /// Randomizes the data in images.
 /// This trait is mainly meant for testing purposes but can be used in other ways.
 /// These functions all return a new instance of the image implementing the trait.
+///
+/// Every function has a `_with` variant taking the random number generator to use.
+/// Passing a seeded generator such as `StdRng::seed_from_u64` gives the same image
+/// on every run, which makes it possible to build reproducible test fixtures.
 pub trait Randomizer: Sized + Clone {
-    /// Fills image with completely random data
-    /// Acts as a combination of all of the other functions
+    /// Fills every channel of the image, including alpha, with random data.
+    /// The color type and dimensions are not changed.
     ///
     /// Returns a new instance of the image
-    fn randomize_all(&self) -> Self;
+    fn randomize_all(&self) -> Self {
+        self.randomize_all_with(&mut rand::rng())
+    }
 
     /// Changes the hue of the image based on random generated values
     /// Returns a new instance of the image
-    fn randomize_hue(&self) -> Self;
+    fn randomize_hue(&self) -> Self {
+        self.randomize_hue_with(&mut rand::rng())
+    }
 
-    /// Changes the saturation of the image based on random generated values
+    /// Scales the saturation of the image by a random factor between 0 and 2.
+    /// Grayscale images are returned unchanged.
     /// Returns a new instance of the image
-    fn randomize_saturation(&self) -> Self;
+    fn randomize_saturation(&self) -> Self {
+        self.randomize_saturation_with(&mut rand::rng())
+    }
 
     /// Changes the size to one random size of range between provided range parameters
     /// Returns a new instance of the image
@@ -38,13 +52,49 @@ pub trait Randomizer: Sized + Clone {
         max_width: u32,
         max_height: u32,
         filter_type: Option<FilterType>,
+    ) -> Self {
+        self.randomize_size_with(
+            &mut rand::rng(),
+            min_width,
+            min_height,
+            max_width,
+            max_height,
+            filter_type,
+        )
+    }
+
+    /// Converts the image to the color type provided as a parameter, then fills
+    /// its color channels with random values over the full range of the bit depth.
+    /// Alpha channels are left untouched.
+    /// Returns a new instance of the image.
+    ///
+    /// Fails if the color type has no `DynamicImage` representation, such as 32-bit luma.
+    fn randomize_color<X>(&self, color_type: X) -> Result<Self>
+    where
+        X: ColorData,
+    {
+        self.randomize_color_with(&mut rand::rng(), color_type)
+    }
+
+    fn randomize_all_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Self;
+
+    fn randomize_hue_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Self;
+
+    fn randomize_saturation_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Self;
+
+    fn randomize_size_with<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        min_width: u32,
+        min_height: u32,
+        max_width: u32,
+        max_height: u32,
+        filter_type: Option<FilterType>,
     ) -> Self;
 
-    /// Changes the coloration of the image based on a color type provided as a parameter
-    /// Color types and bit-depths are not changed within this function.
-    /// Returns a new instance of the image
-    fn randomize_color<X>(&self, color_type: X) -> Self
+    fn randomize_color_with<R, X>(&self, rng: &mut R, color_type: X) -> Result<Self>
     where
+        R: Rng + ?Sized,
         X: ColorData;
 }
 
@@ -52,177 +102,177 @@ pub trait Randomizer: Sized + Clone {
 /// ImageBuffers by using DynamicImage::from(buffer), which removes the need to implement
 /// this for every image type manually
 impl Randomizer for DynamicImage {
-    fn randomize_all(&self) -> Self {
-        self.clone()
+    fn randomize_all_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Self {
+        let mut image = self.clone();
+        let seed = rng.random();
+
+        match &mut image {
+            DynamicImage::ImageLuma8(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageLumaA8(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageRgb8(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageRgba8(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageLuma16(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageLumaA16(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageRgb16(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageRgba16(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageRgb32F(buffer) => fill(buffer, usize::MAX, seed),
+            DynamicImage::ImageRgba32F(buffer) => fill(buffer, usize::MAX, seed),
+            _ => (),
+        }
+        image
     }
 
-    fn randomize_hue(&self) -> Self {
-        let rotation = random_range(0..=360);
+    fn randomize_hue_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Self {
+        let rotation = rng.random_range(0..=360);
         self.huerotate(rotation)
     }
 
-    fn randomize_saturation(&self) -> Self {
-        self.clone()
+    fn randomize_saturation_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Self {
+        let factor: f32 = rng.random_range(0.0..=2.0);
+        let color = self.color();
+
+        if color.channel_count() < 3 {
+            return self.clone();
+        }
+
+        let mut buffer = self.to_rgba32f();
+        buffer.pixels_mut().for_each(|p| {
+            let luma = 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2];
+            for channel in &mut p.0[..3] {
+                *channel = (luma + (*channel - luma) * factor).clamp(0.0, 1.0);
+            }
+        });
+
+        let image = DynamicImage::from(buffer);
+        match color {
+            ColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+            ColorType::Rgba8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+            ColorType::Rgb16 => DynamicImage::ImageRgb16(image.to_rgb16()),
+            ColorType::Rgba16 => DynamicImage::ImageRgba16(image.to_rgba16()),
+            ColorType::Rgb32F => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+            _ => image,
+        }
     }
 
-    fn randomize_size(
+    fn randomize_size_with<R: Rng + ?Sized>(
         &self,
+        rng: &mut R,
         min_width: u32,
         min_height: u32,
         max_width: u32,
         max_height: u32,
         filter_type: Option<FilterType>,
     ) -> Self {
-        let height = random_range(min_height..=max_height);
-        let width = random_range(min_width..=max_width);
+        let height = rng.random_range(min_height..=max_height);
+        let width = rng.random_range(min_width..=max_width);
         match filter_type {
             Some(ftype) => self.resize_exact(width, height, ftype),
             None => self.resize_exact(width, height, FilterType::Nearest),
         }
     }
 
-    fn randomize_color<X>(&self, color_data: X) -> Self
+    fn randomize_color_with<R, X>(&self, rng: &mut R, color_data: X) -> Result<Self>
     where
+        R: Rng + ?Sized,
         X: ColorData,
     {
-        let info = color_data.color_info();
+        let seed = rng.random();
+        let buffer = self.convert_color_to(color_data.color_info()?)?;
 
-        let image = info.convert_image(self.clone());
-
-        match image.convert_color_to(info) {
+        Ok(match buffer {
             ImageBufferData::Rgb8(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 3, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Rgba8(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 3, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Rgb16(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 3, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Rgba16(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 3, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Rgb32f(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color: f32 = random_range(0.00..=(size_of::<u8>() as f32));
-                    p[0] = color;
-
-                    let color: f32 = random_range(0.00..=(size_of::<u8>() as f32));
-                    p[1] = color;
-
-                    let color: f32 = random_range(0.00..=(size_of::<u8>() as f32));
-                    p[2] = color;
-                });
+                fill(&mut buffer, 3, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Rgba32f(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color: f32 = random_range(0.00..=(size_of::<u8>() as f32));
-                    p[0] = color;
-
-                    let color: f32 = random_range(0.00..=(size_of::<u8>() as f32));
-                    p[1] = color;
-
-                    let color: f32 = random_range(0.00..=(size_of::<u8>() as f32));
-                    p[2] = color;
-                });
+                fill(&mut buffer, 3, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Luma8(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 1, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::LumaA8(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 1, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::Luma16(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 1, seed);
                 DynamicImage::from(buffer)
             }
             ImageBufferData::LumaA16(mut buffer) => {
-                buffer.pixels_mut().par_bridge().for_each(|p| {
-                    let color = random_range(0x00..=0xFF);
-                    p[0] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[1] = color;
-
-                    let color = random_range(0x00..=0xFF);
-                    p[2] = color;
-                });
+                fill(&mut buffer, 1, seed);
                 DynamicImage::from(buffer)
             }
-        }
+        })
+    }
+}
+
+/// Subpixel types that can be filled with random values over their full range
+trait RandomChannel: Sized {
+    fn random<R: Rng>(rng: &mut R) -> Self;
+}
+
+impl RandomChannel for u8 {
+    fn random<R: Rng>(rng: &mut R) -> Self {
+        rng.random()
     }
 }
 
+impl RandomChannel for u16 {
+    fn random<R: Rng>(rng: &mut R) -> Self {
+        rng.random()
+    }
+}
+
+impl RandomChannel for f32 {
+    fn random<R: Rng>(rng: &mut R) -> Self {
+        rng.random_range(0.0..=1.0)
+    }
+}
+
+/// Fills the first `count` channels of every pixel with random values.
+///
+/// Rows are filled in parallel, each with its own generator derived from the seed,
+/// so the result only depends on the seed and not on how rows are scheduled.
+fn fill<P>(buffer: &mut ImageBuffer<P, Vec<P::Subpixel>>, count: usize, seed: u64)
+where
+    P: Pixel,
+    P::Subpixel: RandomChannel + Send,
+{
+    let channels = P::CHANNEL_COUNT as usize;
+    let count = count.min(channels);
+    let row_len = (buffer.width() as usize * channels).max(1);
+
+    let data: &mut [P::Subpixel] = buffer;
+    data.par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(row, data)| {
+            let mut rng =
+                StdRng::seed_from_u64(seed ^ (row as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            data.chunks_mut(channels).for_each(|pixel| {
+                pixel[..count]
+                    .iter_mut()
+                    .for_each(|channel| *channel = P::Subpixel::random(&mut rng));
+            });
+        });
+}
+
 #[derive(Debug)]
 pub enum RandomizerError {}
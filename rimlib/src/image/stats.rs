@@ -0,0 +1,264 @@
+use crate::error::{Error, Result};
+use image::{DynamicImage, RgbImage};
+use std::collections::HashSet;
+
+/// Number of bins in each channel histogram
+pub const HISTOGRAM_BINS: usize = 256;
+
+/// Statistics for one channel of an image.
+/// Values are scaled to 0.0 to 1.0 whatever the bit depth.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelStats {
+    /// Channel name: `R`, `G`, `B`, `L` or `A`
+    pub name: char,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub stddev: f32,
+    /// Percentage of pixels at or below 0.0
+    pub clipped_low: f32,
+    /// Percentage of pixels at or above 1.0
+    pub clipped_high: f32,
+    /// Pixel count in each of the `HISTOGRAM_BINS` bins
+    pub histogram: Vec<u64>,
+}
+
+/// Pixel statistics of an image
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ImageStats {
+    pub width: u32,
+    pub height: u32,
+    /// Statistics for every channel in the order they are stored
+    pub channels: Vec<ChannelStats>,
+    /// Number of distinct pixel values
+    pub unique_colors: usize,
+    /// Percentage of pixels that are not fully transparent,
+    /// `None` for images without alpha
+    pub alpha_coverage: Option<f32>,
+}
+
+impl ImageStats {
+    /// Computes the statistics of every channel of the image
+    pub fn compute(image: &DynamicImage) -> Self {
+        let names = channel_names(image);
+        let values = unit_values(image);
+        let pixel_count = image.width() as usize * image.height() as usize;
+
+        let channels = names
+            .iter()
+            .enumerate()
+            .map(|(index, &name)| {
+                channel_stats(
+                    name,
+                    values.iter().skip(index).step_by(names.len()).copied(),
+                    pixel_count,
+                )
+            })
+            .collect::<Vec<ChannelStats>>();
+
+        let alpha_coverage = image.color().has_alpha().then(|| {
+            let alpha = values.iter().skip(names.len() - 1).step_by(names.len());
+            percentage(alpha.filter(|&&a| a > 0.0).count(), pixel_count)
+        });
+
+        let bytes_per_pixel = image.color().bytes_per_pixel() as usize;
+        let unique_colors = image
+            .as_bytes()
+            .chunks_exact(bytes_per_pixel)
+            .collect::<HashSet<&[u8]>>()
+            .len();
+
+        Self {
+            width: image.width(),
+            height: image.height(),
+            channels,
+            unique_colors,
+            alpha_coverage,
+        }
+    }
+
+    /// Whether every channel holds a single value, as in blank or empty images
+    pub fn is_uniform(&self) -> bool {
+        self.channels
+            .iter()
+            .all(|channel| channel.min == channel.max)
+    }
+
+    /// Highest percentage of clipped pixels over the color channels
+    pub fn max_clipping(&self) -> f32 {
+        self.channels
+            .iter()
+            .filter(|channel| channel.name != 'A')
+            .map(|channel| channel.clipped_low.max(channel.clipped_high))
+            .fold(0.0, f32::max)
+    }
+
+    /// Human readable summary, without the histograms
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Unique colors: {}", self.unique_colors);
+
+        if let Some(coverage) = self.alpha_coverage {
+            text.push_str(&format!("\nAlpha coverage: {coverage:.2}%"));
+        }
+
+        for channel in &self.channels {
+            text.push_str(&format!(
+                "\n{}: min {:.4} max {:.4} mean {:.4} stddev {:.4} clipped {:.2}% low {:.2}% high",
+                channel.name,
+                channel.min,
+                channel.max,
+                channel.mean,
+                channel.stddev,
+                channel.clipped_low,
+                channel.clipped_high
+            ));
+        }
+        text
+    }
+
+    /// Draws the histograms of all channels over each other,
+    /// one column per bin, scaled to the tallest bin
+    pub fn histogram_image(&self, height: u32) -> RgbImage {
+        let mut image = RgbImage::new(HISTOGRAM_BINS as u32, height);
+
+        let peak = self
+            .channels
+            .iter()
+            .flat_map(|channel| channel.histogram.iter())
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(1);
+
+        for channel in &self.channels {
+            let color = match channel.name {
+                'R' => [255, 0, 0],
+                'G' => [0, 255, 0],
+                'B' => [0, 0, 255],
+                'A' => [96, 96, 96],
+                _ => [255, 255, 255],
+            };
+
+            for (x, &count) in channel.histogram.iter().enumerate() {
+                let bar = (count as f64 / peak as f64 * height as f64).round() as u32;
+                for y in height - bar..height {
+                    let pixel = image.get_pixel_mut(x as u32, y);
+                    for (value, add) in pixel.0.iter_mut().zip(color) {
+                        *value = value.saturating_add(add);
+                    }
+                }
+            }
+        }
+        image
+    }
+}
+
+/// Values of the pixel at the given position, scaled to 0.0 to 1.0
+pub fn sample_pixel(image: &DynamicImage, x: u32, y: u32) -> Result<Vec<f32>> {
+    sample_rect(image, x, y, 1, 1)
+}
+
+/// Average values over a rectangle of the image, scaled to 0.0 to 1.0
+pub fn sample_rect(
+    image: &DynamicImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Result<Vec<f32>> {
+    let in_bounds = width > 0
+        && height > 0
+        && x.checked_add(width)
+            .is_some_and(|right| right <= image.width())
+        && y.checked_add(height)
+            .is_some_and(|bottom| bottom <= image.height());
+
+    if !in_bounds {
+        return Err(Error::operation(
+            None,
+            &format!(
+                "sample area {width}x{height}+{x}+{y} is outside the {}x{} image",
+                image.width(),
+                image.height()
+            ),
+        ));
+    }
+
+    let channels = channel_names(image).len();
+    let values = unit_values(&image.crop_imm(x, y, width, height));
+    let count = (width * height) as f32;
+
+    Ok((0..channels)
+        .map(|index| values.iter().skip(index).step_by(channels).sum::<f32>() / count)
+        .collect())
+}
+
+fn channel_names(image: &DynamicImage) -> &'static [char] {
+    match image.color().channel_count() {
+        1 => &['L'],
+        2 => &['L', 'A'],
+        3 => &['R', 'G', 'B'],
+        _ => &['R', 'G', 'B', 'A'],
+    }
+}
+
+/// Interleaved channel values of the image scaled to 0.0 to 1.0
+fn unit_values(image: &DynamicImage) -> Vec<f32> {
+    match image.color().channel_count() {
+        1 => image.to_luma32f().into_raw(),
+        2 => image.to_luma_alpha32f().into_raw(),
+        3 => image.to_rgb32f().into_raw(),
+        _ => image.to_rgba32f().into_raw(),
+    }
+}
+
+fn channel_stats(name: char, values: impl Iterator<Item = f32>, count: usize) -> ChannelStats {
+    let mut histogram = vec![0; HISTOGRAM_BINS];
+    let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+    let (mut sum, mut sum_squares) = (0.0f64, 0.0f64);
+    let (mut low, mut high) = (0, 0);
+
+    for value in values {
+        min = min.min(value);
+        max = max.max(value);
+        sum += value as f64;
+        sum_squares += (value as f64).powi(2);
+
+        if value <= 0.0 {
+            low += 1;
+        } else if value >= 1.0 {
+            high += 1;
+        }
+
+        let bin = (value.clamp(0.0, 1.0) * (HISTOGRAM_BINS - 1) as f32).round() as usize;
+        histogram[bin] += 1;
+    }
+
+    let mean = if count > 0 { sum / count as f64 } else { 0.0 };
+    let variance = if count > 0 {
+        (sum_squares / count as f64 - mean.powi(2)).max(0.0)
+    } else {
+        0.0
+    };
+
+    ChannelStats {
+        name,
+        min: if count > 0 { min } else { 0.0 },
+        max: if count > 0 { max } else { 0.0 },
+        mean: mean as f32,
+        stddev: variance.sqrt() as f32,
+        clipped_low: percentage(low, count),
+        clipped_high: percentage(high, count),
+        histogram,
+    }
+}
+
+fn percentage(part: usize, total: usize) -> f32 {
+    if total == 0 {
+        0.0
+    } else {
+        (part as f64 / total as f64 * 100.0) as f32
+    }
+}
@@ -0,0 +1,194 @@
+use super::color::ColorInfo;
+use crate::error::{Error, Result};
+use image::{DynamicImage, Rgba, Rgba32FImage};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use std::str::FromStr;
+
+/// Direction an image is mirrored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlipDirection {
+    /// Mirrors left and right
+    Horizontal,
+    /// Mirrors top and bottom
+    Vertical,
+}
+
+impl FromStr for FlipDirection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "horizontal" | "h" | "x" => Ok(FlipDirection::Horizontal),
+            "vertical" | "v" | "y" => Ok(FlipDirection::Vertical),
+            _ => Err(Error::operation(
+                None,
+                &format!("unknown flip direction {s:?}, must be horizontal or vertical"),
+            )),
+        }
+    }
+}
+
+/// Color filling the corners uncovered when rotating by an arbitrary angle,
+/// with channels from 0.0 to 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Background(pub [f32; 4]);
+
+impl Default for Background {
+    fn default() -> Self {
+        Background([0.0; 4])
+    }
+}
+
+impl FromStr for Background {
+    type Err = Error;
+
+    /// Parses `transparent`, a color name, `#rrggbb`, `#rrggbbaa` or `r,g,b[,a]`
+    /// with channels from 0 to 255
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::operation(
+                None,
+                &format!("invalid background {s:?}, use transparent, a name, #rrggbbaa or r,g,b,a"),
+            )
+        };
+
+        let s = s.trim().to_lowercase();
+        let channels: Vec<u8> = match s.as_str() {
+            "transparent" | "none" => vec![0, 0, 0, 0],
+            "white" => vec![255, 255, 255],
+            "black" => vec![0, 0, 0],
+            "gray" | "grey" => vec![128, 128, 128],
+            hex if hex.starts_with('#') && (hex.len() == 7 || hex.len() == 9) => (1..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| invalid())?,
+            list => list
+                .split(',')
+                .map(|channel| channel.trim().parse::<u8>())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| invalid())?,
+        };
+
+        match channels[..] {
+            [r, g, b] => Ok(Background([r, g, b, 255].map(|c| c as f32 / 255.0))),
+            [r, g, b, a] => Ok(Background([r, g, b, a].map(|c| c as f32 / 255.0))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Mirrors the image
+pub fn flip(image: DynamicImage, direction: FlipDirection) -> DynamicImage {
+    match direction {
+        FlipDirection::Horizontal => image.fliph(),
+        FlipDirection::Vertical => image.flipv(),
+    }
+}
+
+/// Rotates the image clockwise by the angle in degrees.
+///
+/// Multiples of 90 degrees move pixels without resampling.
+/// Other angles enlarge the canvas to fit the rotated image and fill the
+/// uncovered corners with the background, adding an alpha channel
+/// when the background is not opaque.
+///
+/// Fails for angles that are not finite, such as NaN.
+pub fn rotate(image: DynamicImage, degrees: f32, background: Background) -> Result<DynamicImage> {
+    let degrees = normalize_angle(degrees)?;
+
+    match degrees {
+        0.0 => return Ok(image),
+        90.0 => return Ok(image.rotate90()),
+        180.0 => return Ok(image.rotate180()),
+        270.0 => return Ok(image.rotate270()),
+        _ => (),
+    }
+
+    let color = ColorInfo::from_image(&image);
+    let source = image.to_rgba32f();
+    let (width, height) = rotated_size(source.width(), source.height(), degrees);
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let source_center = (source.width() as f32 / 2.0, source.height() as f32 / 2.0);
+    let center = (width as f32 / 2.0, height as f32 / 2.0);
+
+    let mut rotated = Rgba32FImage::new(width, height);
+    let row_len = (width as usize * 4).max(1);
+
+    let data: &mut [f32] = &mut rotated;
+    data.par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let dx = x as f32 + 0.5 - center.0;
+                let dy = y as f32 + 0.5 - center.1;
+                // Inverse rotation maps the output pixel back into the source
+                let sx = dx * cos + dy * sin + source_center.0 - 0.5;
+                let sy = -dx * sin + dy * cos + source_center.1 - 0.5;
+                pixel.copy_from_slice(&bilinear(&source, sx, sy, background));
+            }
+        });
+
+    // The rotated image keeps the color space and bit depth of the source,
+    // every layout a DynamicImage holds can be converted back from RGBA floats
+    let keeps_alpha = color.has_alpha() || background.0[3] < 1.0;
+    color
+        .with_alpha(keeps_alpha)
+        .convert_image(DynamicImage::ImageRgba32F(rotated))
+}
+
+/// Reduces an angle in degrees to the range 0 to 360, rejecting NaN and infinity
+pub fn normalize_angle(degrees: f32) -> Result<f32> {
+    if !degrees.is_finite() {
+        return Err(Error::operation(
+            None,
+            &format!("invalid rotation angle {degrees}, it must be a finite number of degrees"),
+        ));
+    }
+    Ok(degrees.rem_euclid(360.0))
+}
+
+/// Dimensions of the canvas holding an image rotated clockwise by the angle in degrees
+pub fn rotated_size(width: u32, height: u32, degrees: f32) -> (u32, u32) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (sin, cos) = (sin.abs() as f64, cos.abs() as f64);
+    let (width, height) = (width as f64, height as f64);
+
+    // Rounding first keeps float noise from adding a pixel
+    let fit = |value: f64| ((value * 1e4).round() / 1e4).ceil() as u32;
+    (
+        fit(width * cos + height * sin),
+        fit(width * sin + height * cos),
+    )
+}
+
+fn bilinear(source: &Rgba32FImage, x: f32, y: f32, background: Background) -> [f32; 4] {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let sample = |x: f32, y: f32| -> [f32; 4] {
+        if x < 0.0 || y < 0.0 || x >= source.width() as f32 || y >= source.height() as f32 {
+            background.0
+        } else {
+            let Rgba(pixel) = *source.get_pixel(x as u32, y as u32);
+            pixel
+        }
+    };
+
+    let corners = [
+        (sample(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (sample(x0 + 1.0, y0), fx * (1.0 - fy)),
+        (sample(x0, y0 + 1.0), (1.0 - fx) * fy),
+        (sample(x0 + 1.0, y0 + 1.0), fx * fy),
+    ];
+
+    let mut pixel = [0.0; 4];
+    for (color, weight) in corners {
+        for (channel, value) in pixel.iter_mut().zip(color) {
+            *channel += value * weight;
+        }
+    }
+    pixel
+}
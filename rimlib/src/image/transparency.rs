@@ -1,53 +1,220 @@
+use super::color::BitDepth::{B16, B32};
 use super::color::ColorInfo;
-use image::{DynamicImage, GenericImage};
-use rayon::iter::{ParallelBridge, ParallelIterator};
-use super::color::BitDepth::{B8, B16, B32};
+use super::pixels::Channel;
+use crate::error::{Error, Result};
+use image::{DynamicImage, GenericImage, ImageBuffer, Pixel, Rgba};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use std::collections::VecDeque;
+use std::str::FromStr;
 
 /// Trait handling changing transparency of images
 ///
 /// Implementations must implement the `Sized`, `Clone` and `GenericImage` traits.
 pub trait Transparenize: Sized + Clone + GenericImage {
-    /// Removes the background of the image.
-    /// Currently removes the all white pixels from the image.
+    /// Removes the white background of the image.
     /// Returns a new instance of the image
-    fn transparentize(&self) -> Self;
+    fn transparentize(&self) -> Self {
+        self.remove_background(&BackgroundOptions::default())
+    }
+
+    /// Removes the background matching the options.
+    /// Returns a new instance of the image
+    fn remove_background(&self, options: &BackgroundOptions) -> Self;
+}
+
+/// Color treated as background, with channels from 0.0 to 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyColor(pub [f32; 3]);
+
+impl Default for KeyColor {
+    fn default() -> Self {
+        KeyColor([1.0, 1.0, 1.0])
+    }
+}
+
+impl FromStr for KeyColor {
+    type Err = Error;
+
+    /// Parses `white`, `black`, `#rrggbb` or `r,g,b` with channels from 0 to 255
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::operation(
+                None,
+                &format!("invalid key color {s:?}, use a name, #rrggbb or r,g,b"),
+            )
+        };
+
+        let s = s.trim();
+        let channels = match s.to_lowercase().as_str() {
+            "white" => [255, 255, 255],
+            "black" => [0, 0, 0],
+            "red" => [255, 0, 0],
+            "green" => [0, 255, 0],
+            "blue" => [0, 0, 255],
+            hex if hex.starts_with('#') && hex.len() == 7 => {
+                let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+                [
+                    channel(1).map_err(|_| invalid())?,
+                    channel(3).map_err(|_| invalid())?,
+                    channel(5).map_err(|_| invalid())?,
+                ]
+            }
+            list => {
+                let parsed = list
+                    .split(',')
+                    .map(|channel| channel.trim().parse::<u8>())
+                    .collect::<std::result::Result<Vec<u8>, _>>()
+                    .map_err(|_| invalid())?;
+                parsed.try_into().map_err(|_| invalid())?
+            }
+        };
+
+        Ok(KeyColor(channels.map(|channel| channel as f32 / 255.0)))
+    }
+}
+
+/// Which pixels matching the key color are removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemovalMode {
+    /// Every matching pixel in the image
+    #[default]
+    Global,
+    /// Only matching pixels connected to the image border,
+    /// keeping matching areas inside the subject
+    Border,
+}
+
+/// Settings for background removal
+///
+/// Distances are measured in RGB space scaled so that 1.0 is the distance
+/// from black to white, which keeps them the same at every bit depth.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BackgroundOptions {
+    pub key: KeyColor,
+    /// Largest distance from the key color that is made fully transparent
+    pub tolerance: f32,
+    /// Width of the band past the tolerance where alpha fades back in
+    pub feather: f32,
+    pub mode: RemovalMode,
+}
+
+impl BackgroundOptions {
+    /// Alpha multiplier for a pixel, 0.0 for background and 1.0 for foreground
+    fn alpha_factor(&self, rgb: [f32; 3]) -> f32 {
+        let distance = rgb
+            .iter()
+            .zip(self.key.0)
+            .map(|(channel, key)| (channel - key).powi(2))
+            .sum::<f32>()
+            .sqrt()
+            / 3f32.sqrt();
+
+        if distance <= self.tolerance {
+            0.0
+        } else if distance < self.tolerance + self.feather {
+            (distance - self.tolerance) / self.feather
+        } else {
+            1.0
+        }
+    }
+}
+
+fn remove_background<T>(image: &mut ImageBuffer<Rgba<T>, Vec<T>>, options: &BackgroundOptions)
+where
+    T: Channel,
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let factors = image
+        .pixels()
+        .map(|pixel| {
+            options.alpha_factor([pixel[0].to_unit(), pixel[1].to_unit(), pixel[2].to_unit()])
+        })
+        .collect::<Vec<f32>>();
+
+    let factors = match options.mode {
+        RemovalMode::Global => factors,
+        RemovalMode::Border => {
+            border_fill(&factors, image.width() as usize, image.height() as usize)
+        }
+    };
+
+    let pixels: &mut [T] = image;
+    pixels
+        .par_iter_mut()
+        .skip(3)
+        .step_by(4)
+        .zip(factors)
+        .for_each(|(alpha, factor)| {
+            if factor < 1.0 {
+                *alpha = T::from_unit(alpha.to_unit() * factor);
+            }
+        });
+}
+
+/// Keeps the alpha factors of pixels connected to the border through other
+/// background pixels, resetting every other factor to 1.0
+fn border_fill(factors: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut filled = vec![1.0; factors.len()];
+    let mut queue = VecDeque::new();
+
+    let border = (0..width)
+        .flat_map(|x| [(x, 0), (x, height - 1)])
+        .chain((0..height).flat_map(|y| [(0, y), (width - 1, y)]));
+
+    for (x, y) in border {
+        queue.push_back(y * width + x);
+    }
+
+    while let Some(index) = queue.pop_front() {
+        if factors[index] >= 1.0 || filled[index] < 1.0 {
+            continue;
+        }
+        filled[index] = factors[index];
+
+        let (x, y) = (index % width, index / width);
+        if x > 0 {
+            queue.push_back(index - 1);
+        }
+        if x + 1 < width {
+            queue.push_back(index + 1);
+        }
+        if y > 0 {
+            queue.push_back(index - width);
+        }
+        if y + 1 < height {
+            queue.push_back(index + width);
+        }
+    }
+
+    filled
 }
 
 impl Transparenize for DynamicImage {
-    fn transparentize(&self) -> Self {
+    fn remove_background(&self, options: &BackgroundOptions) -> Self {
         let color_info = ColorInfo::from_image(self);
-        let image = self.clone();
 
-        match color_info.bit_depth {
-            B8 => {
-                let mut image8bit = image.to_rgba8();
-                image8bit.pixels_mut().par_bridge().for_each(|pixel| {
-                    if pixel[0] == 255 && pixel[1] == 255 && pixel[2] == 255 {
-                        pixel[3] = 0;
-                    }
-                });
-                DynamicImage::ImageRgba8(image8bit)
-            }
+        if self.width() == 0 || self.height() == 0 {
+            return self.clone();
+        }
 
+        match color_info.bit_depth {
             B16 => {
-                let mut image16bit = image.to_rgba16();
-                image16bit.pixels_mut().par_bridge().for_each(|pixel| {
-                    if pixel[0] == 255 && pixel[1] == 255 && pixel[2] == 255 {
-                        pixel[3] = 0;
-                    }
-                });
+                let mut image16bit = self.to_rgba16();
+                remove_background(&mut image16bit, options);
                 DynamicImage::ImageRgba16(image16bit)
             }
 
             B32 => {
-                let mut image32bit = image.to_rgba32f();
-                image32bit.pixels_mut().par_bridge().for_each(|pixel| {
-                    if pixel[0] == 255.0 && pixel[1] == 255.0 && pixel[2] == 255.0 {
-                        pixel[3] = 0.0;
-                    }
-                });
+                let mut image32bit = self.to_rgba32f();
+                remove_background(&mut image32bit, options);
                 DynamicImage::ImageRgba32F(image32bit)
             }
+
+            _ => {
+                let mut image8bit = self.to_rgba8();
+                remove_background(&mut image8bit, options);
+                DynamicImage::ImageRgba8(image8bit)
+            }
         }
     }
 }
@@ -1,5 +1,8 @@
 // mod backend;
+pub mod error;
 pub mod image;
 
+pub use error::{Error, Result};
+
 #[cfg(test)]
 mod tests;
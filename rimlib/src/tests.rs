@@ -1,12 +1,44 @@
 use std::{
     io::{BufWriter, Cursor},
     sync::mpsc::channel,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use crate::image::transparency::Transparenize;
+use crate::error::Error;
+use crate::image::adjust::Adjustment;
+use crate::image::animation::{
+    Animation, AnimationFrame, LoopCount, open_animation, open_animation_oriented,
+    open_animation_with_metadata, save_animation,
+};
+use crate::image::color::{BitDepth, ColorData, ColorInfo, ColorSpace};
+use crate::image::compare::{Comparison, Metric, ciede2000, diff_image};
+use crate::image::formats::{Compression, EncodeOptions, save_image, save_image_with_metadata};
+use crate::image::geometry::{Geometry, Gravity, Length, Rect, ResizeSpec};
+use crate::image::hash::{HashAlgorithm, ImageHash, group_duplicates};
+use crate::image::info::ImageInfo;
+use crate::image::input::{
+    Glob, InputOptions, collect_inputs, is_decodable, read_path_list, relative_dirs,
+};
+use crate::image::manipulator::{
+    open_image, open_image_oriented, open_image_with_metadata, probe_image, read_exif,
+    read_metadata,
+};
+use crate::image::metadata::Metadata;
+use crate::image::montage::{MontageOptions, Tile, montage};
+use crate::image::naming::{NameFields, NameTemplate, natural_cmp, natural_indices};
+use crate::image::operation::{
+    Adjust, Convert, Crop, Flip, Operation, Overlay, Pipeline, Quantize, Recolor, Resize, Rotate,
+    Transparentize,
+};
+use crate::image::overlay::OverlayOptions;
+use crate::image::quantize::{Dither, IndexedImage, QuantizeOptions, Quantizer, quantize};
 use crate::image::randomize::Randomizer;
-use image::{DynamicImage, ColorType};
+use crate::image::stats::{ImageStats, sample_pixel, sample_rect};
+use crate::image::transform::{Background, FlipDirection};
+use crate::image::transparency::{BackgroundOptions, KeyColor, RemovalMode, Transparenize};
+use image::metadata::Orientation;
+use image::{ColorType, DynamicImage, GenericImageView, Rgba};
+use rand::{SeedableRng, rngs::StdRng};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 #[test]
@@ -20,7 +52,6 @@ fn resize() {
             i.randomize_size(480, 270, 3840, 2160, None)
         };
 
-
         images.push(image);
     }
 }
@@ -36,7 +67,6 @@ fn mass_write() {
             i.randomize_all()
         };
 
-
         images.push(image);
     }
 
@@ -97,7 +127,8 @@ fn multi_random() {
         let image = {
             let i = DynamicImage::new_luma_a16(1920, 1080);
 
-            i.randomize_hue().randomize_size(640, 360, 15360, 8640, None)
+            i.randomize_hue()
+                .randomize_size(640, 360, 15360, 8640, None)
         };
 
         images.push(image);
@@ -112,9 +143,1279 @@ fn transparency() {
         let image = {
             let i = DynamicImage::new_rgba16(1920, 1080);
 
-            i.randomize_color(ColorType::Rgba16).transparentize()
+            i.randomize_color(ColorType::Rgba16)
+                .unwrap()
+                .transparentize()
         };
 
         images.push(image);
     }
 }
+
+#[test]
+fn open_errors() {
+    let dir = std::env::temp_dir().join("rimlib_open_errors");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let missing = dir.join("missing.png");
+    assert!(matches!(open_image(&missing), Err(Error::Io { .. })));
+
+    let corrupt = dir.join("corrupt.png");
+    std::fs::write(&corrupt, b"\x89PNG\r\n\x1a\nnot really a png").unwrap();
+    match open_image(&corrupt) {
+        Err(error @ Error::Decode { .. }) => assert_eq!(error.path(), Some(corrupt.as_path())),
+        other => panic!("expected a decode error, got {other:?}"),
+    }
+}
+
+#[test]
+fn pipeline() {
+    let pipeline = Pipeline::new()
+        .then(Resize::new(640, 360))
+        .then(Recolor(ColorInfo::new(&ColorSpace::Rgb, &BitDepth::B16)))
+        .then(Transparentize::default());
+
+    let image = pipeline
+        .run(
+            DynamicImage::new_rgb8(1920, 1080)
+                .randomize_color(ColorType::Rgb8)
+                .unwrap(),
+        )
+        .unwrap();
+
+    assert_eq!(pipeline.len(), 3);
+    assert_eq!((image.width(), image.height()), (640, 360));
+    assert_eq!(image.color(), ColorType::Rgba16);
+    assert_eq!(
+        pipeline.output_color(ColorType::Rgb8.into()),
+        ColorInfo::from_image(&image)
+    );
+
+    // Quantized images are saved as palette indices, GIF conversion widens to RGBA
+    let indexed = Pipeline::new()
+        .then(Convert::new(Some("gif")))
+        .then(Quantize::new(QuantizeOptions {
+            colors: 16,
+            ..Default::default()
+        }));
+    assert_eq!(
+        Convert::new(Some("gif")).output_color(ColorType::L8.into()),
+        ColorType::Rgba8.into()
+    );
+    assert_eq!(
+        indexed.output_color(ColorType::Rgb16.into()),
+        ColorInfo::new(&ColorSpace::Indexed, &BitDepth::B4)
+    );
+}
+
+#[test]
+fn probe() {
+    let path = std::env::temp_dir().join("rimlib_probe.png");
+    DynamicImage::new_rgba16(320, 240).save(&path).unwrap();
+
+    let probe = probe_image(&path).unwrap();
+
+    assert_eq!((probe.width, probe.height), (320, 240));
+    assert_eq!(probe.format, ::image::ImageFormat::Png);
+    assert_eq!(probe.color_type, ColorType::Rgba16);
+}
+
+#[test]
+fn metadata_roundtrip() {
+    // Little endian EXIF with the camera make in the first IFD and the exposure time in the EXIF IFD
+    let exif = [
+        &b"II\x2a\x00\x08\x00\x00\x00\x02\x00"[..],
+        b"\x0f\x01\x02\x00\x05\x00\x00\x00\x26\x00\x00\x00",
+        b"\x69\x87\x04\x00\x01\x00\x00\x00\x2c\x00\x00\x00\x00\x00\x00\x00",
+        b"Rimi\x00\x00\x01\x00",
+        b"\x9a\x82\x05\x00\x01\x00\x00\x00\x3e\x00\x00\x00\x00\x00\x00\x00",
+        b"\x01\x00\x00\x00\xfa\x00\x00\x00",
+    ]
+    .concat();
+    let metadata = Metadata {
+        exif: Some(exif),
+        icc_profile: Some(vec![7; 512]),
+        xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>".to_vec()),
+    };
+    assert_eq!(metadata.exif_field(0x010F).as_deref(), Some("Rimi"));
+    assert_eq!(metadata.exif_field(0x829A).as_deref(), Some("1/250"));
+
+    let image = DynamicImage::new_rgba8(64, 48)
+        .randomize_color(ColorType::Rgba8)
+        .unwrap();
+
+    for format in ["png", "jpg", "webp", "tiff"] {
+        let path = std::env::temp_dir().join(format!("rimlib_metadata.{format}"));
+        let image = match format {
+            "jpg" => DynamicImage::ImageRgb8(image.to_rgb8()),
+            _ => image.clone(),
+        };
+
+        save_image_with_metadata(&image, &metadata, &path, None).unwrap();
+        let (decoded, read) = open_image_with_metadata(&path).unwrap();
+
+        assert_eq!((decoded.width(), decoded.height()), (64, 48));
+        match format {
+            // TIFF keeps the EXIF entries as tags of the image, the EXIF data is rebuilt from them
+            "tiff" => {
+                assert_eq!(decoded, image);
+                assert_eq!(read.icc_profile, metadata.icc_profile);
+                assert_eq!(read.xmp, metadata.xmp);
+                assert_eq!(read.exif_field(0x010F).as_deref(), Some("Rimi"));
+                assert_eq!(read.exif_field(0x829A).as_deref(), Some("1/250"));
+            }
+            _ => assert_eq!(read, metadata, "metadata mismatch for {format}"),
+        }
+        assert_eq!(read_metadata(&path).unwrap(), read);
+        assert_eq!(read_exif(&path).unwrap(), read.exif);
+    }
+
+    // AVIF cannot be decoded here, its metadata is read from the items and properties
+    let avif = std::env::temp_dir().join("rimlib_metadata.avif");
+    save_image_with_metadata(&image, &metadata, &avif, None).unwrap();
+    assert_eq!(read_metadata(&avif).unwrap(), metadata);
+    assert_eq!(read_exif(&avif).unwrap(), metadata.exif);
+    save_image_with_metadata(&image, &Metadata::default(), &avif, None).unwrap();
+    assert_eq!(read_metadata(&avif).unwrap(), Metadata::default());
+
+    let gif = std::env::temp_dir().join("rimlib_metadata.gif");
+    assert!(save_image_with_metadata(&image, &metadata, &gif, None).is_err());
+    save_image_with_metadata(&image, &Metadata::default(), &gif, None).unwrap();
+}
+
+#[test]
+fn encode_options() {
+    let image = DynamicImage::new_rgb8(128, 96)
+        .randomize_color(ColorType::Rgb8)
+        .unwrap();
+    let dir = std::env::temp_dir();
+    let none = Metadata::default();
+
+    let size = |quality| {
+        let path = dir.join(format!("rimlib_quality_{quality}.jpg"));
+        let options = EncodeOptions {
+            quality: Some(quality),
+            ..Default::default()
+        };
+        save_image(&image, &path, None, &options, &none).unwrap();
+        std::fs::metadata(&path).unwrap().len()
+    };
+    assert!(size(10) < size(95));
+
+    let tiff = dir.join("rimlib_lzw.tiff");
+    let options = EncodeOptions {
+        compression: Some(Compression::Lzw),
+        ..Default::default()
+    };
+    save_image(&image, &tiff, None, &options, &none).unwrap();
+    assert_eq!(open_image(&tiff).unwrap(), image);
+
+    // RGBA is written as is, luma with alpha is widened to RGBA
+    let rgba = DynamicImage::ImageRgba8(image.to_rgba8());
+    save_image(&rgba, &tiff, None, &options, &none).unwrap();
+    assert_eq!(open_image(&tiff).unwrap(), rgba);
+    let luma_alpha = DynamicImage::ImageLumaA16(image.to_luma_alpha16());
+    save_image(&luma_alpha, &tiff, None, &options, &none).unwrap();
+    assert_eq!(
+        open_image(&tiff).unwrap().to_luma_alpha16(),
+        luma_alpha.to_luma_alpha16()
+    );
+
+    let png = dir.join("rimlib_lzw.png");
+    assert!(matches!(
+        save_image(&image, &png, None, &options, &none),
+        Err(Error::InvalidOperation { .. })
+    ));
+}
+
+#[test]
+fn image_info() {
+    let path = std::env::temp_dir().join("rimlib_info.png");
+    DynamicImage::new_rgba8(200, 100).save(&path).unwrap();
+
+    let info = ImageInfo::read(&path).unwrap();
+
+    assert_eq!((info.width, info.height), (200, 100));
+    assert_eq!(info.format, ::image::ImageFormat::Png);
+    assert_eq!(info.file_size, std::fs::metadata(&path).unwrap().len());
+    assert_eq!(info.frames, 1);
+    assert!(info.has_alpha);
+
+    let gif = std::env::temp_dir().join("rimlib_info.gif");
+    let frames = (0..3).map(|_| ::image::Frame::new(::image::RgbaImage::new(16, 16)));
+    ::image::codecs::gif::GifEncoder::new(std::fs::File::create(&gif).unwrap())
+        .encode_frames(frames)
+        .unwrap();
+
+    assert_eq!(ImageInfo::read(&gif).unwrap().frames, 3);
+
+    // Frames are counted from the chunks of APNG and animated WebP images
+    let animation = Animation {
+        frames: (0..4)
+            .map(|_| AnimationFrame {
+                image: DynamicImage::new_rgba8(16, 16),
+                delay: Duration::from_millis(50),
+            })
+            .collect(),
+        loop_count: LoopCount::Infinite,
+    };
+    for format in ["png", "webp"] {
+        let path = std::env::temp_dir().join(format!("rimlib_info_frames.{format}"));
+        save_animation(
+            &animation,
+            &path,
+            None,
+            &EncodeOptions::default(),
+            &Metadata::default(),
+        )
+        .unwrap();
+        assert_eq!(ImageInfo::read(&path).unwrap().frames, 4);
+    }
+
+    // A cut short animation fails instead of counting broken frames
+    let bytes = std::fs::read(&gif).unwrap();
+    let truncated = std::env::temp_dir().join("rimlib_info_truncated.gif");
+    std::fs::write(&truncated, &bytes[..bytes.len() - 20]).unwrap();
+    assert!(ImageInfo::read(&truncated).is_err());
+}
+
+#[test]
+fn background_removal() {
+    // White backdrop with a dark square holding a white spot in its center
+    let mut image = ::image::Rgb32FImage::from_pixel(9, 9, ::image::Rgb([1.0, 1.0, 1.0]));
+    for x in 2..7 {
+        for y in 2..7 {
+            image.put_pixel(x, y, ::image::Rgb([0.1, 0.1, 0.1]));
+        }
+    }
+    image.put_pixel(4, 4, ::image::Rgb([1.0, 1.0, 1.0]));
+    image.put_pixel(0, 0, ::image::Rgb([0.97, 0.97, 0.97]));
+
+    let options = BackgroundOptions {
+        key: "white".parse::<KeyColor>().unwrap(),
+        tolerance: 0.05,
+        ..Default::default()
+    };
+
+    for image in [
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgb32F(image.clone()).to_rgb8()),
+        DynamicImage::ImageRgb16(DynamicImage::ImageRgb32F(image.clone()).to_rgb16()),
+        DynamicImage::ImageRgb32F(image.clone()),
+    ] {
+        let global = image.remove_background(&options).to_rgba32f();
+        assert_eq!(global.get_pixel(0, 0)[3], 0.0);
+        assert_eq!(global.get_pixel(8, 3)[3], 0.0);
+        assert_eq!(global.get_pixel(4, 4)[3], 0.0);
+        assert_eq!(global.get_pixel(3, 3)[3], 1.0);
+
+        let border = image
+            .remove_background(&BackgroundOptions {
+                mode: RemovalMode::Border,
+                ..options
+            })
+            .to_rgba32f();
+        assert_eq!(border.get_pixel(8, 3)[3], 0.0);
+        assert_eq!(border.get_pixel(4, 4)[3], 1.0);
+    }
+
+    let feathered = DynamicImage::ImageRgb32F(image)
+        .remove_background(&BackgroundOptions {
+            tolerance: 0.0,
+            feather: 0.06,
+            ..options
+        })
+        .to_rgba32f();
+    let alpha = feathered.get_pixel(0, 0)[3];
+    assert!(alpha > 0.0 && alpha < 1.0);
+}
+
+#[test]
+fn seeded_randomizer() {
+    let seeded = |seed| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        DynamicImage::new_rgba16(64, 32)
+            .randomize_color_with(&mut rng, ColorType::Rgba16)
+            .unwrap()
+            .randomize_saturation_with(&mut rng)
+    };
+
+    assert_eq!(seeded(7), seeded(7));
+    assert_ne!(seeded(7), seeded(8));
+
+    let image = seeded(7).to_rgba16();
+    assert!(image.pixels().any(|p| p[0] > 0xFF));
+    assert!(image.pixels().all(|p| p[3] == 0));
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let luma = DynamicImage::new_luma8(16, 16)
+        .randomize_color_with(&mut rng, ColorType::L8)
+        .unwrap();
+    assert_eq!(luma.color(), ColorType::L8);
+    assert!(luma.as_bytes().iter().any(|&v| v != 0));
+    // 32-bit luma has no DynamicImage variant
+    let luma_f32 = ColorInfo::new(&ColorSpace::Luma, &BitDepth::B32);
+    assert!(
+        DynamicImage::new_luma8(16, 16)
+            .randomize_color_with(&mut rng, luma_f32)
+            .is_err()
+    );
+
+    let float = DynamicImage::new_rgba32f(16, 16)
+        .randomize_all_with(&mut rng)
+        .to_rgba32f();
+    assert!(float.as_raw().iter().all(|v| (0.0..=1.0).contains(v)));
+    assert!(float.pixels().any(|p| p[3] > 0.0));
+}
+
+#[test]
+fn color_model() {
+    for color_type in [
+        ColorType::L8,
+        ColorType::La8,
+        ColorType::Rgb8,
+        ColorType::Rgba8,
+        ColorType::L16,
+        ColorType::La16,
+        ColorType::Rgb16,
+        ColorType::Rgba16,
+        ColorType::Rgb32F,
+        ColorType::Rgba32F,
+    ] {
+        let info = ColorInfo::from(color_type);
+        assert_eq!(info.to_color_type().unwrap(), color_type);
+
+        let image = info.convert_image(DynamicImage::new_rgb8(4, 4)).unwrap();
+        assert_eq!(image.color(), color_type);
+        assert_eq!(ColorInfo::from_image(&image), info);
+    }
+
+    let luma_f32 = ColorInfo::new(&ColorSpace::Luma, &BitDepth::B32);
+    assert!(matches!(
+        luma_f32.convert_image(DynamicImage::new_rgb8(4, 4)),
+        Err(Error::ColorConversion { .. })
+    ));
+    assert!(
+        ColorInfo::new(&ColorSpace::Indexed, &BitDepth::B8)
+            .to_color_type()
+            .is_err()
+    );
+
+    let bilevel = ColorInfo::try_from(::image::ExtendedColorType::L1).unwrap();
+    assert_eq!(
+        (bilevel.color_space, bilevel.bit_depth),
+        (ColorSpace::Luma, BitDepth::B1)
+    );
+    assert_eq!(
+        bilevel.to_color_type_extended(),
+        ::image::ExtendedColorType::L1
+    );
+    assert_eq!(
+        ::image::ExtendedColorType::L1.color_type().unwrap(),
+        ColorType::L8
+    );
+    // Unknown layouts keep their bit depth only when it is a channel bit depth
+    let unknown = ColorInfo::try_from(::image::ExtendedColorType::Unknown(16)).unwrap();
+    assert_eq!(
+        (unknown.color_space, unknown.bit_depth),
+        (ColorSpace::Unknown, BitDepth::B16)
+    );
+    assert!(ColorInfo::try_from(::image::ExtendedColorType::Unknown(12)).is_err());
+}
+
+#[test]
+fn stats() {
+    // Left half black, right half white at 16 bits, alpha cleared on one column
+    let mut buffer = ::image::ImageBuffer::from_fn(8, 4, |x, _| {
+        let value = if x < 4 { 0 } else { u16::MAX };
+        ::image::Rgba([value, value, value, u16::MAX])
+    });
+    buffer.put_pixel(0, 0, ::image::Rgba([0, 0, 0, 0]));
+    let image = DynamicImage::ImageRgba16(buffer);
+
+    let stats = ImageStats::compute(&image);
+
+    assert_eq!(stats.channels.len(), 4);
+    assert_eq!(stats.unique_colors, 3);
+    assert_eq!(stats.alpha_coverage, Some(31.0 / 32.0 * 100.0));
+
+    let red = &stats.channels[0];
+    assert_eq!((red.min, red.max, red.mean), (0.0, 1.0, 0.5));
+    assert_eq!((red.clipped_low, red.clipped_high), (50.0, 50.0));
+    assert_eq!(red.histogram[0] + red.histogram[255], 32);
+    assert_eq!(stats.max_clipping(), 50.0);
+    assert!(!stats.is_uniform());
+    assert!(ImageStats::compute(&DynamicImage::new_luma8(4, 4)).is_uniform());
+
+    assert_eq!(sample_pixel(&image, 5, 1).unwrap(), vec![1.0; 4]);
+    assert_eq!(sample_rect(&image, 2, 1, 4, 2).unwrap()[0], 0.5);
+    assert!(matches!(
+        sample_rect(&image, 6, 0, 4, 1),
+        Err(Error::InvalidOperation { .. })
+    ));
+}
+
+#[test]
+fn crop() {
+    let rect = |spec: &str, gravity| spec.parse::<Geometry>().unwrap().rect(400, 300, gravity);
+
+    assert_eq!(
+        rect("100x50+10+20", Gravity::NorthWest).unwrap(),
+        Rect {
+            x: 10,
+            y: 20,
+            width: 100,
+            height: 50
+        }
+    );
+    assert_eq!(
+        rect("100x50+10+20", Gravity::SouthEast).unwrap(),
+        Rect {
+            x: 290,
+            y: 230,
+            width: 100,
+            height: 50
+        }
+    );
+    assert_eq!(
+        rect("50%", Gravity::Center).unwrap(),
+        Rect {
+            x: 100,
+            y: 75,
+            width: 200,
+            height: 150
+        }
+    );
+    assert_eq!(
+        rect("1:1", Gravity::Center).unwrap(),
+        Rect {
+            x: 50,
+            y: 0,
+            width: 300,
+            height: 300
+        }
+    );
+    assert_eq!(
+        rect("25%x100-5+0", Gravity::North).unwrap(),
+        Rect {
+            x: 145,
+            y: 0,
+            width: 100,
+            height: 100
+        }
+    );
+    assert!(rect("400x300+1+0", Gravity::NorthWest).is_err());
+    assert!("100y50".parse::<Geometry>().is_err());
+    assert_eq!("north-east".parse::<Gravity>().unwrap(), Gravity::NorthEast);
+
+    let pipeline = Pipeline::new()
+        .then(Resize::new(800, 600))
+        .then(Crop::new("16:9".parse().unwrap(), Gravity::Center));
+    assert_eq!(pipeline.output_size(100, 100).unwrap(), (800, 450));
+
+    let oversized = Pipeline::new().then(Crop::new("200x200".parse().unwrap(), Gravity::Center));
+    assert!(oversized.output_size(100, 300).is_err());
+
+    let image = pipeline.run(DynamicImage::new_rgb16(100, 100)).unwrap();
+    assert_eq!(
+        (image.width(), image.height(), image.color()),
+        (800, 450, ColorType::Rgb16)
+    );
+}
+
+#[test]
+fn orientation() {
+    // Little endian EXIF with a single Orientation entry set to 6, rotate 90 clockwise
+    let exif = b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x12\x01\x03\x00\x01\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00\x00";
+    let metadata = Metadata {
+        exif: Some(exif.to_vec()),
+        ..Default::default()
+    };
+    assert_eq!(metadata.orientation(), Some(Orientation::Rotate90));
+
+    let mut image = DynamicImage::new_rgb8(40, 20);
+    image
+        .as_mut_rgb8()
+        .unwrap()
+        .put_pixel(0, 0, image::Rgb([255, 0, 0]));
+
+    let path = std::env::temp_dir().join("rimlib_orientation.png");
+    save_image_with_metadata(&image, &metadata, &path, None).unwrap();
+
+    assert_eq!(probe_image(&path).unwrap().upright_size(), (20, 40));
+    let (upright, read) = open_image_oriented(&path).unwrap();
+    assert_eq!((upright.width(), upright.height()), (20, 40));
+    assert_eq!(upright.get_pixel(19, 0), Rgba([255, 0, 0, 255]));
+    assert_eq!(read.orientation(), Some(Orientation::NoTransforms));
+
+    let quarter = Pipeline::new().then(Rotate::new(-90.0));
+    assert_eq!(quarter.output_size(40, 20).unwrap(), (20, 40));
+    let turned = quarter.run(image.clone()).unwrap();
+    assert_eq!(turned.get_pixel(0, 39), Rgba([255, 0, 0, 255]));
+
+    let tilted = Pipeline::new().then(Rotate::new(45.0).background("transparent".parse().unwrap()));
+    assert_eq!(tilted.output_size(40, 20).unwrap(), (43, 43));
+    let rotated = tilted.run(image.clone()).unwrap();
+    assert_eq!(
+        (rotated.width(), rotated.height(), rotated.color()),
+        (43, 43, ColorType::Rgba8)
+    );
+    assert_eq!(
+        tilted.output_color(image.color().into()),
+        rotated.color().into()
+    );
+    assert_eq!(
+        quarter.output_color(image.color().into()),
+        ColorType::Rgb8.into()
+    );
+    assert_eq!(rotated.get_pixel(0, 0)[3], 0);
+    assert_eq!(rotated.get_pixel(21, 21)[3], 255);
+
+    // Angles that are not finite are rejected before decoding
+    for degrees in [f32::NAN, f32::INFINITY] {
+        let invalid = Pipeline::new().then(Rotate::new(degrees));
+        assert!(invalid.output_size(40, 20).is_err());
+        assert!(invalid.run(image.clone()).is_err());
+    }
+
+    let flipped = Pipeline::new()
+        .then(Flip(FlipDirection::Vertical))
+        .run(image)
+        .unwrap();
+    assert_eq!(flipped.get_pixel(0, 19), Rgba([255, 0, 0, 255]));
+    assert_eq!(
+        "#ff000080".parse::<Background>().unwrap().0[3],
+        128.0 / 255.0
+    );
+    assert!("diagonal".parse::<FlipDirection>().is_err());
+}
+
+#[test]
+fn resize_spec() {
+    let size = |spec: &str, width, height| {
+        spec.parse::<ResizeSpec>()
+            .unwrap()
+            .output_size(width, height)
+            .unwrap()
+    };
+
+    assert_eq!(size("800x", 1600, 1200), (800, 600));
+    assert_eq!(size("x600", 1600, 1200), (800, 600));
+    assert_eq!(size("50%", 1600, 1200), (800, 600));
+    assert_eq!(size("1000x1000", 1600, 1200), (1000, 750));
+    assert_eq!(size("1000x1000!", 1600, 1200), (1000, 1000));
+    assert_eq!(size("1920x1080^", 1600, 1200), (1920, 1080));
+    assert_eq!(size("1920x1080>", 1600, 1200), (1440, 1080));
+    assert_eq!(size("1920x1080>", 800, 600), (800, 600));
+    assert_eq!(
+        ResizeSpec::max_edge(2048).output_size(4096, 1024).unwrap(),
+        (2048, 512)
+    );
+    assert_eq!(
+        ResizeSpec::max_edge(2048).output_size(1000, 500).unwrap(),
+        (1000, 500)
+    );
+
+    for invalid in ["x", "0x100", "100x100^!", "100y100", ""] {
+        assert!(invalid.parse::<ResizeSpec>().is_err(), "{invalid:?} parsed");
+    }
+    assert_eq!(
+        "1920x1080^>".parse::<ResizeSpec>().unwrap().to_string(),
+        "1920x1080^>"
+    );
+
+    let cover = Pipeline::new().then(Resize::with_spec("100x100^".parse().unwrap()));
+    let mut image = DynamicImage::new_rgb8(300, 100);
+    image
+        .as_mut_rgb8()
+        .unwrap()
+        .put_pixel(150, 50, image::Rgb([255, 255, 255]));
+
+    let covered = cover.run(image).unwrap();
+    assert_eq!((covered.width(), covered.height()), (100, 100));
+    assert_eq!(covered.get_pixel(50, 50), Rgba([255, 255, 255, 255]));
+}
+
+#[test]
+fn contact_sheet() {
+    let options = MontageOptions {
+        columns: Some(3),
+        cell_width: 64,
+        cell_height: 32,
+        spacing: 4,
+        background: "black".parse().unwrap(),
+        captions: true,
+        ..Default::default()
+    };
+
+    let red = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+        640,
+        160,
+        image::Rgb([255, 0, 0]),
+    ));
+    let tiles = (0..5)
+        .map(|index| Tile::new(&red, Some(format!("render_{index:04}_final.png")), &options))
+        .collect::<Vec<Tile>>();
+    assert_eq!((tiles[0].image.width(), tiles[0].image.height()), (64, 16));
+
+    let sheet = montage(&tiles, &options).unwrap();
+    // Three cells of 64 plus four gaps, two rows of 32 plus an 11 pixel caption
+    assert_eq!(
+        options.sheet_size(5).unwrap(),
+        (3 * 64 + 4 * 4, 2 * (32 + 11) + 3 * 4)
+    );
+    assert_eq!(
+        (sheet.width(), sheet.height()),
+        options.sheet_size(5).unwrap()
+    );
+
+    assert_eq!(sheet.get_pixel(4 + 32, 4 + 16), Rgba([255, 0, 0, 255]));
+    assert_eq!(sheet.get_pixel(4 + 32, 4 + 2), Rgba([0, 0, 0, 255]));
+    assert_eq!(
+        sheet.get_pixel(sheet.width() - 2, sheet.height() - 2),
+        Rgba([0, 0, 0, 255])
+    );
+
+    let caption_row = (4..64)
+        .map(|x| sheet.get_pixel(x, 4 + 32 + 2 + 3))
+        .collect::<Vec<_>>();
+    assert!(caption_row.contains(&Rgba([255, 255, 255, 255])));
+
+    assert!(montage(&[], &options).is_err());
+}
+
+#[test]
+fn overlay() {
+    let logo = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+        20,
+        10,
+        Rgba([255, 255, 255, 255]),
+    ));
+    let options = OverlayOptions {
+        gravity: Gravity::SouthEast,
+        margin: Length::Pixels(5),
+        opacity: 0.5,
+        scale: Some(0.1),
+        ..Default::default()
+    };
+    let overlay = Overlay::new(logo, options);
+    let shared = overlay.clone();
+    assert!(std::sync::Arc::ptr_eq(&overlay.overlay, &shared.overlay));
+
+    let pipeline = Pipeline::new().then(overlay);
+    let image = pipeline.run(DynamicImage::new_rgb16(400, 200)).unwrap();
+    assert_eq!(image.color(), ColorType::Rgb16);
+
+    // Scaled to 40x20 in the bottom right corner, 5 pixels from the edges
+    let marked = image.as_rgb16().unwrap();
+    assert_eq!(marked.get_pixel(394, 194).0, [32768; 3]);
+    assert_eq!(marked.get_pixel(355, 175).0, [32768; 3]);
+    assert_eq!(marked.get_pixel(354, 194).0, [0; 3]);
+    assert_eq!(marked.get_pixel(395, 195).0, [0; 3]);
+
+    assert!(pipeline.output_size(10, 5).is_err());
+
+    let tiled = Pipeline::new()
+        .then(Overlay::new(
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, Rgba([255; 4]))),
+            OverlayOptions {
+                margin: Length::Pixels(1),
+                tile: true,
+                ..Default::default()
+            },
+        ))
+        .run(DynamicImage::new_luma8(4, 4))
+        .unwrap();
+    let tiled = tiled.as_luma8().unwrap();
+    assert_eq!(
+        (
+            tiled.get_pixel(0, 0).0,
+            tiled.get_pixel(1, 0).0,
+            tiled.get_pixel(2, 2).0
+        ),
+        ([255], [0], [255])
+    );
+}
+
+#[test]
+fn adjustments() {
+    let deep = DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(
+        8,
+        8,
+        Rgba([1000, 30000, 60000, 1234]),
+    ));
+    let adjust =
+        |adjustment: Adjustment, image: &DynamicImage| adjustment.apply(image.clone()).unwrap();
+
+    // 16-bit values that 8 bits could not hold survive the round trip
+    let brighter = adjust(Adjustment::Brightness(1.0 / 65535.0), &deep);
+    assert_eq!(
+        brighter.as_rgba16().unwrap().get_pixel(0, 0).0,
+        [1001, 30001, 60001, 1234]
+    );
+
+    let inverted = adjust(Adjustment::Invert, &deep);
+    assert_eq!(
+        inverted.as_rgba16().unwrap().get_pixel(3, 3).0,
+        [64535, 35535, 5535, 1234]
+    );
+
+    let gray = adjust(Adjustment::Grayscale, &deep);
+    let [r, g, b, a] = gray.as_rgba16().unwrap().get_pixel(0, 0).0;
+    assert!(r == g && g == b && a == 1234);
+
+    let hdr = DynamicImage::ImageRgb32F(image::ImageBuffer::from_pixel(
+        4,
+        4,
+        image::Rgb([0.75, 0.5, 0.25]),
+    ));
+    let exposed = adjust(Adjustment::Exposure(1.0), &hdr);
+    assert_eq!(
+        exposed.as_rgb32f().unwrap().get_pixel(0, 0).0,
+        [1.5, 1.0, 0.5]
+    );
+    let flat = adjust(Adjustment::Contrast(0.0), &hdr);
+    assert_eq!(flat.as_rgb32f().unwrap().get_pixel(0, 0).0, [0.5; 3]);
+    let corrected = adjust(Adjustment::Gamma(0.5), &hdr);
+    assert_eq!(
+        corrected.as_rgb32f().unwrap().get_pixel(0, 0).0,
+        [0.5625, 0.25, 0.0625]
+    );
+
+    // A single bright pixel spreads out when blurred and stands out more when sharpened
+    let mut dot = image::GrayImage::new(9, 9);
+    dot.put_pixel(4, 4, image::Luma([200]));
+    dot.put_pixel(3, 4, image::Luma([100]));
+    let dot = DynamicImage::ImageLuma8(dot);
+    let blurred = adjust(Adjustment::Blur { sigma: 1.0 }, &dot);
+    assert!(blurred.as_luma8().unwrap().get_pixel(5, 4).0[0] > 0);
+    let sharpened = adjust(
+        Adjustment::Unsharp {
+            sigma: 1.0,
+            amount: 1.0,
+            threshold: 0.0,
+        },
+        &dot,
+    );
+    assert_eq!(sharpened.color(), ColorType::L8);
+    assert_eq!(sharpened.as_luma8().unwrap().get_pixel(4, 4).0[0], 255);
+
+    // Blurring and sharpening need a finite radius
+    for sigma in [f32::INFINITY, f32::NAN] {
+        assert!(Adjustment::Blur { sigma }.validate().is_err());
+        let unsharp = Adjustment::Unsharp {
+            sigma,
+            amount: 1.0,
+            threshold: 0.0,
+        };
+        assert!(unsharp.validate().is_err());
+    }
+
+    let pipeline = Pipeline::new().then(Adjust::new(vec![Adjustment::Gamma(-1.0)]));
+    assert!(pipeline.output_size(8, 8).is_err());
+    assert!(pipeline.run(deep).is_err());
+}
+
+#[test]
+fn palette() {
+    let gradient = DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(64, 64, |x, y| {
+        image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+    }));
+    let dir = std::env::temp_dir();
+    let none = Metadata::default();
+
+    for quantizer in [Quantizer::MedianCut, Quantizer::KMeans, Quantizer::NeuQuant] {
+        for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            let options = QuantizeOptions {
+                colors: 16,
+                quantizer,
+                dither,
+            };
+            let reduced = Pipeline::new()
+                .then(Quantize::new(options))
+                .run(gradient.clone())
+                .unwrap();
+            let indexed = IndexedImage::from_image(&reduced).unwrap();
+            assert!(
+                indexed.palette.len() <= 16,
+                "{quantizer} gave {} colors",
+                indexed.palette.len()
+            );
+        }
+    }
+
+    let options = QuantizeOptions {
+        colors: 16,
+        ..Default::default()
+    };
+    let quantized = quantize(&gradient, &options).unwrap();
+    let reduced = quantized.to_image().unwrap();
+
+    // Indices that do not match the size or the palette are rejected
+    let mut broken = quantized.clone();
+    broken.indices.pop();
+    assert!(broken.to_image().is_err());
+    let mut broken = quantized.clone();
+    broken.indices[0] = broken.palette.len() as u8;
+    assert!(broken.to_image().is_err());
+    let indexed = EncodeOptions {
+        indexed: true,
+        ..Default::default()
+    };
+
+    // The palette PNG keeps every pixel and is smaller than the truecolor one
+    let palette_png = dir.join("rimlib_indexed.png");
+    let truecolor_png = dir.join("rimlib_truecolor.png");
+    save_image(&reduced, &palette_png, None, &indexed, &none).unwrap();
+    save_image(
+        &reduced,
+        &truecolor_png,
+        None,
+        &EncodeOptions::default(),
+        &none,
+    )
+    .unwrap();
+    assert_eq!(
+        open_image(&palette_png).unwrap().to_rgba8(),
+        reduced.to_rgba8()
+    );
+    assert!(
+        std::fs::metadata(&palette_png).unwrap().len()
+            < std::fs::metadata(&truecolor_png).unwrap().len()
+    );
+    // Probing reports the palette rather than the colors it expands to
+    let stored = probe_image(&palette_png).unwrap().original_color;
+    assert_eq!(
+        (stored.color_space, stored.bit_depth),
+        (ColorSpace::Indexed, BitDepth::B4)
+    );
+
+    let gif = dir.join("rimlib_indexed.gif");
+    save_image(&reduced, &gif, None, &indexed, &none).unwrap();
+    assert_eq!(open_image(&gif).unwrap().to_rgba8(), reduced.to_rgba8());
+    assert_eq!(
+        probe_image(&gif).unwrap().original_color.color_space,
+        ColorSpace::Indexed
+    );
+
+    let jpeg = dir.join("rimlib_indexed.jpg");
+    assert!(save_image(&reduced, &jpeg, None, &indexed, &none).is_err());
+    // Too many colors for a palette without quantizing first
+    assert!(save_image(&gradient, &palette_png, None, &indexed, &none).is_err());
+}
+
+#[test]
+fn animation() {
+    let dir = std::env::temp_dir();
+    let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 128]];
+    let animation = Animation {
+        frames: colors
+            .iter()
+            .enumerate()
+            .map(|(i, &color)| AnimationFrame {
+                image: DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(
+                    16,
+                    12,
+                    Rgba(color),
+                )),
+                delay: Duration::from_millis(100 * (i as u64 + 1)),
+            })
+            .collect(),
+        loop_count: LoopCount::Times(3),
+    };
+
+    let gif = dir.join("rimlib_animation.gif");
+    save_animation(
+        &animation,
+        &gif,
+        None,
+        &EncodeOptions::default(),
+        &Metadata::default(),
+    )
+    .unwrap();
+    let decoded = open_animation(&gif).unwrap().unwrap();
+    assert_eq!(decoded.loop_count, LoopCount::Times(3));
+    assert_eq!(decoded.frames.len(), 3);
+    assert_eq!(decoded.frames[1].delay, Duration::from_millis(200));
+    assert_eq!(
+        decoded.frames[1].image.to_rgba8().get_pixel(5, 5).0,
+        colors[1]
+    );
+
+    // Every frame goes through the pipeline
+    let pipeline = Pipeline::new().then(Resize::new(8, 6));
+    let resized = pipeline.run_animation(animation).unwrap();
+    assert_eq!(resized.dimensions(), (8, 6));
+
+    let apng = dir.join("rimlib_animation.png");
+    save_animation(
+        &resized,
+        &apng,
+        None,
+        &EncodeOptions::default(),
+        &Metadata::default(),
+    )
+    .unwrap();
+    let decoded = open_animation(&apng).unwrap().unwrap();
+    assert_eq!(decoded, resized);
+
+    // Animated WebP keeps the frames, delays and metadata
+    let exif = b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x12\x01\x03\x00\x01\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00\x00";
+    let metadata = Metadata {
+        exif: Some(exif.to_vec()),
+        icc_profile: Some(vec![7; 512]),
+        xmp: None,
+    };
+    let webp = dir.join("rimlib_animation.webp");
+    save_animation(&resized, &webp, None, &EncodeOptions::default(), &metadata).unwrap();
+    let (decoded, read) = open_animation_with_metadata(&webp).unwrap().unwrap();
+    assert_eq!(decoded, resized);
+    assert_eq!(read, metadata);
+
+    // Every frame is turned upright
+    let (oriented, read) = open_animation_oriented(&webp).unwrap().unwrap();
+    assert_eq!(oriented.dimensions(), (6, 8));
+    assert_eq!(oriented.frames.len(), 3);
+    assert_eq!(read.orientation(), Some(Orientation::NoTransforms));
+
+    // GIF cannot hold metadata
+    assert!(save_animation(&resized, &gif, None, &EncodeOptions::default(), &metadata).is_err());
+
+    let jpeg = dir.join("rimlib_animation.jpg");
+    assert!(matches!(
+        save_animation(
+            &resized,
+            &jpeg,
+            None,
+            &EncodeOptions::default(),
+            &Metadata::default()
+        ),
+        Err(Error::InvalidOperation { .. })
+    ));
+
+    let still = dir.join("rimlib_still.png");
+    DynamicImage::new_rgba8(4, 4).save(&still).unwrap();
+    assert!(open_animation(&still).unwrap().is_none());
+    let still_gif = dir.join("rimlib_still.gif");
+    DynamicImage::new_rgba8(4, 4).save(&still_gif).unwrap();
+    assert!(open_animation(&still_gif).unwrap().is_none());
+}
+
+#[test]
+fn compare() {
+    let image = DynamicImage::new_rgb16(64, 48)
+        .randomize_color(ColorType::Rgb16)
+        .unwrap();
+    let same = Comparison::compute(&image, &image.clone(), true).unwrap();
+    assert_eq!(
+        (same.mse, same.max_delta, same.differing_pixels),
+        (0.0, 0.0, 0.0)
+    );
+    assert!(same.psnr.is_infinite());
+    assert!((same.ssim - 1.0).abs() < 1e-6);
+    assert_eq!(same.delta_e.unwrap().max, 0.0);
+    assert!(!same.exceeds(Metric::Psnr, 40.0).unwrap());
+
+    // Saving as a low quality JPEG degrades the image
+    let path = std::env::temp_dir().join("rimlib_compare.jpg");
+    let original = DynamicImage::ImageRgb8(image.to_rgb8());
+    let options = EncodeOptions {
+        quality: Some(20),
+        ..Default::default()
+    };
+    save_image(&original, &path, None, &options, &Metadata::default()).unwrap();
+    let lossy = open_image(&path).unwrap();
+
+    let compared = Comparison::compute(&original, &lossy, false).unwrap();
+    assert!(compared.mse > 0.0 && compared.psnr.is_finite() && compared.ssim < 1.0);
+    assert!(compared.exceeds(Metric::Ssim, 0.99).unwrap());
+    assert!(!compared.exceeds(Metric::MaxDelta, 1.0).unwrap());
+    assert!(compared.exceeds(Metric::DeltaE, 1.0).is_err());
+
+    let mut changed = original.to_rgb8();
+    changed.put_pixel(3, 4, image::Rgb([255, 0, 255]));
+    let diff = diff_image(&original, &DynamicImage::ImageRgb8(changed), 0.0).unwrap();
+    assert!(diff.get_pixel(3, 4).0[0] >= 128 && diff.get_pixel(3, 4).0[1] == 0);
+    let [r, g, b] = diff.get_pixel(0, 0).0;
+    assert!(r == g && g == b);
+
+    assert!(Comparison::compute(&original, &DynamicImage::new_rgb8(10, 10), false).is_err());
+
+    // Reference pair from Sharma, Wu and Dalal
+    let delta_e = ciede2000([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485]);
+    assert!((delta_e - 2.0425).abs() < 1e-4);
+}
+
+#[test]
+fn perceptual_hash() {
+    let mut rng = StdRng::seed_from_u64(20);
+    let mut random_image = || {
+        let noise =
+            image::RgbImage::from_fn(16, 12, |_, _| image::Rgb(rand::Rng::random(&mut rng)));
+        DynamicImage::ImageRgb8(noise).resize_exact(640, 480, image::imageops::FilterType::Triangle)
+    };
+    let original = random_image();
+    let other = random_image();
+    let smaller = original.resize(320, 240, image::imageops::FilterType::Lanczos3);
+    let deeper = DynamicImage::ImageRgb16(original.to_rgb16());
+
+    for algorithm in [
+        HashAlgorithm::Average,
+        HashAlgorithm::Difference,
+        HashAlgorithm::Perceptual,
+    ] {
+        let hash = |image: &DynamicImage| ImageHash::compute(image, algorithm);
+        assert_eq!(hash(&original), hash(&deeper));
+        assert!(
+            hash(&original).distance(&hash(&smaller)) <= 4,
+            "{algorithm}"
+        );
+        assert!(hash(&original).distance(&hash(&other)) > 12, "{algorithm}");
+
+        let hashes = [hash(&original), hash(&other), hash(&smaller)];
+        assert_eq!(group_duplicates(&hashes, 4), vec![vec![0, 2]]);
+    }
+
+    let hash = ImageHash::compute(&original, HashAlgorithm::default());
+    assert_eq!(hash.to_string().parse::<ImageHash>().unwrap(), hash);
+    assert_eq!(hash.to_string().len(), 16);
+}
+
+#[test]
+fn input_paths() {
+    let root = std::env::temp_dir().join("rimlib_inputs");
+    let _ = std::fs::remove_dir_all(&root);
+    for file in [
+        "a.png",
+        "notes.txt",
+        "sub/b.JPG",
+        "sub/deep/c.gif",
+        "sub/thumbs/d.png",
+        "skip/e.webp",
+    ] {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, []).unwrap();
+    }
+    let names = |paths: Vec<std::path::PathBuf>| {
+        paths
+            .iter()
+            .map(|path| {
+                path.strip_prefix(&root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect::<Vec<String>>()
+    };
+    let collect = |options: &InputOptions| {
+        names(collect_inputs(std::slice::from_ref(&root), options).unwrap())
+    };
+
+    assert_eq!(collect(&InputOptions::default()), ["a.png"]);
+
+    let recursive = InputOptions {
+        recursive: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        collect(&recursive),
+        [
+            "a.png",
+            "skip/e.webp",
+            "sub/b.JPG",
+            "sub/deep/c.gif",
+            "sub/thumbs/d.png"
+        ]
+    );
+
+    let filtered = InputOptions {
+        recursive: true,
+        include: vec!["*.{png,gif}".parse().unwrap()],
+        exclude: vec!["thumbs".parse().unwrap(), "sub/deep/**".parse().unwrap()],
+    };
+    assert_eq!(collect(&filtered), ["a.png"]);
+
+    let nested = InputOptions {
+        recursive: true,
+        include: vec!["sub/**/*.[a-z]*".parse().unwrap()],
+        exclude: vec!["[d-z].*".parse().unwrap()],
+    };
+    assert_eq!(collect(&nested), ["sub/deep/c.gif"]);
+
+    // Files given directly are kept whatever their extension, once
+    let given = [root.join("notes.txt"), root.clone(), root.join("a.png")];
+    assert_eq!(
+        names(collect_inputs(&given, &InputOptions::default()).unwrap()),
+        ["notes.txt", "a.png"]
+    );
+    // Excludes also apply to files given directly, matched against the path as given
+    let excluded = InputOptions {
+        exclude: vec!["*.txt".parse().unwrap(), "**/thumbs/**".parse().unwrap()],
+        ..Default::default()
+    };
+    let given = [
+        root.join("notes.txt"),
+        root.join("sub/thumbs/d.png"),
+        root.join("a.png"),
+    ];
+    assert_eq!(names(collect_inputs(&given, &excluded).unwrap()), ["a.png"]);
+
+    let list = read_path_list(&b"one.png\0two words.jpg\0\0"[..]).unwrap();
+    assert_eq!(
+        list,
+        [std::path::PathBuf::from("one.png"), "two words.jpg".into()]
+    );
+    let list = read_path_list(&b"one.png\r\n\nthree.gif\n"[..]).unwrap();
+    assert_eq!(
+        list,
+        [std::path::PathBuf::from("one.png"), "three.gif".into()]
+    );
+
+    // Found images keep their place below the given directory, given files below their common parent
+    let found = collect_inputs(std::slice::from_ref(&root), &recursive).unwrap();
+    let dirs = |dirs: Vec<std::path::PathBuf>| {
+        dirs.iter()
+            .map(|dir| dir.to_string_lossy().replace('\\', "/"))
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(
+        dirs(relative_dirs(&found, std::slice::from_ref(&root))),
+        ["", "skip", "sub", "sub/deep", "sub/thumbs"]
+    );
+    let files = [root.join("sub/deep/c.gif"), root.join("skip/e.webp")];
+    assert_eq!(dirs(relative_dirs(&files, &files)), ["sub/deep", "skip"]);
+
+    // Sibling directories keep their names so files with the same name do not collide
+    let shoots = std::env::temp_dir().join("rimlib_shoots");
+    let _ = std::fs::remove_dir_all(&shoots);
+    for file in ["a/x.png", "b/x.png", "b/raw/y.png"] {
+        let path = shoots.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, []).unwrap();
+    }
+    let siblings = [shoots.join("a"), shoots.join("b")];
+    let found = collect_inputs(&siblings, &recursive).unwrap();
+    assert_eq!(dirs(relative_dirs(&found, &siblings)), ["a", "b/raw", "b"]);
+    let through_parent = [shoots.join("a/../b")];
+    assert_eq!(
+        dirs(relative_dirs(
+            &collect_inputs(&through_parent, &recursive).unwrap(),
+            &through_parent
+        )),
+        ["raw", ""]
+    );
+
+    assert!(Glob::new("*.{png").is_err());
+    assert!(Glob::new("[abc").is_err());
+    // Wildcards that could backtrack exponentially finish at once
+    let wildcards = Glob::new("*a*a*a*a*a*a*a*a*a*a*b").unwrap();
+    assert!(!wildcards.is_match(std::path::Path::new(&"a".repeat(200))));
+    assert!(wildcards.is_match(std::path::Path::new(&format!("{}b", "a".repeat(200)))));
+    assert!(
+        Glob::new("**/[!x]?.\\*")
+            .unwrap()
+            .is_match(std::path::Path::new("d/e/ab.*"))
+    );
+    let glob = |pattern: &str, path: &str| {
+        Glob::new(pattern)
+            .unwrap()
+            .is_match(std::path::Path::new(path))
+    };
+    // `**` spans any number of directories, including none, and not part of a name
+    assert!(glob("a/**/b.png", "a/b.png"));
+    assert!(glob("a/**/b.png", "a/x/y/b.png"));
+    assert!(!glob("a/**/b.png", "a/xb.png"));
+    assert!(glob("**/*.png", "top.png"));
+    assert!(glob("**/*.png", "x/y/deep.png"));
+    assert!(!glob("**/b.png", "ab.png"));
+    assert!(glob("a/**", "a/x/y.png"));
+    // Nested alternatives, and braces taken literally when escaped or in a class
+    let nested = Glob::new("*.{png,{jpg,jpeg}}").unwrap();
+    for name in ["a.png", "a.jpg", "a.jpeg"] {
+        assert!(nested.is_match(std::path::Path::new(name)), "{name}");
+    }
+    assert!(!nested.is_match(std::path::Path::new("a.{jpg,jpeg}")));
+    assert!(glob("{a,b{c,d}}.png", "bd.png"));
+    assert!(glob("\\{a,b\\}.png", "{a,b}.png"));
+    assert!(!glob("\\{a,b\\}.png", "a.png"));
+    assert!(glob("{x\\,y,z}.png", "x,y.png"));
+    assert!(glob("[{]*.png", "{1}.png"));
+    assert!(is_decodable(std::path::Path::new("photo.JPEG")));
+    assert!(!is_decodable(std::path::Path::new("notes.txt")));
+}
+
+#[test]
+fn name_templates() {
+    // Model in the first IFD, DateTimeOriginal in the EXIF IFD, both stored after the entries
+    let mut exif = b"II\x2a\x00\x08\x00\x00\x00\x02\x00".to_vec();
+    for (tag, kind, count, value) in [(0x0110u16, 2u16, 6u32, 38u32), (0x8769, 4, 1, 44)] {
+        exif.extend(
+            tag.to_le_bytes()
+                .iter()
+                .chain(&kind.to_le_bytes())
+                .chain(&count.to_le_bytes())
+                .chain(&value.to_le_bytes()),
+        );
+    }
+    exif.extend(b"\x00\x00\x00\x00Pixel\x00\x01\x00\x03\x90\x02\x00\x14\x00\x00\x00\x3e\x00\x00\x00\x00\x00\x00\x002024:06:01 14:30:00\x00");
+    let metadata = Metadata {
+        exif: Some(exif),
+        ..Default::default()
+    };
+    assert_eq!(metadata.exif_field(0x0110).as_deref(), Some("Pixel"));
+
+    let path = std::path::Path::new("shots/hero.PNG");
+    let fields = NameFields {
+        path,
+        index: 7,
+        width: 1920,
+        height: 1080,
+        format: image::ImageFormat::WebP,
+        metadata: Some(&metadata),
+    };
+    let render = |template: &str| {
+        template
+            .parse::<NameTemplate>()
+            .unwrap()
+            .render(&fields)
+            .unwrap()
+    };
+
+    assert_eq!(render("{stem}_{width}w"), "hero_1920w");
+    assert_eq!(
+        render("{parent}-{index:04}.{ext}-{format}"),
+        "shots-0007.PNG-webp"
+    );
+    assert_eq!(
+        render("{date:%Y%m%d}_{exif:model}_{{{height}}}"),
+        "20240601_Pixel_{1080}"
+    );
+    assert_eq!(render("this-image"), "this-image_7");
+    assert!("{date}".parse::<NameTemplate>().unwrap().uses_metadata());
+    assert!(
+        "{exif:Make}"
+            .parse::<NameTemplate>()
+            .unwrap()
+            .render(&fields)
+            .is_err()
+    );
+    for invalid in [
+        "{size}",
+        "{stem",
+        "a}",
+        "{stem:3}",
+        "{exif:Nope}",
+        "dir/{stem}",
+        "",
+    ] {
+        assert!(
+            invalid.parse::<NameTemplate>().is_err(),
+            "{invalid} should be rejected"
+        );
+    }
+
+    let paths =
+        ["img10.png", "IMG2.png", "img1.png", "img02.png", "a.png"].map(std::path::PathBuf::from);
+    assert_eq!(natural_indices(&paths), [5, 3, 2, 4, 1]);
+    assert_eq!(natural_cmp(&paths[1], &paths[3]), std::cmp::Ordering::Less);
+}